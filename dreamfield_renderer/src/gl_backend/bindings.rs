@@ -16,9 +16,27 @@ pub enum AttribBinding {
     Tangents = 4,
     Colors = 5,
     Joints = 6,
-    Weights = 7
+    Weights = 7,
+    /// The per-instance model matrix for instanced draws, which takes up four locations. Shaders
+    /// that support instancing declare it as `layout(location = 9) in mat4`, and multiply the model
+    /// matrix by it when GlobalParams.instancing_enabled is set. Instances are drawn one at a time
+    /// with shaders that don't declare it.
+    InstanceTransform = 9
 }
 
 pub enum TextureSlot {
-    BaseColor = 0
+    BaseColor = 0,
+    Palette = 1,
+    Emissive = 2,
+    EmissivePalette = 3
+}
+
+impl TextureSlot {
+    /// Get the slot that the palette of a paletted texture in this slot is bound to
+    pub fn palette_slot(&self) -> TextureSlot {
+        match self {
+            TextureSlot::Emissive => TextureSlot::EmissivePalette,
+            _ => TextureSlot::Palette
+        }
+    }
 }
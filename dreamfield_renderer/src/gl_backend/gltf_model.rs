@@ -12,8 +12,10 @@ use gltf::json::extras::RawValue;
 use gltf::khr_lights_punctual::Kind;
 use super::texture::{Texture, TextureParams};
 use super::uniform_buffer::{UniformBuffer, GlobalParams, MaterialParams};
-use super::{bindings, JointParams, Joint, ToStd140};
+use super::{bindings, JointParams, Joint, ToStd140, InstanceBuffer};
 use super::lights::LightType;
+use dreamfield_system::world::world_texture::TextureFormat;
+use dreamfield_system::world::texture_processor::{self, TextureSettings};
 use cgmath::{Matrix4, Vector3, Matrix};
 use serde::{Deserialize, Serialize};
 
@@ -27,6 +29,10 @@ use gltf_light::GltfLight;
 /// How many bits to downsample textures to
 const TEXTURE_BITS: Option<u8> = Some(5);
 
+/// The indexed format to palettise textures to, if any. The palette colors are downsampled to
+/// TEXTURE_BITS.
+const TEXTURE_PALETTE: Option<TextureFormat> = None;
+
 /// A gltf model
 pub struct GltfModel {
     transform_hierarchy: GltfTransformHierarchy,
@@ -180,51 +186,96 @@ impl GltfModel {
 
         // Render all prims
         for drawable in self.drawables.iter() {
-            let mesh = &drawable.mesh;
-            let model_mat = object_world_transform * drawable.transform
-                .as_ref()
-                .map(|t| t.lock().unwrap().world_transform().clone())
-                .unwrap_or(self.transform_hierarchy.root().lock().unwrap().world_transform().clone());
-
-            // Set model matrix based on whether this is a billboard or not
-            if mesh.extras().is_billboard {
-                let view_mat = ubo_global.get_mat_view();
-                let billboard_mat = Self::calc_billboard_matrix(&view_mat, &model_mat, mesh.extras().keep_upright);
-                ubo_global.set_mat_model_derive(&billboard_mat);
-            }
-            else {
-                ubo_global.set_mat_model_derive(&model_mat);
-            }
+            self.render_drawable(drawable, object_world_transform, ubo_global, ubo_joints, patches);
+        }
+    }
 
-            // Set lighting strength
-            let lighting_strength = &drawable.parsed_extras.lighting_strength;
-            ubo_global.set_lighting_strength(lighting_strength);
+    /// Render a model once for each transform in an instance buffer, with one draw call per
+    /// primitive. Billboards and skinned drawables can't be instanced, so they're still drawn one
+    /// instance at a time. The bound shader must support instancing, see AttribBinding::InstanceTransform.
+    pub fn render_instanced(&self, instances: &InstanceBuffer, ubo_global: &mut UniformBuffer<GlobalParams>,
+        ubo_joints: &mut UniformBuffer<JointParams>, patches: bool)
+    {
+        // Bind global ubo
+        ubo_global.bind(bindings::UniformBlockBinding::GlobalParams);
+
+        for drawable in self.drawables.iter() {
+            if drawable.mesh.extras().is_billboard || drawable.skin.is_some() {
+                for transform in instances.transforms() {
+                    self.render_drawable(drawable, transform, ubo_global, ubo_joints, patches);
+                }
+                continue;
+            }
 
-            // Update global uniforms
+            // The instance transforms are applied on top of the drawable's own transform
+            ubo_global.set_mat_model_derive(&self.drawable_world_transform(drawable));
+            ubo_global.set_lighting_strength(&drawable.parsed_extras.lighting_strength);
+            ubo_global.set_instancing_enabled(&true);
             ubo_global.upload_changed();
 
-            // Update joint matrices for skinned drawables
-            if let Some(skin) = &drawable.skin {
-                ubo_joints.set_skinning_enabled(&true);
+            ubo_joints.set_skinning_enabled(&false);
+            ubo_joints.bind(bindings::UniformBlockBinding::JointParams);
 
-                for (i, joint) in skin.lock().unwrap().joints().iter().enumerate() {
-                    let mut joint_transform = joint.transform().lock().unwrap();
-                    let joint_world_transform = object_world_transform * joint_transform.world_transform();
+            drawable.mesh.draw_instanced(patches, instances);
+        }
 
-                    let joint_matrix = joint_world_transform * joint.inverse_bind_matrix();
-                    ubo_joints.set_joints(i, &Joint {
-                        joint_matrix: joint_matrix.to_std140()
-                    });
-                }
-            }
-            else {
-                ubo_joints.set_skinning_enabled(&false);
-            }
-            ubo_joints.bind(bindings::UniformBlockBinding::JointParams);
+        ubo_global.set_instancing_enabled(&false);
+        ubo_global.upload_changed();
+    }
+
+    /// Render a single drawable
+    fn render_drawable(&self, drawable: &GltfDrawable, object_world_transform: &Matrix4<f32>,
+        ubo_global: &mut UniformBuffer<GlobalParams>, ubo_joints: &mut UniformBuffer<JointParams>, patches: bool)
+    {
+        let mesh = &drawable.mesh;
+        let model_mat = object_world_transform * self.drawable_world_transform(drawable);
+
+        // Set model matrix based on whether this is a billboard or not
+        if mesh.extras().is_billboard {
+            let view_mat = ubo_global.get_mat_view();
+            let billboard_mat = Self::calc_billboard_matrix(&view_mat, &model_mat, mesh.extras().keep_upright);
+            ubo_global.set_mat_model_derive(&billboard_mat);
+        }
+        else {
+            ubo_global.set_mat_model_derive(&model_mat);
+        }
+
+        // Set lighting strength
+        let lighting_strength = &drawable.parsed_extras.lighting_strength;
+        ubo_global.set_lighting_strength(lighting_strength);
+
+        // Update global uniforms
+        ubo_global.upload_changed();
 
-            // Draw mesh
-            mesh.draw(patches);
+        // Update joint matrices for skinned drawables
+        if let Some(skin) = &drawable.skin {
+            ubo_joints.set_skinning_enabled(&true);
+
+            for (i, joint) in skin.lock().unwrap().joints().iter().enumerate() {
+                let mut joint_transform = joint.transform().lock().unwrap();
+                let joint_world_transform = object_world_transform * joint_transform.world_transform();
+
+                let joint_matrix = joint_world_transform * joint.inverse_bind_matrix();
+                ubo_joints.set_joints(i, &Joint {
+                    joint_matrix: joint_matrix.to_std140()
+                });
+            }
+        }
+        else {
+            ubo_joints.set_skinning_enabled(&false);
         }
+        ubo_joints.bind(bindings::UniformBlockBinding::JointParams);
+
+        // Draw mesh
+        mesh.draw(patches);
+    }
+
+    /// Get the world transform of a drawable within the model
+    fn drawable_world_transform(&self, drawable: &GltfDrawable) -> Matrix4<f32> {
+        drawable.transform
+            .as_ref()
+            .map(|t| t.lock().unwrap().world_transform().clone())
+            .unwrap_or(self.transform_hierarchy.root().lock().unwrap().world_transform().clone())
     }
 
     /// Set the model's transform
@@ -252,6 +303,10 @@ impl GltfModel {
         let data = &image_data[tex.source().index()];
         let sampler = tex.sampler();
 
+        if let Some(format) = TEXTURE_PALETTE {
+            return Self::load_paletted_texture(data, &sampler, format);
+        }
+
         let mut pixels = data.pixels.to_vec();
 
         // Downsample if enabled
@@ -285,6 +340,43 @@ impl GltfModel {
         tex
     }
 
+    /// Load a gltf texture as a paletted texture, generating the palette and mips on load
+    fn load_paletted_texture(data: &gltf::image::Data, sampler: &gltf::texture::Sampler, format: TextureFormat)
+        -> Texture
+    {
+        let settings = TextureSettings {
+            format,
+            bit_depth: TEXTURE_BITS,
+            generate_mips: true,
+            ..Default::default()
+        };
+        let processed = texture_processor::process_texture(data, &settings);
+
+        let tex_params = TextureParams {
+            horz_wrap: sampler.wrap_s().as_gl_enum(),
+            vert_wrap: sampler.wrap_t().as_gl_enum(),
+            min_filter: sampler.min_filter().map(|f| f.as_gl_enum()).unwrap_or(gl::NEAREST_MIPMAP_NEAREST),
+            mag_filter: sampler.mag_filter().map(|f| f.as_gl_enum()).unwrap_or(gl::NEAREST)
+        };
+
+        let level_indices = |level: usize| {
+            let width = u32::max(1, processed.width >> level) as i32;
+            let height = u32::max(1, processed.height >> level) as i32;
+            (format.unpack_indices(&processed.levels[level], (width * height) as usize), width, height)
+        };
+
+        let (indices, width, height) = level_indices(0);
+        let tex = Texture::new_paletted(&indices, width, height, &processed.palette, tex_params)
+            .expect("Failed to load gltf texture");
+
+        for level in 1..processed.levels.len() {
+            let (indices, width, height) = level_indices(level);
+            tex.upload_mip_level(level as i32, &indices, width, height, gl::RED, gl::UNSIGNED_BYTE, gl::R8);
+        }
+
+        tex
+    }
+
     /// build the transform hierarchy
     fn build_hierarchy_recursive(node: &gltf::Node, parent: &Arc<Mutex<GltfTransform>>,
         transform_hierarchy: &mut GltfTransformHierarchy)
@@ -1,5 +1,6 @@
 use super::{bindings, UniformBuffer, MaterialParams};
-use cgmath::vec4;
+use cgmath::{vec3, vec4};
+use gltf::material::AlphaMode;
 
 /// A gltf material
 pub struct GltfMaterial {
@@ -21,8 +22,21 @@ impl GltfMaterial {
         let pbr = mat.pbr_metallic_roughness();
         let base_color = pbr.base_color_factor();
         ubo.set_has_base_color_texture(&pbr.base_color_texture().is_some());
+        ubo.set_has_palette(&(pbr.base_color_texture().is_some() && super::TEXTURE_PALETTE.is_some()));
         ubo.set_base_color(&vec4(base_color[0], base_color[1], base_color[2], base_color[3]));
 
+        let alpha_cutoff = match mat.alpha_mode() {
+            AlphaMode::Mask => mat.alpha_cutoff().unwrap_or(0.5),
+            _ => 0.0
+        };
+        ubo.set_alpha_cutoff(&alpha_cutoff);
+
+        let emissive = mat.emissive_factor();
+        ubo.set_has_emissive_texture(&mat.emissive_texture().is_some());
+        ubo.set_has_emissive_palette(&(mat.emissive_texture().is_some() && super::TEXTURE_PALETTE.is_some()));
+        ubo.set_emissive(&vec3(emissive[0], emissive[1], emissive[2]));
+        ubo.set_unlit(&mat.unlit());
+
         GltfMaterial {
             uniform_buffer: ubo
         }
@@ -1,6 +1,7 @@
 use std::sync::{Arc, Mutex};
 use super::GltfMaterial;
 use super::Texture;
+use crate::gl_backend::InstanceBuffer;
 use gltf::{Semantic, material::AlphaMode};
 use serde::{Deserialize, Serialize, Deserializer};
 use crate::gl_backend::bindings::{TextureSlot, AttribBinding};
@@ -18,6 +19,7 @@ pub struct GltfMeshPrimitive {
     indexed_offset_length: Option<(i32, i32)>,
     material: Arc<Mutex<GltfMaterial>>,
     base_color_texture: Option<Arc<Texture>>,
+    emissive_texture: Option<Arc<Texture>>,
     primitive_count: Option<i32>,
     alpha_blend: bool
 }
@@ -138,6 +140,11 @@ impl GltfMesh {
                 .base_color_texture()
                 .map(|tex_info| tex_info.texture().index())
                 .map(|idx| textures[idx].clone());
+            let emissive_texture = prim
+                .material()
+                .emissive_texture()
+                .map(|tex_info| tex_info.texture().index())
+                .map(|idx| textures[idx].clone());
 
             // Look up whether it's alpha blended
             let alpha_blend = prim.material().alpha_mode() == AlphaMode::Blend;
@@ -153,6 +160,7 @@ impl GltfMesh {
                 vao,
                 indexed_offset_length,
                 base_color_texture,
+                emissive_texture,
                 material,
                 primitive_count,
                 alpha_blend
@@ -178,7 +186,14 @@ impl GltfMesh {
     /// Draw the mesh
     pub fn draw(&self, patches: bool) {
         for primitive in self.primitives.iter() {
-            primitive.draw(patches);
+            primitive.draw(patches, None);
+        }
+    }
+
+    /// Draw the mesh once for each instance in an instance buffer
+    pub fn draw_instanced(&self, patches: bool, instances: &InstanceBuffer) {
+        for primitive in self.primitives.iter() {
+            primitive.draw(patches, Some(instances));
         }
     }
 
@@ -198,8 +213,8 @@ impl GltfMesh {
 }
 
 impl GltfMeshPrimitive {
-    /// Draw the primitive
-    pub fn draw(&self, patches: bool) {
+    /// Draw the primitive, optionally once for each instance in an instance buffer
+    pub fn draw(&self, patches: bool, instances: Option<&InstanceBuffer>) {
         // Figure out primitive type
         let prim_type = match patches {
             true => gl::PATCHES,
@@ -226,27 +241,47 @@ impl GltfMeshPrimitive {
         if let Some(texture) = &self.base_color_texture {
             texture.bind(TextureSlot::BaseColor);
         }
+        if let Some(texture) = &self.emissive_texture {
+            texture.bind(TextureSlot::Emissive);
+        }
+
+        unsafe { gl::BindVertexArray(self.vao) }
+        if let Some(instances) = instances {
+            instances.bind_attribs();
+        }
 
         // Indexed draw
         if let Some((offset, length)) = self.indexed_offset_length {
             unsafe {
-                gl::BindVertexArray(self.vao);
-                gl::DrawElements(prim_type,
-                                 length,
-                                 gl::UNSIGNED_SHORT,
-                                 offset as *const GLvoid);
+                match instances {
+                    Some(instances) => gl::DrawElementsInstanced(prim_type,
+                                                                 length,
+                                                                 gl::UNSIGNED_SHORT,
+                                                                 offset as *const GLvoid,
+                                                                 instances.count()),
+                    None => gl::DrawElements(prim_type,
+                                             length,
+                                             gl::UNSIGNED_SHORT,
+                                             offset as *const GLvoid)
+                }
             }
         }
         // Non-indexed
         else if let Some(count) = self.primitive_count {
             unsafe {
-                gl::BindVertexArray(self.vao);
-                gl::DrawArrays(prim_type, 0, count);
+                match instances {
+                    Some(instances) => gl::DrawArraysInstanced(prim_type, 0, count, instances.count()),
+                    None => gl::DrawArrays(prim_type, 0, count)
+                }
             }
         }
         else {
             log::warn!("No index data or primitive count for model");
         }
+
+        if let Some(instances) = instances {
+            instances.unbind_attribs();
+        }
     }
 }
 
@@ -1,6 +1,7 @@
 use std::ptr;
 use gl::types::*;
-use cgmath::{Vector2, Vector3, Vector4};
+use cgmath::{Vector2, Vector3, Vector4, Matrix4};
+use super::bindings::AttribBinding;
 
 /// A mesh
 pub struct Mesh {
@@ -74,6 +75,15 @@ impl Mesh {
         }
     }
 
+    /// Draw a range of the mesh's index buffer, starting at first_element
+    pub fn draw_indexed_range(&self, element_type: u32, first_element: i32, element_count: i32) {
+        unsafe {
+            gl::BindVertexArray(self.vao);
+            let offset = first_element as usize * std::mem::size_of::<u32>();
+            gl::DrawElements(element_type, element_count, gl::UNSIGNED_INT, offset as *const GLvoid);
+        }
+    }
+
     /// Create a vbo from a &[f32]
     fn create_vbo(vertex_buffer: &[f32]) -> u32 {
         unsafe {
@@ -152,6 +162,79 @@ impl Drop for Mesh {
     }
 }
 
+/// A buffer of per-instance model matrices, for drawing many copies of a mesh in one draw call.
+/// Shaders read the matrix from the InstanceTransform attribute when instancing is enabled.
+pub struct InstanceBuffer {
+    vbo: u32,
+    transforms: Vec<Matrix4<f32>>
+}
+
+impl InstanceBuffer {
+    /// Create a new InstanceBuffer from a list of model matrices
+    pub fn new(transforms: Vec<Matrix4<f32>>) -> Self {
+        let vbo = unsafe {
+            let mut vbo = 0;
+            gl::GenBuffers(1, &mut vbo);
+
+            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+            gl::BufferData(gl::ARRAY_BUFFER,
+                           (transforms.len() * std::mem::size_of::<Matrix4<f32>>()) as GLsizeiptr,
+                           transforms.as_ptr() as *const GLvoid,
+                           gl::STATIC_DRAW);
+            vbo
+        };
+
+        Self { vbo, transforms }
+    }
+
+    /// Get the model matrices
+    pub fn transforms(&self) -> &[Matrix4<f32>] {
+        &self.transforms
+    }
+
+    /// Get the number of instances
+    pub fn count(&self) -> i32 {
+        self.transforms.len() as i32
+    }
+
+    /// Bind the instance attribute to the currently bound vao, advancing once per instance
+    pub fn bind_attribs(&self) {
+        unsafe {
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+
+            // A mat4 attribute is four vec4 attributes, one for each column
+            let stride = std::mem::size_of::<Matrix4<f32>>() as i32;
+            for column in 0..4 {
+                let index = AttribBinding::InstanceTransform as u32 + column;
+                let offset = column as usize * std::mem::size_of::<Vector4<f32>>();
+                gl::EnableVertexAttribArray(index);
+                gl::VertexAttribPointer(index, 4, gl::FLOAT, gl::FALSE, stride, offset as *const GLvoid);
+                gl::VertexAttribDivisor(index, 1);
+            }
+        }
+    }
+
+    /// Unbind the instance attribute from the currently bound vao, so that non-instanced draws
+    /// with it don't read past the end of the buffer
+    pub fn unbind_attribs(&self) {
+        unsafe {
+            for column in 0..4 {
+                let index = AttribBinding::InstanceTransform as u32 + column;
+                gl::VertexAttribDivisor(index, 0);
+                gl::DisableVertexAttribArray(index);
+            }
+        }
+    }
+}
+
+impl Drop for InstanceBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.vbo);
+        }
+    }
+}
+
 /// An editable mesh, which stores the buffer as a vector in system memory so that it can be edited,
 /// and re-uploads the buffer next time it's drawn. Currently only supports non-indexed meshes.
 pub struct EditableMesh {
@@ -7,7 +7,8 @@ use crate::resources::ShaderSource;
 
 /// A shader program
 pub struct ShaderProgram {
-  id: u32
+  id: u32,
+  supports_instancing: bool
 }
 
 impl ShaderProgram {
@@ -40,11 +41,14 @@ impl ShaderProgram {
         // Create ShaderProgram struct
         program_id.map(|id| {
             // Create ShaderProgram instance
-            let program = ShaderProgram { id };
+            let mut program = ShaderProgram { id, supports_instancing: false };
 
             // Set standard uniform block bindings
             program.set_standard_uniform_block_bindings();
 
+            // Instanced draws need the shader to read the instance transform attribute
+            program.supports_instancing = program.has_attrib_at(bindings::AttribBinding::InstanceTransform as i32);
+
             program
         })
     }
@@ -54,6 +58,35 @@ impl ShaderProgram {
         self.id
     }
 
+    /// Get whether the shader reads the InstanceTransform attribute, and so can be used for
+    /// instanced draws
+    pub fn supports_instancing(&self) -> bool {
+        self.supports_instancing
+    }
+
+    /// Get whether the shader has an active attribute at a location
+    fn has_attrib_at(&self, location: i32) -> bool {
+        unsafe {
+            let mut attrib_count = 0;
+            gl::GetProgramiv(self.id, gl::ACTIVE_ATTRIBUTES, &mut attrib_count);
+
+            let mut name_buf = [0u8; 256];
+            for i in 0..attrib_count as u32 {
+                let mut name_len = 0;
+                let mut size = 0;
+                let mut attrib_type = 0;
+                gl::GetActiveAttrib(self.id, i, name_buf.len() as i32, &mut name_len, &mut size, &mut attrib_type,
+                    name_buf.as_mut_ptr() as *mut GLchar);
+
+                if gl::GetAttribLocation(self.id, name_buf.as_ptr() as *const GLchar) == location {
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
     /// Get a uniform location
     pub fn get_loc(&self, uniform_name: &str) -> i32 {
         let c_str = CString::new(uniform_name).unwrap();
@@ -6,11 +6,14 @@ use image::DynamicImage;
 use image::io::Reader;
 use super::bindings;
 
-/// A texture
+/// A texture. Paletted textures store palette indices in the red channel, normalized to 0..1,
+/// and have a separate palette texture that's bound to the palette slot, so shaders look up the
+/// final color with texelFetch(palette, ivec2(int(index * 255.0 + 0.5), 0), 0).
 pub struct Texture {
     id: u32,
     width: i32,
-    height: i32
+    height: i32,
+    palette: Option<Box<Texture>>
 }
 
 pub struct TextureParams {
@@ -28,6 +31,17 @@ impl TextureParams {
     pub fn repeat_nearest() -> Self {
         Self::new(gl::REPEAT, gl::REPEAT, gl::NEAREST, gl::NEAREST)
     }
+
+    /// Get the params with any linear filtering switched to nearest, since palette indices can't
+    /// be interpolated
+    pub fn without_linear_filtering(self) -> Self {
+        let nearest = |filter| match filter {
+            gl::LINEAR => gl::NEAREST,
+            gl::LINEAR_MIPMAP_NEAREST | gl::LINEAR_MIPMAP_LINEAR | gl::NEAREST_MIPMAP_LINEAR => gl::NEAREST_MIPMAP_NEAREST,
+            filter => filter
+        };
+        Self::new(self.horz_wrap, self.vert_wrap, nearest(self.min_filter), nearest(self.mag_filter))
+    }
 }
 
 impl Texture {
@@ -85,6 +99,8 @@ impl Texture {
             gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, params.min_filter as i32);
             gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, params.mag_filter as i32);
 
+            // Single channel rows aren't necessarily 4 byte aligned
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
             gl::TexImage2D(gl::TEXTURE_2D,
                            0,
                            dest_format as i32,
@@ -95,7 +111,56 @@ impl Texture {
                            source_type,
                            &buf[0] as *const u8 as *const GLvoid);
 
-            Ok(Texture { id: texture, width, height })
+            Ok(Texture { id: texture, width, height, palette: None })
+        }
+    }
+
+    /// Load a new paletted texture from a buffer of 8-bit palette indices and an rgba8 palette
+    pub fn new_paletted(indices: &[u8], width: i32, height: i32, palette: &[u8], params: TextureParams)
+        -> Result<Texture, Box<dyn Error>>
+    {
+        let palette_params = TextureParams::new(gl::CLAMP_TO_EDGE, gl::CLAMP_TO_EDGE, gl::NEAREST, gl::NEAREST);
+        let palette = Texture::new_from_buf(palette, (palette.len() / 4) as i32, 1, gl::RGBA, gl::UNSIGNED_BYTE,
+            gl::SRGB8_ALPHA8, palette_params)?;
+
+        let mut texture = Texture::new_from_buf(indices, width, height, gl::RED, gl::UNSIGNED_BYTE, gl::R8,
+            params.without_linear_filtering())?;
+        texture.palette = Some(Box::new(palette));
+
+        Ok(texture)
+    }
+
+    /// Replace the palette of a paletted texture, e.g. for palette swaps. The palette should be
+    /// rgba8, with at least as many colors as the original.
+    pub fn set_palette(&self, palette: &[u8]) {
+        let palette_texture = self.palette.as_ref().expect("set_palette: Texture isn't paletted");
+        palette_texture.upload_mip_level(0, palette, (palette.len() / 4) as i32, 1, gl::RGBA, gl::UNSIGNED_BYTE,
+            gl::SRGB8_ALPHA8);
+    }
+
+    /// Check whether this is a paletted texture
+    pub fn has_palette(&self) -> bool {
+        self.palette.is_some()
+    }
+
+    /// Upload a pre-generated mip level. Levels should be uploaded in order, since the texture's max
+    /// level is set to the last one uploaded.
+    pub fn upload_mip_level(&self, level: i32, buf: &[u8], width: i32, height: i32, source_format: u32,
+                            source_type: u32, dest_format: u32)
+    {
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.id);
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::TexImage2D(gl::TEXTURE_2D,
+                           level,
+                           dest_format as i32,
+                           width,
+                           height,
+                           0,
+                           source_format,
+                           source_type,
+                           &buf[0] as *const u8 as *const GLvoid);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, level);
         }
     }
 
@@ -104,12 +169,18 @@ impl Texture {
         unsafe { gl::GenerateTextureMipmap(self.id) }
     }
 
-    /// Bind texture
+    /// Bind texture, and its palette to the slot's palette slot if it has one
     pub fn bind(&self, slot: bindings::TextureSlot) {
+        let palette_slot = slot.palette_slot();
+
         unsafe {
             gl::ActiveTexture(gl::TEXTURE0 + slot as u32);
             gl::BindTexture(gl::TEXTURE_2D, self.id)
         }
+
+        if let Some(palette) = &self.palette {
+            palette.bind(palette_slot);
+        }
     }
 
     /// Unbind texture
@@ -121,7 +121,10 @@ pub struct GlobalParams {
     pub render_res: std140::vec2,
     pub fog_color: std140::vec3,
     pub fog_dist: std140::vec2,
-    pub lighting_strength: std140::float
+    pub lighting_strength: std140::float,
+    /// Whether the model matrix is multiplied by the InstanceTransform attribute, only set for
+    /// shaders that declare it
+    pub instancing_enabled: std140::boolean
 }
 
 impl Default for GlobalParams {
@@ -142,7 +145,8 @@ impl Default for GlobalParams {
             render_fov: (std::f32::consts::PI).to_std140(),
             fog_color: vec3(0.0, 0.0, 0.0).to_std140(),
             fog_dist: vec2(0.0, 0.0).to_std140(),
-            lighting_strength: (1.0).to_std140()
+            lighting_strength: (1.0).to_std140(),
+            instancing_enabled: false.to_std140()
         }
     }
 }
@@ -188,14 +192,29 @@ impl UniformBuffer<GlobalParams> {
 #[derive(UniformSetters)]
 pub struct MaterialParams {
     pub has_base_color_texture: std140::boolean,
-    pub base_color: std140::vec4
+    pub has_palette: std140::boolean,
+    pub base_color: std140::vec4,
+    /// Fragments with alpha below this are discarded, 0 for materials that aren't alpha masked
+    pub alpha_cutoff: std140::float,
+    pub has_emissive_texture: std140::boolean,
+    pub has_emissive_palette: std140::boolean,
+    pub emissive: std140::vec3,
+    pub unlit: std140::boolean,
+    pub use_vertex_colors: std140::boolean
 }
 
 impl Default for MaterialParams {
     fn default() -> Self {
         MaterialParams {
             has_base_color_texture: false.to_std140(),
-            base_color: vec4(1.0, 1.0, 1.0, 1.0).to_std140()
+            has_palette: false.to_std140(),
+            base_color: vec4(1.0, 1.0, 1.0, 1.0).to_std140(),
+            alpha_cutoff: (0.0).to_std140(),
+            has_emissive_texture: false.to_std140(),
+            has_emissive_palette: false.to_std140(),
+            emissive: vec3(0.0, 0.0, 0.0).to_std140(),
+            unlit: false.to_std140(),
+            use_vertex_colors: true.to_std140()
         }
     }
 }
@@ -5,7 +5,7 @@ use std::time::Duration;
 
 use bevy_ecs::query::Without;
 use bevy_ecs::system::{Local, Res, Query, ResMut, ParamSet};
-use cgmath::{SquareMatrix, Matrix4, vec2, InnerSpace, vec4, vec3};
+use cgmath::{SquareMatrix, Matrix4, Vector3, vec2, InnerSpace, vec4, vec3};
 use dreamfield_system::intersection::{Collider, Shape};
 use renderer_resources::RendererResources;
 use crate::gl_backend::*;
@@ -14,9 +14,9 @@ use crate::resources::{ModelManager, TextureManager, ShaderManager, FontManager}
 use crate::components::{PlayerCamera, Visual, ScreenEffect, RunTime, TextBox, DiagnosticsTextBox};
 use dreamfield_system::WindowSettings;
 use dreamfield_system::world::WorldChunkManager;
-use dreamfield_system::world::world_chunk::{WorldChunk, WorldChunkMesh, ChunkIndex};
-use dreamfield_system::world::world_texture::WorldTexture;
-use dreamfield_system::world::wrapped_vectors::WrappedVector3;
+use dreamfield_system::world::world_chunk::{WorldChunk, WorldChunkMesh, WorldChunkMaterial, WorldAlphaMode, ChunkIndex,
+    WorldLightType, CHUNK_SIZE};
+use dreamfield_system::world::world_texture::{WorldTexture, TextureFormat};
 use dreamfield_system::resources::{SimTime, Diagnostics};
 use dreamfield_system::components::{Transform, Disabled};
 
@@ -39,6 +39,15 @@ pub fn renderer_system(
 {
     let local = &mut *local;
 
+    // Discard the world meshes and textures if the level has changed, as their ids are per level
+    if local.world_level_generation != world.level_generation() {
+        log::info!("Level changed, clearing world render caches");
+        local.world_level_generation = world.level_generation();
+        local.world_meshes.clear();
+        local.world_instances.clear();
+        local.world_textures.clear();
+    }
+
     // Update window size if it's changed
     if window_settings.is_added() || window_settings.is_changed() {
         let (width, height) = window_settings.window_size;
@@ -97,6 +106,10 @@ pub fn renderer_system(
     local.ubo_global.set_mat_view_derive(&player_camera.view);
     local.ubo_global.bind(bindings::UniformBlockBinding::GlobalParams);
 
+    // Update the world lights around the camera
+    update_world_lights(local, &world, &player_camera);
+    local.ubo_lights.bind(bindings::UniformBlockBinding::LightParams);
+
     // Bind framebuffer and clear
     unsafe { gl::Viewport(0, 0, player_camera.render_res.x as i32, player_camera.render_res.y as i32) };
     local.framebuffer.as_ref().unwrap().bind_draw();
@@ -137,6 +150,12 @@ pub fn renderer_system(
         draw_colliders(local, &models, &colliders_query);
     }
 
+    // Reset any render state that models' materials changed
+    unsafe {
+        gl::Disable(gl::BLEND);
+        gl::DepthMask(gl::TRUE);
+    }
+
     // Render post-scene effects
     render_screen_effects(RunTime::PostScene, local, &mut textures, &mut shaders, &mut effect_query);
 
@@ -151,6 +170,56 @@ pub fn renderer_system(
     final_composite(local, &window_settings, player_camera);
 }
 
+/// Gather the world lights and the lights nearest the camera from the loaded world chunks, and upload
+/// them. Directional lights always come first, and other lights are ordered by the distance from the
+/// camera to the edge of their range.
+fn update_world_lights(local: &mut RendererResources, world: &WorldChunkManager, camera: &PlayerCamera) {
+    let camera_pos = camera.view.invert().unwrap().w.truncate();
+
+    let mut lights: Vec<_> = world.loaded_chunks()
+        .flat_map(|(_, chunk)| chunk.lights().iter())
+        .chain(world.world_lights().iter())
+        .filter(|light| !light.baked())
+        .map(|light| {
+            let distance = match light.light_type() {
+                WorldLightType::Directional => f32::NEG_INFINITY,
+                _ => (light.pos() - camera_pos).magnitude() - light.range().unwrap_or(0.0)
+            };
+            (distance, light)
+        })
+        .collect();
+    lights.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    for i in 0..LIGHT_COUNT {
+        let light = match lights.get(i) {
+            Some((_, light)) => {
+                let light_type = match light.light_type() {
+                    WorldLightType::Point => LightType::PointLight,
+                    WorldLightType::Directional => LightType::DirectionalLight,
+                    WorldLightType::Spot => LightType::SpotLight,
+                };
+
+                Light {
+                    enabled: true.to_std140(),
+                    light_type: (light_type as i32).to_std140(),
+                    intensity: light.intensity().to_std140(),
+                    range: light.range().unwrap_or(0.0).to_std140(),
+                    inner_cone_angle: light.inner_cone_angle().unwrap_or(0.0).to_std140(),
+                    outer_cone_angle: light.outer_cone_angle().unwrap_or(0.0).to_std140(),
+                    color: light.color().to_std140(),
+                    light_dir: light.dir().to_std140(),
+                    light_pos: light.pos().to_std140(),
+                }
+            },
+            None => Light::default()
+        };
+
+        local.ubo_lights.set_lights(i, &light);
+    }
+
+    local.ubo_lights.upload_changed();
+}
+
 /// Draw the world
 fn draw_world(local: &mut RendererResources, mut world: &mut ResMut<WorldChunkManager>, models: &Res<ModelManager>,
     camera: &PlayerCamera)
@@ -216,22 +285,58 @@ fn draw_world(local: &mut RendererResources, mut world: &mut ResMut<WorldChunkMa
     let (view_min_chunk_x, view_min_chunk_z) = WorldChunk::point_to_chunk_index_2d(&view_aabb_min);
     let (view_max_chunk_x, view_max_chunk_z) = WorldChunk::point_to_chunk_index_2d(&view_aabb_max);
 
+    let mut chunks = Vec::new();
     for chunk_x in view_min_chunk_x..=view_max_chunk_x {
         for chunk_z in view_min_chunk_z..=view_max_chunk_z {
-            draw_world_chunk(local, &mut world, &models, (chunk_x, chunk_z));
+            chunks.push((chunk_x, chunk_z));
         }
     }
+
+    // Draw opaque meshes first, then blended meshes from back to front so that they blend over
+    // everything behind them
+    for chunk_index in chunks.iter() {
+        draw_world_chunk(local, &mut world, &models, *chunk_index, &pos, false);
+    }
+
+    let chunk_distance = |(x, z): &ChunkIndex| {
+        let center = vec2((*x as f32 + 0.5) * CHUNK_SIZE, (*z as f32 + 0.5) * CHUNK_SIZE);
+        (center - vec2(pos.x, pos.z)).magnitude2()
+    };
+    chunks.sort_by(|a, b| chunk_distance(b).total_cmp(&chunk_distance(a)));
+
+    unsafe {
+        gl::Enable(gl::BLEND);
+        gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+        gl::DepthMask(gl::FALSE);
+    }
+
+    for chunk_index in chunks.iter() {
+        draw_world_chunk(local, &mut world, &models, *chunk_index, &pos, true);
+    }
+
+    unsafe {
+        gl::Disable(gl::BLEND);
+        gl::DepthMask(gl::TRUE);
+        gl::Disable(gl::CULL_FACE);
+    }
 }
 
-/// Draw a WorldChunk
+/// Draw a WorldChunk, at a level of detail based on its distance from the camera. Either draws the
+/// chunk's instances and opaque meshes, or its blended meshes from back to front.
 fn draw_world_chunk(local: &mut RendererResources, world: &mut ResMut<WorldChunkManager>, models: &Res<ModelManager>,
-    chunk_index: ChunkIndex)
+    chunk_index: ChunkIndex, camera_pos: &Vector3<f32>, blended: bool)
 {
     let mut textures_to_load = Vec::new();
 
     if let Some(chunk) = world.get_or_load_chunk(chunk_index) {
-        // Draw instances in chunk
-        for instance in chunk.instances().iter() {
+        // Draw instances in chunk, uploading each instance's transforms once. Only the chunk's own
+        // meshes are culled, so turn culling off that a previous chunk's material may have left on
+        unsafe { gl::Disable(gl::CULL_FACE) }
+        let instances = match blended {
+            true => &[],
+            false => chunk.instances()
+        };
+        for (i, instance) in instances.iter().enumerate() {
             // Get reference to model from the renderer resources cache, loading it if it's not in there
             let model = local.models
                 .entry(instance.mesh_name().to_string())
@@ -240,9 +345,19 @@ fn draw_world_chunk(local: &mut RendererResources, world: &mut ResMut<WorldChunk
                     Arc::new(GltfModel::from_buf(data).unwrap())
                 });
 
-            for WrappedVector3(point) in instance.points().iter() {
-                let transform = Matrix4::from_translation(*point);
-                model.render(&transform, &mut local.ubo_global, &mut local.ubo_joints, true);
+            let instances = local.world_instances
+                .entry((chunk_index, i))
+                .or_insert_with(|| {
+                    InstanceBuffer::new(instance.transforms().iter().map(|transform| *transform.as_mat()).collect())
+                });
+
+            if local.ps1_tess_shader.supports_instancing() {
+                model.render_instanced(instances, &mut local.ubo_global, &mut local.ubo_joints, true);
+            }
+            else {
+                for transform in instances.transforms() {
+                    model.render(transform, &mut local.ubo_global, &mut local.ubo_joints, true);
+                }
             }
         }
 
@@ -252,38 +367,31 @@ fn draw_world_chunk(local: &mut RendererResources, world: &mut ResMut<WorldChunk
         local.ubo_joints.set_skinning_enabled(&false);
         local.ubo_joints.upload_changed();
 
-        for mesh in chunk.meshes().iter() {
-            // Bind material
-            local.ubo_material.set_has_base_color_texture(&false);
-            if let Some(material) = mesh.material() {
-                if let Some(texture_id) = material.base_color_tex() {
-                    if let Some(texture) = local.world_textures.get(texture_id) {
-                        local.ubo_material.set_has_base_color_texture(&true);
-                        texture.bind(TextureSlot::BaseColor);
-                    }
-                    else {
-                        // Annoyingly we can't just load it now as we still have world borrowed
-                        // mutably through the reference to chunk
-                        textures_to_load.push(*texture_id);
-                    }
-                }
-                else {
-                }
+        let lod = chunk.lod_for_distance(chunk.aabb().distance_to_point(camera_pos).unwrap_or(0.0));
+
+        let mut meshes: Vec<&WorldChunkMesh> = chunk.meshes()
+            .iter()
+            .filter(|mesh| {
+                let mesh_blended = mesh.material().as_ref()
+                    .map(|material| material.alpha_mode() == WorldAlphaMode::Blend)
+                    .unwrap_or(false);
+                mesh_blended == blended
+            })
+            .collect();
+
+        if blended {
+            let mesh_distance = |mesh: &WorldChunkMesh| mesh.aabb().distance_to_point(camera_pos).unwrap_or(0.0);
+            meshes.sort_by(|a, b| mesh_distance(b).total_cmp(&mesh_distance(a)));
+        }
 
-                local.ubo_material.set_base_color(material.base_color().as_vec());
-                local.ubo_material.bind(bindings::UniformBlockBinding::MaterialParams);
-            }
-            else {
-                local.ubo_material.set_base_color(&vec4(1.0, 1.0, 1.0, 1.0));
-                local.ubo_material.set_has_base_color_texture(&false);
-                local.ubo_material.bind(bindings::UniformBlockBinding::MaterialParams);
-            }
-            local.ubo_material.upload_changed();
+        for mesh in meshes {
+            bind_world_material(local, mesh.material(), &mut textures_to_load);
 
-            // Draw mesh
-            let count = mesh.indices().len() as i32;
+            // Draw mesh, the lods are stored after the full detail indices in the index buffer
+            let first = (0..lod.min(mesh.lods().len())).map(|i| mesh.lod_indices(i).len()).sum::<usize>() as i32;
+            let count = mesh.lod_indices(lod).len() as i32;
             let mesh = get_gl_mesh(local, &mesh);
-            mesh.draw_indexed(gl::PATCHES, count);
+            mesh.draw_indexed_range(gl::PATCHES, first, count);
         }
     }
 
@@ -296,13 +404,79 @@ fn draw_world_chunk(local: &mut RendererResources, world: &mut ResMut<WorldChunk
     }
 }
 
+/// Bind the material for a world mesh, or the default material if it doesn't have one. Textures
+/// that aren't loaded yet are added to textures_to_load.
+fn bind_world_material(local: &mut RendererResources, material: &Option<WorldChunkMaterial>,
+    textures_to_load: &mut Vec<i32>)
+{
+    let ubo_material = &mut local.ubo_material;
+    ubo_material.set_has_base_color_texture(&false);
+    ubo_material.set_has_palette(&false);
+    ubo_material.set_has_emissive_texture(&false);
+    ubo_material.set_has_emissive_palette(&false);
+
+    if let Some(material) = material {
+        if let Some(texture_id) = material.base_color_tex() {
+            if let Some(texture) = local.world_textures.get(texture_id) {
+                ubo_material.set_has_base_color_texture(&true);
+                ubo_material.set_has_palette(&texture.has_palette());
+                texture.bind(TextureSlot::BaseColor);
+            }
+            else {
+                // Annoyingly we can't just load it now as we still have world borrowed
+                // mutably through the reference to chunk
+                textures_to_load.push(*texture_id);
+            }
+        }
+
+        if let Some(texture_id) = material.emissive_tex() {
+            if let Some(texture) = local.world_textures.get(texture_id) {
+                ubo_material.set_has_emissive_texture(&true);
+                ubo_material.set_has_emissive_palette(&texture.has_palette());
+                texture.bind(TextureSlot::Emissive);
+            }
+            else {
+                textures_to_load.push(*texture_id);
+            }
+        }
+
+        let alpha_cutoff = match material.alpha_mode() {
+            WorldAlphaMode::Mask => material.alpha_cutoff(),
+            _ => 0.0
+        };
+
+        ubo_material.set_base_color(material.base_color().as_vec());
+        ubo_material.set_alpha_cutoff(&alpha_cutoff);
+        ubo_material.set_emissive(material.emissive().as_vec());
+        ubo_material.set_unlit(&material.unlit());
+        ubo_material.set_use_vertex_colors(&material.vertex_colors());
+
+        unsafe {
+            if material.double_sided() {
+                gl::Disable(gl::CULL_FACE);
+            }
+            else {
+                gl::Enable(gl::CULL_FACE);
+            }
+        }
+    }
+    else {
+        ubo_material.set_base_color(&vec4(1.0, 1.0, 1.0, 1.0));
+        ubo_material.set_alpha_cutoff(&0.0);
+        ubo_material.set_emissive(&vec3(0.0, 0.0, 0.0));
+        ubo_material.set_unlit(&false);
+        ubo_material.set_use_vertex_colors(&true);
+        unsafe { gl::Disable(gl::CULL_FACE) }
+    }
+
+    ubo_material.bind(bindings::UniformBlockBinding::MaterialParams);
+}
+
 // Get the gl mesh for a world mesh
 fn get_gl_mesh<'a>(local: &'a mut RendererResources, mesh: &WorldChunkMesh) -> &'a Mesh {
     local.world_meshes
         .entry(mesh.index())
         .or_insert_with(|| {
-            // TODO: pregenerate them as u32, or just load them as u16
-            let index_buffer = mesh.indices().iter().map(|i| *i as u32).collect::<Vec<u32>>();
             let buffer_layout = vec![
                 VertexAttrib {
                     index: AttribBinding::Positions as u32,
@@ -323,9 +497,20 @@ fn get_gl_mesh<'a>(local: &'a mut RendererResources, mesh: &WorldChunkMesh) -> &
                     index: AttribBinding::Colors as u32,
                     attrib_type: gl::FLOAT,
                     size: 4
+                },
+                VertexAttrib {
+                    index: AttribBinding::Tangents as u32,
+                    attrib_type: gl::FLOAT,
+                    size: 4
                 }
             ];
 
+            // Store every level of detail in the same index buffer
+            let mut index_buffer = mesh.indices().to_vec();
+            for lod in mesh.lods() {
+                index_buffer.extend_from_slice(lod);
+            }
+
             Mesh::new_indexed(mesh.vertices(), &index_buffer, &buffer_layout)
         })
 }
@@ -335,10 +520,41 @@ fn get_gl_texture<'a>(local: &'a mut RendererResources, texture: &WorldTexture)
     local.world_textures
         .entry(texture.index())
         .or_insert_with(|| {
-            let dest_format = gl::SRGB8_ALPHA8;
-            let tex_params = TextureParams::repeat_nearest();
-            Texture::new_from_buf(&texture.pixels(), texture.width() as i32, texture.height() as i32, texture.format(),
-                gl::UNSIGNED_BYTE, dest_format, tex_params).expect("Failed to create world texture")
+            // World textures are already processed by the world builder, so they're just uploaded
+            let mut tex_params = TextureParams::repeat_nearest();
+            if !texture.mips().is_empty() {
+                tex_params.min_filter = gl::NEAREST_MIPMAP_NEAREST;
+            }
+
+            let (width, height) = (texture.width() as i32, texture.height() as i32);
+            match texture.format() {
+                TextureFormat::Rgba8 => {
+                    let dest_format = gl::SRGB8_ALPHA8;
+                    let tex = Texture::new_from_buf(&texture.pixels(), width, height, gl::RGBA, gl::UNSIGNED_BYTE,
+                        dest_format, tex_params).expect("Failed to create world texture");
+
+                    for (i, mip) in texture.mips().iter().enumerate() {
+                        let level = i + 1;
+                        let (width, height) = texture.mip_size(level);
+                        tex.upload_mip_level(level as i32, mip, width as i32, height as i32, gl::RGBA,
+                            gl::UNSIGNED_BYTE, dest_format);
+                    }
+
+                    tex
+                },
+                TextureFormat::Indexed4 | TextureFormat::Indexed8 => {
+                    let tex = Texture::new_paletted(&texture.unpack_indices(0), width, height, texture.palette(),
+                        tex_params).expect("Failed to create world texture");
+
+                    for level in 1..=texture.mips().len() {
+                        let (width, height) = texture.mip_size(level);
+                        tex.upload_mip_level(level as i32, &texture.unpack_indices(level), width as i32,
+                            height as i32, gl::RED, gl::UNSIGNED_BYTE, gl::R8);
+                    }
+
+                    tex
+                }
+            }
         })
 }
 
@@ -402,9 +618,7 @@ fn draw_colliders(local: &mut RendererResources, models: &Res<ModelManager>,
     unsafe { gl::Enable(gl::DEPTH_TEST); }
     local.ps1_tess_shader.use_program();
 
-    local.ubo_material.set_has_base_color_texture(&false);
-    local.ubo_material.set_base_color(&vec4(1.0, 1.0, 1.0, 1.0));
-    local.ubo_material.bind(bindings::UniformBlockBinding::MaterialParams);
+    bind_world_material(local, &None, &mut Vec::new());
 
     for (transform, collider) in colliders_query.iter() {
         // Get sphere model, loading it if it isn't already loaded
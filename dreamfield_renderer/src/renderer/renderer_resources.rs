@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 use bevy_ecs::world::{FromWorld, World};
+use dreamfield_system::world::world_chunk::ChunkIndex;
 use crate::gl_backend::{Mesh, EditableMesh, VertexAttrib, Texture, GltfModel, UniformBuffer,
-    Framebuffer, GlobalParams, JointParams, ShaderProgram, MaterialParams};
+    Framebuffer, GlobalParams, JointParams, ShaderProgram, MaterialParams, LightParams, InstanceBuffer};
 use crate::resources::ShaderManager;
 
 /// The renderer state resource
@@ -11,6 +12,7 @@ pub struct RendererResources {
     pub ubo_global: UniformBuffer<GlobalParams>,
     pub ubo_joints: UniformBuffer<JointParams>,
     pub ubo_material: UniformBuffer<MaterialParams>,
+    pub ubo_lights: UniformBuffer<LightParams>,
     pub framebuffer_size: Option<(i32, i32)>,
     pub framebuffer: Option<Framebuffer>,
     pub yiq_framebuffer: Option<Framebuffer>,
@@ -20,7 +22,9 @@ pub struct RendererResources {
     pub blit_shader: Arc<ShaderProgram>,
     pub models: HashMap<String, Arc<GltfModel>>,
     pub world_meshes: HashMap<i32, Mesh>,
+    pub world_instances: HashMap<(ChunkIndex, usize), InstanceBuffer>,
     pub world_textures: HashMap<i32, Texture>,
+    pub world_level_generation: u32,
     pub text_mesh: EditableMesh,
 }
 
@@ -32,6 +36,7 @@ impl FromWorld for RendererResources {
         let ubo_global = UniformBuffer::<GlobalParams>::new();
         let ubo_joints = UniformBuffer::<JointParams>::new();
         let ubo_material = UniformBuffer::<MaterialParams>::new();
+        let ubo_lights = UniformBuffer::<LightParams>::new();
 
         // Load meshes
         let full_screen_rect = Mesh::new_indexed(
@@ -69,6 +74,7 @@ impl FromWorld for RendererResources {
             ubo_global,
             ubo_joints,
             ubo_material,
+            ubo_lights,
             framebuffer_size: None,
             framebuffer: None,
             yiq_framebuffer: None,
@@ -78,7 +84,9 @@ impl FromWorld for RendererResources {
             blit_shader,
             models: HashMap::new(),
             world_meshes: HashMap::new(),
+            world_instances: HashMap::new(),
             world_textures: HashMap::new(),
+            world_level_generation: 0,
             text_mesh
         }
     }
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use dreamfield_system::world::world_builder::{WorldBuilder, WorldModel, WorldBuildSettings, ClipMode};
+use serde::Deserialize;
+
+/// The default output directory if neither the command line or project file specify one
+const DEFAULT_OUT_DIR: &str = "world_chunks";
+
+const USAGE: &str = "Usage: world_builder [--project <project.json>] [--out-dir <dir>] [model.gltf|model.glb ...]
+
+Builds glTF models into world chunks and textures.
+
+Options:
+    --project <file>    A json project file, e.g. { \"out_dir\": \"chunks\", \"models\": [\"level.glb\"],
+                        \"instance_models\": { \"tree\": \"tree.glb\" },
+                        \"levels\": { \"cave\": [\"cave.glb\"] },
+                        \"settings\": { \"clip_mode\": \"exact\" } }.
+                        Paths in the project file are relative to the project file.
+                        Models are identified by their path relative to the project file, or to
+                        the current directory for models on the command line, which entity IDs
+                        are derived from.
+    --out-dir <dir>     The directory to write chunks and textures to, overriding the project file.
+    --exact-clipping    Split triangles at chunk borders instead of keeping them whole in every
+                        chunk they overlap.
+    --no-optimize       Don't merge, weld or reorder meshes.
+    --help              Show this message.";
+
+/// A world builder project file
+#[derive(Deserialize)]
+struct Project {
+    #[serde(default)]
+    out_dir: Option<PathBuf>,
+
+    #[serde(default)]
+    models: Vec<PathBuf>,
+
+    /// Models instanced in the world by name, which instances get their collision shape from
+    #[serde(default)]
+    instance_models: HashMap<String, PathBuf>,
+
+    /// Named levels, each built into its own subdirectory of the output directory
+    #[serde(default)]
+    levels: HashMap<String, Vec<PathBuf>>,
+
+    #[serde(default)]
+    settings: WorldBuildSettings,
+}
+
+/// The parsed command line arguments
+#[derive(Default)]
+struct Args {
+    project: Option<PathBuf>,
+    out_dir: Option<PathBuf>,
+    models: Vec<PathBuf>,
+    exact_clipping: bool,
+    no_optimize: bool,
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let args = match parse_args(std::env::args().skip(1))? {
+        Some(args) => args,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    // Models are identified by their path relative to the project directory, or the current
+    // directory for models given on the command line
+    let mut out_dir = args.out_dir;
+    let mut model_paths = Vec::new();
+    let mut instance_model_paths = HashMap::new();
+    let mut level_paths = Vec::new();
+    let mut settings = WorldBuildSettings::default();
+
+    // Load the project file, if there is one
+    if let Some(project_path) = args.project {
+        let project: Project = serde_json::from_str(&std::fs::read_to_string(&project_path)
+            .map_err(|err| format!("Failed to read project {}: {}", project_path.display(), err))?)
+            .map_err(|err| format!("Failed to parse project {}: {}", project_path.display(), err))?;
+        project.settings.textures.validate()
+            .map_err(|err| format!("Invalid settings in project {}: {}", project_path.display(), err))?;
+
+        let project_dir = project_path.parent().unwrap_or(Path::new(""));
+        out_dir = out_dir.or(project.out_dir.map(|dir| project_dir.join(dir)));
+        model_paths.extend(project.models.iter().map(|model| (project_dir.to_path_buf(), model.clone())));
+        instance_model_paths.extend(project.instance_models.iter()
+            .map(|(name, model)| (name.clone(), (project_dir.to_path_buf(), model.clone()))));
+        level_paths.extend(project.levels.iter()
+            .map(|(name, models)| (name.clone(), models.iter()
+                .map(|model| (project_dir.to_path_buf(), model.clone()))
+                .collect::<Vec<_>>())));
+        level_paths.sort();
+        settings = project.settings;
+    }
+
+    model_paths.extend(args.models.into_iter().map(|model| (PathBuf::from("."), model)));
+
+    if args.exact_clipping {
+        settings.clip_mode = ClipMode::Exact;
+    }
+    if args.no_optimize {
+        settings.optimize_meshes = false;
+    }
+
+    if model_paths.is_empty() && level_paths.is_empty() {
+        return Err(format!("No models specified\n\n{}", USAGE).into());
+    }
+
+    let models = model_paths
+        .iter()
+        .map(|(root_dir, path)| WorldModel::from_file(root_dir, path)
+            .map_err(|err| format!("Failed to read model {}: {}", root_dir.join(path).display(), err)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let out_dir = out_dir.unwrap_or(PathBuf::from(DEFAULT_OUT_DIR));
+    let mut builder = WorldBuilder::new_standalone(&out_dir, models);
+    builder.set_settings(settings);
+    for (name, (root_dir, path)) in instance_model_paths.iter() {
+        let model = WorldModel::from_file(root_dir, path)
+            .map_err(|err| format!("Failed to read instance model {}: {}", root_dir.join(path).display(), err))?;
+        builder.add_instance_model(name, model);
+    }
+    for (name, paths) in level_paths.iter() {
+        let models = paths
+            .iter()
+            .map(|(root_dir, path)| WorldModel::from_file(root_dir, path)
+                .map_err(|err| format!("Failed to read model {} in level {}: {}", root_dir.join(path).display(), name,
+                    err)))
+            .collect::<Result<Vec<_>, _>>()?;
+        builder.add_level(name, &models);
+    }
+    let summary = builder.build()?;
+
+    println!("Wrote world to {}", out_dir.display());
+    if !level_paths.is_empty() {
+        let names: Vec<&str> = level_paths.iter().map(|(name, _)| name.as_str()).collect();
+        println!("  Levels:    {}", names.join(", "));
+    }
+    println!("  Models:    {} built, {} unchanged", summary.models_built, summary.models_cached);
+    println!("Rebuilt, not counting anything that was unchanged:");
+    println!("  Chunks:    {}", summary.chunks);
+    println!("  Meshes:    {} ({} before optimization)", summary.meshes.meshes, summary.unoptimized_meshes.meshes);
+    println!("  Vertices:  {} ({} before optimization)", summary.meshes.vertices, summary.unoptimized_meshes.vertices);
+    println!("  Triangles: {} ({} before optimization)", summary.meshes.triangles, summary.unoptimized_meshes.triangles);
+    println!("  Textures:  {}", summary.textures);
+    println!("  Entities:  {}", summary.entities);
+
+    Ok(())
+}
+
+/// Parse the command line arguments, returning None if the usage should be shown
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>, String> {
+    let mut parsed = Args::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(None),
+            "--project" => {
+                parsed.project = Some(args.next().ok_or("--project requires a file")?.into());
+            },
+            "--out-dir" | "-o" => {
+                parsed.out_dir = Some(args.next().ok_or("--out-dir requires a directory")?.into());
+            },
+            "--exact-clipping" => parsed.exact_clipping = true,
+            "--no-optimize" => parsed.no_optimize = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}\n\n{}", arg, USAGE)),
+            _ => parsed.models.push(arg.into()),
+        }
+    }
+
+    Ok(Some(parsed))
+}
@@ -1,8 +1,9 @@
 use bevy_ecs::prelude::Component;
-use cgmath::{Vector3, vec3, Matrix3, SquareMatrix};
+use cgmath::{Vector3, vec3, Matrix3, Matrix4, SquareMatrix, InnerSpace};
+use serde::{Serialize, Deserialize};
 
 /// A component for representing an entities name
-#[derive(Component)]
+#[derive(Component, Serialize, Deserialize)]
 pub struct EntityName {
     pub name: String
 }
@@ -14,7 +15,7 @@ impl EntityName {
 }
 
 /// A component for representing object transforms
-#[derive(Component)]
+#[derive(Component, Serialize, Deserialize)]
 pub struct Transform {
     pub pos: Vector3<f32>,
     pub rot: Matrix3<f32>,
@@ -27,6 +28,17 @@ impl Transform {
             rot,
         }
     }
+
+    /// Create a transform from a world matrix, discarding any scale
+    pub fn from_matrix(mat: &Matrix4<f32>) -> Self {
+        let pos = mat.w.truncate();
+        let rot = Matrix3::from_cols(
+            mat.x.truncate().normalize(),
+            mat.y.truncate().normalize(),
+            mat.z.truncate().normalize());
+
+        Self::new(pos, rot)
+    }
 }
 
 impl Default for Transform {
@@ -38,4 +38,8 @@ impl FixedTimestep {
     pub fn sim_time(&self) -> f64 {
         self.sim_time
     }
+
+    pub fn set_sim_time(&mut self, sim_time: f64) {
+        self.sim_time = sim_time;
+    }
 }
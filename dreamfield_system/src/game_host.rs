@@ -96,6 +96,13 @@ impl GameHost {
                 update_schedule.run(&mut world);
                 let update_time = update_start.elapsed();
 
+                // If the update changed the sim time (e.g. by loading a save game), continue from there
+                world.resource_scope(|_, sim_time: Mut<SimTime>| {
+                    if sim_time.sim_time != fixed_timestep.sim_time() {
+                        fixed_timestep.set_sim_time(sim_time.sim_time);
+                    }
+                });
+
                 // Update diagnostics
                 world.resource_scope(|_, mut diagnostics: Mut<Diagnostics>| {
                     diagnostics.update_time = update_time;
@@ -260,6 +260,40 @@ pub fn toi_unit_sphere_point(center: Vector3<f32>, velocity: Vector3<f32>, point
     lowest_root(a, b, c, 1000.0)
 }
 
+/// Time of intersection between a line segment and a triangle, from 0..1 along the velocity
+/// (Möller–Trumbore). Triangles are hit from either side.
+pub fn toi_segment_triangle(start: Vector3<f32>, velocity: Vector3<f32>, triangle: &Triangle) -> Option<f32> {
+    let edge1 = triangle.b - triangle.a;
+    let edge2 = triangle.c - triangle.a;
+
+    let p = velocity.cross(edge2);
+    let det = edge1.dot(p);
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let inv_det = 1.0 / det;
+    let s = start - triangle.a;
+    let u = s.dot(p) * inv_det;
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+
+    let q = s.cross(edge1);
+    let v = velocity.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = edge2.dot(q) * inv_det;
+    if t >= 0.0 && t <= 1.0 {
+        Some(t)
+    }
+    else {
+        None
+    }
+}
+
 // Solve a quadratic equation and find the lowest non-zero root
 fn lowest_root(a: f32, b: f32, c: f32, max: f32) -> Option<f32> {
     let determinant = b * b - 4.0 * a * c;
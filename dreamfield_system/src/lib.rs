@@ -4,6 +4,7 @@ pub mod world;
 pub mod components;
 pub mod systems;
 pub mod intersection;
+pub mod save_game;
 mod fixed_timestep;
 mod glfw_system;
 mod game_host;
@@ -15,8 +16,13 @@ pub use game_host::*;
 use bevy_ecs::{schedule::SystemSet, world::World, prelude::Events};
 use input::InputState;
 use resources::{SimTime, Diagnostics};
-use systems::entity_spawner::EntitySpawnEvent;
+use systems::entity_spawner::{EntitySpawnEvent, EntityDespawnEvent, EntitySpawnResource};
 use world::world_collision::WorldCollision;
+use world::world_navigation::WorldNavigation;
+use save_game::SaveGameRegistry;
+use systems::prefabs::PrefabRegistry;
+use systems::triggers::{TriggerEnterEvent, TriggerExitEvent, TriggerResource};
+use systems::levels::{ChangeLevelEvent, LevelChangedEvent};
 
 /// Initialise resources etc
 pub fn init(world: &mut World) {
@@ -26,15 +32,30 @@ pub fn init(world: &mut World) {
     world.init_resource::<WindowSettings>();
     world.init_resource::<Diagnostics>();
     world.init_resource::<WorldCollision>();
+    world.init_resource::<WorldNavigation>();
+    world.init_resource::<EntitySpawnResource>();
+    world.init_resource::<SaveGameRegistry>();
+    world.init_resource::<PrefabRegistry>();
+    world.init_resource::<TriggerResource>();
 
     // Events
     world.init_resource::<Events::<EntitySpawnEvent>>();
+    world.init_resource::<Events::<EntityDespawnEvent>>();
+    world.init_resource::<Events::<TriggerEnterEvent>>();
+    world.init_resource::<Events::<TriggerExitEvent>>();
+    world.init_resource::<Events::<ChangeLevelEvent>>();
+    world.init_resource::<Events::<LevelChangedEvent>>();
 }
 
 /// The system systems
 pub fn systems() -> SystemSet {
     SystemSet::new()
         .with_system(systems::entity_spawner::entity_spawner_system)
+        .with_system(systems::prefabs::prefab_spawner_system)
+        .with_system(systems::prefabs::prefab_despawner_system)
         .with_system(intersection::update_world_chunks_system)
+        .with_system(systems::triggers::trigger_system)
+        .with_system(systems::levels::level_change_system)
+        .with_system(systems::character_controller::character_controller_system)
 }
 
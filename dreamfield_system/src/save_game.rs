@@ -0,0 +1,196 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+
+use bevy_ecs::prelude::{Component, Entity, With};
+use bevy_ecs::world::{World, Mut};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::components::{Transform, EntityName};
+use crate::resources::SimTime;
+use crate::systems::entity_spawner::{EntitySpawnResource, WorldEntity};
+
+/// The save game format version, this should be incremented whenever the format changes so that
+/// old save games are rejected instead of being loaded incorrectly
+pub const SAVE_GAME_VERSION: u32 = 1;
+
+/// A component for tagging entities that should be written to save games. Only the components
+/// registered with the SaveGameRegistry are saved.
+#[derive(Component, Serialize, Deserialize)]
+pub struct Saveable;
+
+/// The save game registry resource, which keeps track of the component types that get saved, so
+/// that games can register their own components alongside the engine ones
+pub struct SaveGameRegistry {
+    components: Vec<SaveableComponent>
+}
+
+/// The functions for saving and loading a single component type
+struct SaveableComponent {
+    name: String,
+    save: fn(&World, Entity) -> Option<serde_json::Result<Value>>,
+    load: fn(&mut World, Entity, Value) -> serde_json::Result<()>,
+}
+
+impl SaveGameRegistry {
+    /// Create a new registry with only the engine components registered
+    pub fn new() -> Self {
+        let mut registry = Self {
+            components: Vec::new()
+        };
+
+        registry.register::<Saveable>("saveable");
+        registry.register::<Transform>("transform");
+        registry.register::<EntityName>("entity_name");
+        registry.register::<WorldEntity>("world_entity");
+
+        registry
+    }
+
+    /// Register a component type to be saved under a unique name
+    pub fn register<T: Component + Serialize + DeserializeOwned>(&mut self, name: &str) {
+        if self.components.iter().any(|c| c.name == name) {
+            panic!("SaveGameRegistry: Component {} registered twice", name);
+        }
+
+        self.components.push(SaveableComponent {
+            name: name.to_string(),
+            save: Self::save_component::<T>,
+            load: Self::load_component::<T>,
+        });
+    }
+
+    /// Serialize a component from an entity, if it has one
+    fn save_component<T: Component + Serialize>(world: &World, entity: Entity) -> Option<serde_json::Result<Value>> {
+        world.get::<T>(entity).map(serde_json::to_value)
+    }
+
+    /// Deserialize a component and add it to an entity
+    fn load_component<T: Component + DeserializeOwned>(world: &mut World, entity: Entity, value: Value)
+        -> serde_json::Result<()>
+    {
+        let component: T = serde_json::from_value(value)?;
+        world.entity_mut(entity).insert(component);
+        Ok(())
+    }
+}
+
+impl Default for SaveGameRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Just the save game version, so it can be checked before parsing the rest of the file
+#[derive(Deserialize)]
+struct SaveGameHeader {
+    version: u32
+}
+
+/// A save game file
+#[derive(Serialize, Deserialize)]
+struct SaveGame {
+    version: u32,
+    sim_time: f64,
+    spawner: EntitySpawnResource,
+    entities: Vec<SavedEntity>,
+}
+
+/// A single saved entity, with its components keyed by their registered name
+#[derive(Serialize, Deserialize)]
+struct SavedEntity {
+    components: HashMap<String, Value>
+}
+
+/// Save the sim time, entity spawner state, and all entities tagged with Saveable to a file
+pub fn save_game(world: &mut World, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+    let path = path.as_ref();
+    log::info!("Saving game to {}", path.display());
+
+    let mut query = world.query_filtered::<Entity, With<Saveable>>();
+    let saveable_entities: Vec<Entity> = query.iter(world).collect();
+
+    let registry = world.resource::<SaveGameRegistry>();
+    let mut entities = Vec::with_capacity(saveable_entities.len());
+    for entity in saveable_entities {
+        let mut components = HashMap::new();
+        for component in registry.components.iter() {
+            if let Some(value) = (component.save)(world, entity) {
+                components.insert(component.name.clone(), value?);
+            }
+        }
+        entities.push(SavedEntity { components });
+    }
+
+    let save_game = SaveGame {
+        version: SAVE_GAME_VERSION,
+        sim_time: world.resource::<SimTime>().sim_time,
+        spawner: world.resource::<EntitySpawnResource>().clone(),
+        entities,
+    };
+
+    std::fs::write(path, serde_json::to_string_pretty(&save_game)?)?;
+
+    log::info!("Saved {} entities", save_game.entities.len());
+    Ok(())
+}
+
+/// Load a save game, replacing all Saveable entities and world entities with the saved ones and
+/// restoring the sim time and entity spawner state. World entities that weren't saved get spawned
+/// again by the entity spawner, unless they were destroyed or consumed.
+pub fn load_game(world: &mut World, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+    let path = path.as_ref();
+    log::info!("Loading game from {}", path.display());
+
+    let contents = std::fs::read_to_string(path)?;
+
+    let header: SaveGameHeader = serde_json::from_str(&contents)?;
+    if header.version != SAVE_GAME_VERSION {
+        return Err(format!("Unsupported save game version {} (expected {})", header.version, SAVE_GAME_VERSION).into());
+    }
+
+    let save_game: SaveGame = serde_json::from_str(&contents)?;
+
+    // Remove existing saveable and world entities, they're either restored from the save or
+    // respawned by the entity spawner
+    let mut old_entities = HashSet::new();
+    old_entities.extend(world.query_filtered::<Entity, With<Saveable>>().iter(world));
+    old_entities.extend(world.query_filtered::<Entity, With<WorldEntity>>().iter(world));
+    for entity in old_entities {
+        world.despawn(entity);
+    }
+
+    // Spawn saved entities
+    let entity_count = save_game.entities.len();
+    let mut restored_world_entities = HashSet::new();
+    world.resource_scope(|world, registry: Mut<SaveGameRegistry>| -> Result<(), Box<dyn Error>> {
+        for saved_entity in save_game.entities {
+            let entity = world.spawn().id();
+            for (name, value) in saved_entity.components {
+                if let Some(component) = registry.components.iter().find(|c| c.name == name) {
+                    (component.load)(world, entity, value)?;
+                }
+                else {
+                    log::warn!("load_game: Skipping unregistered component {}", name);
+                }
+            }
+
+            if let Some(world_entity) = world.get::<WorldEntity>(entity) {
+                restored_world_entities.insert(world_entity.entity_id);
+            }
+        }
+        Ok(())
+    })?;
+
+    // Restore entity spawner state, only world entities that were restored are still spawned
+    let mut spawner = save_game.spawner;
+    spawner.retain_spawned(|entity_id| restored_world_entities.contains(&entity_id));
+    world.insert_resource(spawner);
+
+    // Restore sim time, the game host picks this up after the update
+    world.resource_mut::<SimTime>().sim_time = save_game.sim_time;
+
+    log::info!("Loaded {} entities", entity_count);
+    Ok(())
+}
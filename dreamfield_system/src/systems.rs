@@ -1 +1,5 @@
 pub mod entity_spawner;
+pub mod prefabs;
+pub mod triggers;
+pub mod levels;
+pub mod character_controller;
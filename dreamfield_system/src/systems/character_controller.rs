@@ -0,0 +1,315 @@
+use bevy_ecs::prelude::{Component, Entity};
+use bevy_ecs::system::{Query, Res, ResMut};
+use cgmath::{Vector3, vec3, Rad, Deg, Angle, InnerSpace, ElementWise, Zero};
+
+use crate::components::Transform;
+use crate::resources::SimTime;
+use crate::world::WorldChunkManager;
+use crate::world::world_collision::WorldCollision;
+
+/// How far to stay away from surfaces after a collision, so that the next sweep doesn't start
+/// embedded in them
+const SKIN_WIDTH: f32 = 0.005;
+
+/// How far below the character to look for ground when it isn't snapping to it
+const GROUND_PROBE_DISTANCE: f32 = 0.05;
+
+/// Movement shorter than this is ignored
+const MIN_MOVE_DISTANCE: f32 = 0.0001;
+
+/// A component for moving an entity through the world with collide-and-slide. The game sets the
+/// velocity each update, and the character_controller_system moves the entity's transform by it,
+/// sliding along walls, stepping up onto ledges and snapping to the ground. Afterwards the velocity
+/// is replaced by the velocity the character actually moved at, so e.g. gravity doesn't build up
+/// while standing on the ground.
+#[derive(Component)]
+pub struct CharacterController {
+    /// The offset of the collision spheroid's center from the entity's position
+    pub offset: Vector3<f32>,
+    /// The radius of the collision spheroid
+    pub radius: Vector3<f32>,
+    /// The velocity in units per second
+    pub velocity: Vector3<f32>,
+    /// The highest ledge that the character can step up onto while grounded
+    pub max_step_height: f32,
+    /// The steepest slope that counts as ground, anything steeper is treated as a wall
+    pub max_slope: Rad<f32>,
+    /// How far the character is pulled down onto the ground when walking down slopes or steps
+    pub snap_distance: f32,
+    /// The maximum number of collisions to slide along per move
+    pub max_iterations: usize,
+    grounded: bool,
+    ground_normal: Option<Vector3<f32>>,
+}
+
+impl CharacterController {
+    pub fn new(offset: Vector3<f32>, radius: Vector3<f32>) -> Self {
+        Self {
+            offset,
+            radius,
+            velocity: vec3(0.0, 0.0, 0.0),
+            max_step_height: 0.3,
+            max_slope: Deg(45.0).into(),
+            snap_distance: 0.3,
+            max_iterations: 4,
+            grounded: false,
+            ground_normal: None,
+        }
+    }
+
+    /// Get whether the character was standing on walkable ground after its last move
+    pub fn is_grounded(&self) -> bool {
+        self.grounded
+    }
+
+    /// Get the normal of the ground the character is standing on, if it's grounded
+    pub fn ground_normal(&self) -> Option<Vector3<f32>> {
+        self.ground_normal
+    }
+
+    /// Get whether a surface normal is shallow enough to stand on
+    fn is_walkable(&self, normal: &Vector3<f32>) -> bool {
+        normal.y >= self.max_slope.cos()
+    }
+}
+
+/// Something the character hit while sweeping
+struct SweepHit {
+    /// The normal of the plane to slide along
+    normal: Vector3<f32>,
+    /// The normal of the ground, if what was hit can be stood on
+    ground_normal: Option<Vector3<f32>>,
+}
+
+/// What to do when a sweep hits something
+#[derive(Clone, Copy, PartialEq)]
+enum SlideMode {
+    /// Slide along everything, but treat slopes that are too steep as vertical walls so that they
+    /// can't be walked up
+    Horizontal,
+    /// Stop on walkable ground so that standing on a slope doesn't slide down it, and slide down
+    /// anything steeper
+    Vertical,
+}
+
+/// The character controller system. Moves entities with a CharacterController by their velocity,
+/// ignoring their own collider.
+pub fn character_controller_system(sim_time: Res<SimTime>,
+                                   mut collision: ResMut<WorldCollision>,
+                                   mut world: ResMut<WorldChunkManager>,
+                                   mut query: Query<(Entity, &mut Transform, &mut CharacterController)>)
+{
+    let dt = sim_time.sim_time_delta as f32;
+    if dt <= 0.0 {
+        return;
+    }
+
+    for (entity, mut transform, mut controller) in query.iter_mut() {
+        let mut mover = CharacterMover {
+            collision: &mut collision,
+            world: &mut world,
+            entity,
+            controller: &controller,
+        };
+
+        let start = transform.pos + controller.offset;
+        let motion = controller.velocity * dt;
+        let horizontal_motion = vec3(motion.x, 0.0, motion.z);
+        let vertical_motion = vec3(0.0, motion.y, 0.0);
+
+        // Move horizontally first, stepping up onto anything low enough if we're on the ground
+        let mut pos = match controller.grounded && controller.max_step_height > 0.0 {
+            true => mover.move_with_step(start, horizontal_motion),
+            false => mover.slide(start, horizontal_motion, SlideMode::Horizontal)
+        };
+        let horizontal_end = pos;
+
+        // Then vertically, for gravity and jumping
+        pos = mover.slide(pos, vertical_motion, SlideMode::Vertical);
+        let vertical_distance = pos.y - horizontal_end.y;
+
+        // Look for ground below, snapping down to it if we were already on the ground so that we
+        // stay on it when walking down slopes and steps
+        let moving_up = controller.velocity.y > 0.0;
+        let snap = controller.grounded && !moving_up;
+        let ground = match moving_up {
+            true => None,
+            false => {
+                let probe_distance = match snap {
+                    true => controller.snap_distance.max(GROUND_PROBE_DISTANCE),
+                    false => GROUND_PROBE_DISTANCE
+                };
+                mover.find_ground(pos, probe_distance)
+            }
+        };
+
+        if let Some((distance, _)) = ground {
+            pos.y -= distance;
+        }
+
+        // The horizontal velocity includes walking up and down slopes, but gravity only cares about
+        // how far we actually fell
+        let horizontal_moved = horizontal_end - start;
+        controller.velocity = vec3(horizontal_moved.x, vertical_distance, horizontal_moved.z) / dt;
+        controller.grounded = ground.is_some();
+        controller.ground_normal = ground.map(|(_, normal)| normal);
+        if controller.grounded && controller.velocity.y < 0.0 {
+            controller.velocity.y = 0.0;
+        }
+
+        transform.pos = pos - controller.offset;
+    }
+}
+
+/// The state needed to move a single character through the world
+struct CharacterMover<'a> {
+    collision: &'a mut WorldCollision,
+    world: &'a mut WorldChunkManager,
+    entity: Entity,
+    controller: &'a CharacterController,
+}
+
+impl<'a> CharacterMover<'a> {
+    /// Move horizontally, stepping up onto ledges if that gets further than sliding along them
+    fn move_with_step(&mut self, start: Vector3<f32>, motion: Vector3<f32>) -> Vector3<f32> {
+        let flat_end = self.slide(start, motion, SlideMode::Horizontal);
+        let flat_distance = horizontal_distance(start, flat_end);
+        if flat_distance >= motion.magnitude() - MIN_MOVE_DISTANCE {
+            return flat_end;
+        }
+
+        // Move up, across and back down again, only stepping if we land on walkable ground
+        let up = self.sweep(start, vec3(0.0, self.controller.max_step_height, 0.0)).0;
+        let across = self.slide(up, motion, SlideMode::Horizontal);
+        let (down, hit) = self.sweep(across, vec3(0.0, start.y - up.y, 0.0));
+
+        let landed = hit.map(|hit| hit.ground_normal.is_some()).unwrap_or(false);
+        match landed && horizontal_distance(start, down) > flat_distance + MIN_MOVE_DISTANCE {
+            true => down,
+            false => flat_end
+        }
+    }
+
+    /// Look for walkable ground below a position, returning the distance to it and its normal
+    fn find_ground(&mut self, pos: Vector3<f32>, max_distance: f32) -> Option<(f32, Vector3<f32>)> {
+        let (end, hit) = self.sweep(pos, vec3(0.0, -max_distance, 0.0));
+        hit
+            .and_then(|hit| hit.ground_normal)
+            .map(|normal| (pos.y - end.y, normal))
+    }
+
+    /// Collide and slide: move as far as possible along the motion, then slide the rest of it along
+    /// whatever was hit, up to max_iterations times. Returns the final position.
+    fn slide(&mut self, start: Vector3<f32>, motion: Vector3<f32>, mode: SlideMode) -> Vector3<f32> {
+        let mut pos = start;
+        let mut remaining = motion;
+        let mut previous_normal: Option<Vector3<f32>> = None;
+
+        for _ in 0..self.controller.max_iterations {
+            if remaining.magnitude() < MIN_MOVE_DISTANCE {
+                break;
+            }
+
+            let (end, hit) = self.sweep(pos, remaining);
+            let moved = end - pos;
+            pos = end;
+
+            let hit = match hit {
+                Some(hit) => hit,
+                None => break
+            };
+            let mut normal = hit.normal;
+
+            // Walkable ground stops vertical movement rather than sliding down it
+            let walkable = hit.ground_normal.is_some();
+            if mode == SlideMode::Vertical && walkable {
+                break;
+            }
+
+            // Steep slopes are walls when moving horizontally, so they can't be climbed
+            if mode == SlideMode::Horizontal && !walkable && normal.y > 0.0 {
+                let wall_normal = vec3(normal.x, 0.0, normal.z);
+                if wall_normal.magnitude2() > 0.0 {
+                    normal = wall_normal.normalize();
+                }
+            }
+
+            // Slide the rest of the motion along the surface, or along the crease between this
+            // surface and the last one if sliding along this one would push us back into that
+            let left = remaining - moved;
+            let mut slide = left - normal * normal.dot(left);
+            if let Some(previous) = previous_normal {
+                if slide.dot(previous) < 0.0 {
+                    let crease = previous.cross(normal);
+                    slide = match crease.magnitude2() > 0.0 {
+                        true => crease.normalize() * crease.normalize().dot(left),
+                        false => Vector3::zero()
+                    };
+                }
+            }
+
+            previous_normal = Some(normal);
+            remaining = slide;
+        }
+
+        pos
+    }
+
+    /// Sweep the character's spheroid along a motion, returning where it stops, staying SKIN_WIDTH
+    /// away from anything it hits, and what it hit
+    fn sweep(&mut self, start: Vector3<f32>, motion: Vector3<f32>) -> (Vector3<f32>, Option<SweepHit>) {
+        let distance = motion.magnitude();
+        if distance < MIN_MOVE_DISTANCE {
+            return (start, None);
+        }
+
+        let radius = self.controller.radius;
+        let hit = match self.collision.sweep_sphere(self.world, start, motion, radius, Some(self.entity)) {
+            Some(hit) => hit,
+            None => return (start + motion, None)
+        };
+
+        let travel = (hit.toi() * distance - SKIN_WIDTH).max(0.0);
+        let end = start + motion * (travel / distance);
+
+        // The sliding plane is perpendicular to the line from the hit point to the spheroid's center
+        // at the time of impact, in the space where the spheroid is a sphere. This is better than the
+        // hit normal for edges and corners, and doesn't care which way triangles face.
+        let cbm = vec3(1.0 / radius.x, 1.0 / radius.y, 1.0 / radius.z);
+        let contact_center = start + motion * hit.toi();
+        let unit_normal = (contact_center - hit.point()).mul_element_wise(cbm);
+        let unit_normal = match unit_normal.magnitude2() > 0.0 {
+            true => unit_normal,
+            false => *hit.normal()
+        };
+
+        // Transform the normals back out of ellipsoid space, and make sure they face against the motion
+        let face_against_motion = |normal: Vector3<f32>| match normal.dot(motion) > 0.0 {
+            true => -normal,
+            false => normal
+        };
+        let normal = face_against_motion(unit_normal.mul_element_wise(cbm).normalize());
+        let surface_normal = face_against_motion(hit.normal().mul_element_wise(cbm).normalize());
+
+        // Hitting the face of a triangle can be stood on if the surface isn't too steep. Hitting an
+        // edge or a corner (like the top of a step) can be stood on if it's far enough under the
+        // spheroid, which is measured in ellipsoid space so that it doesn't depend on the radius.
+        let face_hit = normal.dot(surface_normal) > 0.999;
+        let ground_normal = if self.controller.is_walkable(&surface_normal) {
+            Some(surface_normal)
+        }
+        else if !face_hit && self.controller.is_walkable(&face_against_motion(unit_normal.normalize())) {
+            Some(normal)
+        }
+        else {
+            None
+        };
+
+        (end, Some(SweepHit { normal, ground_normal }))
+    }
+}
+
+/// Get the horizontal distance between two points
+fn horizontal_distance(a: Vector3<f32>, b: Vector3<f32>) -> f32 {
+    vec3(b.x - a.x, 0.0, b.z - a.z).magnitude()
+}
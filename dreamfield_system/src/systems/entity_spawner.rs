@@ -1,67 +1,248 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use bevy_ecs::{prelude::{Component, EventWriter}, system::{Local, Query, ResMut}};
-use cgmath::vec3;
+use bevy_ecs::{prelude::{Component, EventWriter}, system::{Query, Res, ResMut}};
+use cgmath::{Vector3, vec3};
+use serde::{Serialize, Deserialize};
 
-use crate::world::{world_chunk::{WorldChunkEntity, EntityId, WorldChunk}, WorldChunkManager};
+use crate::world::{world_chunk::{WorldChunkEntity, EntityId, WorldChunk, ChunkIndex, RespawnPolicy, CHUNK_SIZE},
+    WorldChunkManager};
 use crate::components::Transform;
+use crate::resources::SimTime;
+
+/// The default distance an entity's chunk can be outside of a spawn radius before it's despawned
+pub const DEFAULT_DESPAWN_HYSTERESIS: f32 = CHUNK_SIZE;
 
 /// An event sent by the system that instructs the game to spawn an entity
 pub struct EntitySpawnEvent {
     pub entity_info: WorldChunkEntity
 }
 
+/// An event sent by the system that instructs the game to despawn an entity, because it's no
+/// longer near any EntitySpawnRadius
+pub struct EntityDespawnEvent {
+    pub entity_id: EntityId
+}
+
 /// A component that can be used to tag the player, so that entities in nearby world chunks get
-/// spawned automatically when they come nearby
+/// spawned automatically when they come nearby, and despawned again when it moves further than
+/// radius + hysteresis away
 #[derive(Component)]
 pub struct EntitySpawnRadius {
-    pub radius: f32
+    pub radius: f32,
+    pub hysteresis: f32
 }
 
 impl EntitySpawnRadius {
     pub fn new(radius: f32) -> Self {
-        Self { radius }
+        Self::new_with_hysteresis(radius, DEFAULT_DESPAWN_HYSTERESIS)
+    }
+
+    pub fn new_with_hysteresis(radius: f32, hysteresis: f32) -> Self {
+        Self { radius, hysteresis }
     }
 }
 
-/// A local resource for the entity spawner system, so it can keep track of spawned entities
-#[derive(Default)]
+/// A component for tagging game entities that were spawned from a world chunk entity, so that
+/// they can be found again when an EntityDespawnEvent is received
+#[derive(Component, Serialize, Deserialize)]
+pub struct WorldEntity {
+    pub entity_id: EntityId
+}
+
+impl WorldEntity {
+    pub fn new(entity_id: EntityId) -> Self {
+        Self { entity_id }
+    }
+}
+
+/// The entity spawner resource, keeps track of which world entities are spawned, and which ones
+/// the game has destroyed or consumed so that they're only respawned according to their policy
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct EntitySpawnResource {
-    spawned_entities: HashSet<EntityId>
+    spawned_entities: HashMap<EntityId, SpawnedEntity>,
+    destroyed_entities: HashMap<EntityId, DestroyedEntity>,
+    consumed_entities: HashSet<EntityId>,
+    #[serde(skip)]
+    failed_entities: HashMap<EntityId, ChunkIndex>,
+}
+
+/// A currently spawned world entity
+#[derive(Clone, Serialize, Deserialize)]
+struct SpawnedEntity {
+    chunk: ChunkIndex,
+    respawn_policy: RespawnPolicy,
+}
+
+/// A world entity that the game destroyed, waiting to be respawned
+#[derive(Clone, Serialize, Deserialize)]
+struct DestroyedEntity {
+    chunk: ChunkIndex,
+    respawn_policy: RespawnPolicy,
+    destroyed_at: f64,
+}
+
+impl EntitySpawnResource {
+    /// Get whether a world entity is currently spawned
+    pub fn is_spawned(&self, entity_id: EntityId) -> bool {
+        self.spawned_entities.contains_key(&entity_id)
+    }
+
+    /// Get whether a world entity has been permanently consumed
+    pub fn is_consumed(&self, entity_id: EntityId) -> bool {
+        self.consumed_entities.contains(&entity_id)
+    }
+
+    /// Mark a spawned world entity as destroyed by the game. It won't be spawned again until its
+    /// respawn policy allows it.
+    pub fn mark_destroyed(&mut self, entity_id: EntityId, sim_time: f64) {
+        if let Some(spawned) = self.spawned_entities.remove(&entity_id) {
+            log::info!("Entity {} destroyed, respawn policy {:?}", entity_id, spawned.respawn_policy);
+            self.destroyed_entities.insert(entity_id, DestroyedEntity {
+                chunk: spawned.chunk,
+                respawn_policy: spawned.respawn_policy,
+                destroyed_at: sim_time,
+            });
+        }
+        else {
+            log::warn!("mark_destroyed: Entity {} is not spawned", entity_id);
+        }
+    }
+
+    /// Mark a world entity as permanently consumed (e.g. a collected item), so that it never
+    /// respawns regardless of its respawn policy
+    pub fn mark_consumed(&mut self, entity_id: EntityId) {
+        log::info!("Entity {} consumed", entity_id);
+        self.spawned_entities.remove(&entity_id);
+        self.destroyed_entities.remove(&entity_id);
+        self.consumed_entities.insert(entity_id);
+    }
+
+    /// Mark a world entity that the game failed to spawn. It isn't spawned again until its chunk
+    /// goes out of range and comes back, rather than being retried every update.
+    pub fn mark_spawn_failed(&mut self, entity_id: EntityId) {
+        if let Some(spawned) = self.spawned_entities.remove(&entity_id) {
+            self.failed_entities.insert(entity_id, spawned.chunk);
+        }
+    }
+
+    /// Forget that any entities are spawned, other than those for which `keep` returns true. Used
+    /// when loading a save game, so that world entities that weren't restored get spawned again.
+    pub fn retain_spawned(&mut self, mut keep: impl FnMut(EntityId) -> bool) {
+        self.spawned_entities.retain(|entity_id, _| keep(*entity_id));
+    }
+
+    /// Forget all spawned entities, returning their ids so that they can be despawned. Used when the
+    /// level changes.
+    pub fn despawn_all(&mut self) -> Vec<EntityId> {
+        self.failed_entities.clear();
+        self.spawned_entities.drain().map(|(entity_id, _)| entity_id).collect()
+    }
+
+    /// Get whether a world entity is allowed to be spawned right now
+    fn can_spawn(&self, entity_id: EntityId) -> bool {
+        !self.spawned_entities.contains_key(&entity_id) &&
+        !self.destroyed_entities.contains_key(&entity_id) &&
+        !self.consumed_entities.contains(&entity_id) &&
+        !self.failed_entities.contains_key(&entity_id)
+    }
+
+    /// Despawn any spawned entities whose chunk is no longer in range, returning their ids. Entities
+    /// that failed to spawn can be tried again once their chunk is out of range.
+    fn despawn_out_of_range(&mut self, chunks_in_range: &HashSet<ChunkIndex>) -> Vec<EntityId> {
+        let mut despawned = Vec::new();
+
+        self.failed_entities.retain(|_, chunk| chunks_in_range.contains(chunk));
+
+        self.spawned_entities.retain(|entity_id, spawned| {
+            let in_range = chunks_in_range.contains(&spawned.chunk);
+            if !in_range {
+                despawned.push(*entity_id);
+            }
+            in_range
+        });
+
+        despawned
+    }
+
+    /// Release destroyed entities whose respawn policy now allows them to be spawned again
+    fn update_respawns(&mut self, chunks_in_range: &HashSet<ChunkIndex>, sim_time: f64) {
+        self.destroyed_entities.retain(|entity_id, destroyed| {
+            let can_respawn = match destroyed.respawn_policy {
+                RespawnPolicy::Never => false,
+                RespawnPolicy::OnChunkReload => !chunks_in_range.contains(&destroyed.chunk),
+                RespawnPolicy::After(respawn_time) => sim_time >= destroyed.destroyed_at + respawn_time,
+            };
+            if can_respawn {
+                log::info!("Entity {} can respawn", entity_id);
+            }
+            !can_respawn
+        });
+    }
 }
 
 /// The entity spawner system. Watches for entities with an EntitySpawnRadius and a Transform (e.g.
-/// the player or camera entity), and spawns entities in world chunks when it comes nearby
-pub fn entity_spawner_system(mut local: Local<EntitySpawnResource>,
+/// the player or camera entity), and spawns entities in world chunks when it comes nearby, and
+/// despawns them again when there's no longer any EntitySpawnRadius nearby
+pub fn entity_spawner_system(mut spawner: ResMut<EntitySpawnResource>,
+                             sim_time: Res<SimTime>,
                              query: Query<(&Transform, &EntitySpawnRadius)>,
                              mut chunks: ResMut<WorldChunkManager>,
-                             mut writer: EventWriter<EntitySpawnEvent>)
+                             mut spawn_writer: EventWriter<EntitySpawnEvent>,
+                             mut despawn_writer: EventWriter<EntityDespawnEvent>)
 {
+    // Find all chunks within the despawn radius of any spawn radius, entities in any other chunk
+    // get despawned
+    let mut chunks_in_range = HashSet::new();
     for (transform, radius) in query.iter() {
-        let radius = radius.radius;
-        let min = transform.pos - vec3(radius, radius, radius);
-        let max = transform.pos + vec3(radius, radius, radius);
+        let ((min_chunk_x, min_chunk_y), (max_chunk_x, max_chunk_y)) =
+            chunk_range(&transform.pos, radius.radius + radius.hysteresis);
 
-        let (min_chunk_x, min_chunk_y) = WorldChunk::point_to_chunk_index(&min);
-        let (max_chunk_x, max_chunk_y) = WorldChunk::point_to_chunk_index(&max);
+        for x in min_chunk_x..=max_chunk_x {
+            for y in min_chunk_y..=max_chunk_y {
+                chunks_in_range.insert((x, y));
+            }
+        }
+    }
+
+    for entity_id in spawner.despawn_out_of_range(&chunks_in_range) {
+        log::info!("Despawning entity {}", entity_id);
+        despawn_writer.send(EntityDespawnEvent { entity_id });
+    }
+
+    spawner.update_respawns(&chunks_in_range, sim_time.sim_time);
+
+    // Spawn entities in chunks within the spawn radius
+    for (transform, radius) in query.iter() {
+        let ((min_chunk_x, min_chunk_y), (max_chunk_x, max_chunk_y)) = chunk_range(&transform.pos, radius.radius);
 
         for x in min_chunk_x..=max_chunk_x {
             for y in min_chunk_y..=max_chunk_y {
                 if let Some(chunk) = chunks.get_or_load_chunk((x, y)) {
                     for entity in chunk.entities().iter() {
                         let entity_id = entity.entity_id();
-                        if local.spawned_entities.contains(&entity_id) {
+                        if !spawner.can_spawn(entity_id) {
                             continue;
                         }
 
                         log::info!("Spawning entity {} ({})", entity.object_id(), entity.entity_id());
-                        writer.send(EntitySpawnEvent {
+                        spawn_writer.send(EntitySpawnEvent {
                             entity_info: entity.clone()
                         });
-                        local.spawned_entities.insert(entity_id);
+                        spawner.spawned_entities.insert(entity_id, SpawnedEntity {
+                            chunk: (x, y),
+                            respawn_policy: entity.respawn_policy(),
+                        });
                     }
                 }
             }
         }
     }
 }
+
+/// Get the min and max chunk indexes within a radius of a point
+fn chunk_range(pos: &Vector3<f32>, radius: f32) -> (ChunkIndex, ChunkIndex) {
+    let min = pos - vec3(radius, radius, radius);
+    let max = pos + vec3(radius, radius, radius);
+
+    (WorldChunk::point_to_chunk_index(&min), WorldChunk::point_to_chunk_index(&max))
+}
@@ -0,0 +1,76 @@
+use std::marker::PhantomData;
+use bevy_ecs::prelude::{EventReader, EventWriter};
+use bevy_ecs::system::{Query, ResMut, SystemParam};
+
+use crate::intersection::Collider;
+use crate::systems::entity_spawner::{EntityDespawnEvent, EntitySpawnResource};
+use crate::systems::triggers::TriggerResource;
+use crate::world::WorldChunkManager;
+use crate::world::world_collision::WorldCollision;
+use crate::world::world_navigation::WorldNavigation;
+
+/// An event sent by the game to switch to another level, or to the default level if None
+pub struct ChangeLevelEvent {
+    pub level: Option<String>
+}
+
+impl ChangeLevelEvent {
+    pub fn new(level: &str) -> Self {
+        Self { level: Some(level.to_string()) }
+    }
+}
+
+/// An event sent by the system once the level has changed, after the world entities of the old
+/// level have been despawned
+pub struct LevelChangedEvent {
+    pub level: Option<String>
+}
+
+/// The resources holding state from the active level, which all need clearing when it changes
+#[derive(SystemParam)]
+pub struct LevelResources<'w, 's> {
+    world: ResMut<'w, WorldChunkManager>,
+    collision: ResMut<'w, WorldCollision>,
+    navigation: ResMut<'w, WorldNavigation>,
+    spawner: ResMut<'w, EntitySpawnResource>,
+    triggers: ResMut<'w, TriggerResource>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+/// The level change system. Switches the active level in response to a ChangeLevelEvent, clearing
+/// the chunk, collision, navigation, trigger and spawner state of the old level. The game is
+/// responsible for moving the player into the new level.
+pub fn level_change_system(mut events: EventReader<ChangeLevelEvent>,
+                           mut resources: LevelResources,
+                           mut colliders: Query<&mut Collider>,
+                           mut despawn_writer: EventWriter<EntityDespawnEvent>,
+                           mut level_changed_writer: EventWriter<LevelChangedEvent>)
+{
+    // Only the most recent request matters if there's more than one
+    let level = match events.iter().last() {
+        Some(event) => event.level.clone(),
+        None => return
+    };
+
+    if let Err(e) = resources.world.set_level(level.as_deref()) {
+        log::error!("Failed to change level: {e}");
+        return;
+    }
+
+    resources.collision.clear();
+    resources.navigation.clear();
+    resources.triggers.clear();
+
+    for entity_id in resources.spawner.despawn_all() {
+        log::info!("Despawning entity {}", entity_id);
+        despawn_writer.send(EntityDespawnEvent { entity_id });
+    }
+
+    // The chunk manager forgot where every entity is, so they need to be added to chunks again
+    for mut collider in colliders.iter_mut() {
+        collider.chunks_in.clear();
+    }
+
+    level_changed_writer.send(LevelChangedEvent { level });
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use bevy_ecs::prelude::{Bundle, Entity, EventReader};
+use bevy_ecs::system::{Commands, EntityCommands, Query, Res, ResMut};
+use serde::de::DeserializeOwned;
+
+use crate::components::Transform;
+use crate::world::world_chunk::WorldChunkEntity;
+use super::entity_spawner::{EntitySpawnEvent, EntityDespawnEvent, EntitySpawnResource, WorldEntity};
+
+/// A type-erased prefab spawn function, which parses the entity's extras and spawns it
+type PrefabSpawnFn = Box<dyn Fn(&mut Commands, &WorldChunkEntity) -> Result<Entity, String> + Send + Sync>;
+
+/// The prefab registry resource, which maps world entity object_ids to functions that spawn them.
+/// Games can keep handling EntitySpawnEvents themselves for any object_id without a prefab.
+pub struct PrefabRegistry {
+    prefabs: HashMap<String, PrefabSpawnFn>
+}
+
+impl PrefabRegistry {
+    pub fn new() -> Self {
+        Self {
+            prefabs: HashMap::new()
+        }
+    }
+
+    /// Register a prefab for an object_id. The entity's extras are deserialized to E, and the
+    /// spawn closure is called with a new entity that already has a Transform and WorldEntity.
+    pub fn register<E, F>(&mut self, object_id: &str, spawn: F)
+        where E: DeserializeOwned,
+              F: Fn(&mut EntityCommands, &WorldChunkEntity, E) + Send + Sync + 'static
+    {
+        let spawn_fn = move |commands: &mut Commands, entity_info: &WorldChunkEntity| -> Result<Entity, String> {
+            let extras: E = Self::parse_extras(entity_info)?;
+
+            let mut entity = commands.spawn();
+            entity
+                .insert(Transform::from_matrix(entity_info.world_transform()))
+                .insert(WorldEntity::new(entity_info.entity_id()));
+
+            spawn(&mut entity, entity_info, extras);
+
+            Ok(entity.id())
+        };
+
+        if self.prefabs.insert(object_id.to_string(), Box::new(spawn_fn)).is_some() {
+            panic!("PrefabRegistry: Prefab {} registered twice", object_id);
+        }
+    }
+
+    /// Register a prefab for an object_id that spawns a component bundle built from its extras
+    pub fn register_bundle<E, B, F>(&mut self, object_id: &str, bundle: F)
+        where E: DeserializeOwned,
+              B: Bundle,
+              F: Fn(E) -> B + Send + Sync + 'static
+    {
+        self.register(object_id, move |entity: &mut EntityCommands, _: &WorldChunkEntity, extras: E| {
+            entity.insert_bundle(bundle(extras));
+        });
+    }
+
+    /// Get whether any prefabs are registered
+    pub fn is_empty(&self) -> bool {
+        self.prefabs.is_empty()
+    }
+
+    /// Get whether a prefab is registered for an object_id
+    pub fn contains(&self, object_id: &str) -> bool {
+        self.prefabs.contains_key(object_id)
+    }
+
+    /// Spawn a world entity from its prefab
+    pub fn spawn(&self, commands: &mut Commands, entity_info: &WorldChunkEntity) -> Result<Entity, String> {
+        let spawn_fn = self.prefabs
+            .get(entity_info.object_id())
+            .ok_or(format!("No prefab registered for object_id {} (entity {})", entity_info.object_id(),
+                entity_info.entity_id()))?;
+
+        spawn_fn(commands, entity_info)
+    }
+
+    /// Parse a world entity's extras, an entity with no extras is parsed as an empty object
+    fn parse_extras<E: DeserializeOwned>(entity_info: &WorldChunkEntity) -> Result<E, String> {
+        let extras = entity_info.extras().map(String::as_str).unwrap_or("{}");
+
+        serde_json::from_str(extras).map_err(|err| {
+            format!("Failed to parse extras for object_id {} (entity {}): {}\n{}", entity_info.object_id(),
+                entity_info.entity_id(), err, extras)
+        })
+    }
+}
+
+impl Default for PrefabRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The prefab spawner system, spawns world entities from the prefab registry. Entities without a
+/// prefab are left for the game to spawn itself.
+pub fn prefab_spawner_system(mut commands: Commands, prefabs: Res<PrefabRegistry>,
+    mut spawner: ResMut<EntitySpawnResource>, mut reader: EventReader<EntitySpawnEvent>)
+{
+    for event in reader.iter() {
+        let entity_info = &event.entity_info;
+        if !prefabs.contains(entity_info.object_id()) {
+            log::debug!("No prefab registered for object_id {} (entity {})", entity_info.object_id(),
+                entity_info.entity_id());
+            continue;
+        }
+
+        if let Err(err) = prefabs.spawn(&mut commands, entity_info) {
+            log::error!("{}", err);
+            spawner.mark_spawn_failed(entity_info.entity_id());
+        }
+    }
+}
+
+/// The prefab despawner system, despawns world entities when the entity spawner says they're out of range
+pub fn prefab_despawner_system(mut commands: Commands, mut reader: EventReader<EntityDespawnEvent>,
+    query: Query<(Entity, &WorldEntity)>)
+{
+    for event in reader.iter() {
+        for (entity, world_entity) in query.iter() {
+            if world_entity.entity_id == event.entity_id {
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy_ecs::prelude::{Entity, EventWriter};
+use bevy_ecs::system::{Query, ResMut};
+
+use crate::components::Transform;
+use crate::intersection::{Collider, Shape};
+use crate::world::WorldChunkManager;
+use crate::world::world_chunk::{WorldChunk, WorldChunkTrigger, TriggerId};
+
+/// An event sent when an entity with a collider enters a trigger volume
+pub struct TriggerEnterEvent {
+    pub entity: Entity,
+    pub trigger_id: TriggerId,
+    pub name: String,
+    pub extras: Option<String>,
+}
+
+/// An event sent when an entity with a collider leaves a trigger volume
+pub struct TriggerExitEvent {
+    pub entity: Entity,
+    pub trigger_id: TriggerId,
+    pub name: String,
+    pub extras: Option<String>,
+}
+
+/// The trigger resource, keeps track of which triggers each entity is inside
+#[derive(Default)]
+pub struct TriggerResource {
+    entities_in_triggers: HashMap<Entity, HashMap<TriggerId, WorldChunkTrigger>>,
+}
+
+impl TriggerResource {
+    /// Get whether an entity is currently inside a trigger
+    pub fn is_inside(&self, entity: Entity, trigger_id: TriggerId) -> bool {
+        self.entities_in_triggers
+            .get(&entity)
+            .map(|triggers| triggers.contains_key(&trigger_id))
+            .unwrap_or(false)
+    }
+
+    /// Forget which triggers every entity is inside, without sending exit events
+    pub fn clear(&mut self) {
+        self.entities_in_triggers.clear();
+    }
+}
+
+/// The trigger system. Checks the center of every entity's collider against the trigger volumes in
+/// its chunk, and sends events when it enters or leaves one. Entities that are removed just stop
+/// being tracked, without an exit event.
+pub fn trigger_system(mut triggers: ResMut<TriggerResource>,
+                      mut world: ResMut<WorldChunkManager>,
+                      query: Query<(Entity, &Transform, &Collider)>,
+                      mut enter_writer: EventWriter<TriggerEnterEvent>,
+                      mut exit_writer: EventWriter<TriggerExitEvent>)
+{
+    let mut live_entities = HashSet::new();
+
+    for (entity, transform, collider) in query.iter() {
+        live_entities.insert(entity);
+
+        let center = match collider.shape {
+            Shape::BoundingSpheroid(offset, _) => transform.pos + offset,
+            _ => transform.pos
+        };
+
+        // Triggers are in every chunk they overlap, so the chunk containing the point is enough
+        let inside: HashMap<TriggerId, WorldChunkTrigger> = world
+            .get_or_load_chunk(WorldChunk::point_to_chunk_index(&center))
+            .as_ref()
+            .map(|chunk| {
+                chunk.triggers().iter()
+                    .filter(|trigger| trigger.contains_point(&center))
+                    .map(|trigger| (trigger.trigger_id(), trigger.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let previous = triggers.entities_in_triggers.remove(&entity).unwrap_or_default();
+
+        for (trigger_id, trigger) in previous.iter() {
+            if !inside.contains_key(trigger_id) {
+                log::info!("Entity {:?} left trigger {}", entity, trigger.name());
+                exit_writer.send(TriggerExitEvent {
+                    entity,
+                    trigger_id: *trigger_id,
+                    name: trigger.name().to_string(),
+                    extras: trigger.extras().cloned(),
+                });
+            }
+        }
+
+        for (trigger_id, trigger) in inside.iter() {
+            if !previous.contains_key(trigger_id) {
+                log::info!("Entity {:?} entered trigger {}", entity, trigger.name());
+                enter_writer.send(TriggerEnterEvent {
+                    entity,
+                    trigger_id: *trigger_id,
+                    name: trigger.name().to_string(),
+                    extras: trigger.extras().cloned(),
+                });
+            }
+        }
+
+        if !inside.is_empty() {
+            triggers.entities_in_triggers.insert(entity, inside);
+        }
+    }
+
+    triggers.entities_in_triggers.retain(|entity, _| live_entities.contains(entity));
+}
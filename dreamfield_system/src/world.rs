@@ -4,13 +4,22 @@ pub mod world_texture;
 pub mod aabb;
 pub mod wrapped_vectors;
 pub mod world_collision;
+pub mod accessor_reader;
+pub mod build_cache;
+pub mod mesh_optimizer;
+pub mod mesh_simplifier;
+pub mod mesh_normals;
+pub mod navmesh_builder;
+pub mod vertex_lighting;
+pub mod world_navigation;
+pub mod texture_processor;
 
 use std::collections::{HashMap, HashSet};
 use bevy_ecs::prelude::Entity;
 use cgmath::Vector3;
 use speedy::Readable;
 use include_dir::Dir;
-use world_chunk::{WorldChunk, ChunkIndex};
+use world_chunk::{WorldChunk, WorldChunkLight, WorldLights, ChunkIndex};
 use world_texture::{WorldTexture, TextureIndex};
 
 /// The size of a world chunk in each dimension
@@ -20,7 +29,11 @@ use crate::{components::{Transform, EntityName}, intersection::{Collider, Shape}
 
 /// The world chunk manager
 pub struct WorldChunkManager {
+    world_dir: &'static Dir<'static>,
     world_chunks_dir: &'static Dir<'static>,
+    level: Option<String>,
+    level_generation: u32,
+    world_lights: WorldLights,
     loaded_chunks: HashMap<ChunkIndex, Option<WorldChunk>>,
     loaded_textures: HashMap<TextureIndex, Option<WorldTexture>>,
     entity_locations: HashMap<Entity, EntityLocation>,
@@ -35,10 +48,15 @@ struct EntityLocation {
 }
 
 impl WorldChunkManager {
-    /// Create new WorldChunkManager
+    /// Create new WorldChunkManager. The chunks in the root of the directory are the default level,
+    /// and each named level is a subdirectory of it.
     pub fn new(world_chunks_dir: &'static Dir<'static>) -> Self {
         Self {
+            world_dir: world_chunks_dir,
             world_chunks_dir,
+            level: None,
+            level_generation: 0,
+            world_lights: Self::load_world_lights(world_chunks_dir),
             loaded_chunks: HashMap::new(),
             loaded_textures: HashMap::new(),
             entity_locations: HashMap::new(),
@@ -47,6 +65,70 @@ impl WorldChunkManager {
         }
     }
 
+    /// Create new WorldChunkManager, starting in the named level
+    pub fn new_with_level(world_chunks_dir: &'static Dir<'static>, level: &str) -> Result<Self, String> {
+        let mut world = Self::new(world_chunks_dir);
+        world.set_level(Some(level))?;
+        Ok(world)
+    }
+
+    /// Get the name of the active level, or None for the default level
+    pub fn level(&self) -> Option<&str> {
+        self.level.as_deref()
+    }
+
+    /// Get the names of the levels available, not including the default level
+    pub fn levels(&self) -> impl Iterator<Item=&'static str> {
+        self.world_dir
+            .dirs()
+            .filter_map(|dir| dir.path().file_name())
+            .filter_map(|name| name.to_str())
+    }
+
+    /// Get a counter that's incremented every time the level changes, so that anything caching
+    /// chunk data can tell when it needs to be discarded
+    pub fn level_generation(&self) -> u32 {
+        self.level_generation
+    }
+
+    /// Switch the active level, or to the default level if None, discarding all the loaded chunks and
+    /// textures. This is done by the level_change_system in response to a ChangeLevelEvent, so that
+    /// the other world caches get cleared too.
+    pub(crate) fn set_level(&mut self, level: Option<&str>) -> Result<(), String> {
+        let world_chunks_dir = match level {
+            Some(level) => self.world_dir
+                .get_dir(self.world_dir.path().join(level))
+                .ok_or_else(|| format!("No such level: {level}"))?,
+            None => self.world_dir
+        };
+
+        log::info!("Changing level to {}", level.unwrap_or("default"));
+        self.world_chunks_dir = world_chunks_dir;
+        self.level = level.map(str::to_string);
+        self.level_generation = self.level_generation.wrapping_add(1);
+        self.world_lights = Self::load_world_lights(world_chunks_dir);
+        self.loaded_chunks.clear();
+        self.loaded_textures.clear();
+        self.entity_locations.clear();
+        self.chunk_entities.clear();
+
+        Ok(())
+    }
+
+    /// Get the lights that reach the whole level rather than being bound to a chunk, like the sun
+    pub fn world_lights(&self) -> &[WorldChunkLight] {
+        self.world_lights.lights()
+    }
+
+    /// Load the world lights from a level's directory, if it has any
+    fn load_world_lights(world_chunks_dir: &'static Dir<'static>) -> WorldLights {
+        let path = world_chunks_dir.path().join(WorldLights::filename());
+        match world_chunks_dir.get_file(&path) {
+            Some(file) => WorldLights::read_from_buffer(file.contents()).expect("Failed to load world lights"),
+            None => WorldLights::new()
+        }
+    }
+
     /// Get the specified chunk, loading it if necessary
     pub fn get_or_load_chunk(&mut self, (x, z): ChunkIndex) -> &Option<WorldChunk> {
         self.loaded_chunks
@@ -54,7 +136,8 @@ impl WorldChunkManager {
             .or_insert_with(|| {
                 log::info!("Loading world chunk {}, {}", x, z);
                 let chunk_filename = WorldChunk::filename((x, z));
-                if let Some(file) = self.world_chunks_dir.get_file(&chunk_filename) {
+                let chunk_path = self.world_chunks_dir.path().join(&chunk_filename);
+                if let Some(file) = self.world_chunks_dir.get_file(&chunk_path) {
                     let chunk = WorldChunk::read_from_buffer(file.contents()).expect("Failed to load world chunk");
                     Some(chunk)
                 }
@@ -65,6 +148,13 @@ impl WorldChunkManager {
             })
     }
 
+    /// Get the chunks that are currently loaded
+    pub fn loaded_chunks(&self) -> impl Iterator<Item=(&ChunkIndex, &WorldChunk)> {
+        self.loaded_chunks
+            .iter()
+            .filter_map(|(chunk_index, chunk)| chunk.as_ref().map(|chunk| (chunk_index, chunk)))
+    }
+
     /// Get the specified texture, loading it if necessary
     pub fn get_or_load_texture(&mut self, idx: TextureIndex) -> &Option<WorldTexture> {
         self.loaded_textures
@@ -72,7 +162,8 @@ impl WorldChunkManager {
             .or_insert_with(|| {
                 log::info!("Loading world texture {}", idx);
                 let texture_filename = WorldTexture::filename(idx);
-                if let Some(file) = self.world_chunks_dir.get_file(&texture_filename) {
+                let texture_path = self.world_chunks_dir.path().join(&texture_filename);
+                if let Some(file) = self.world_chunks_dir.get_file(&texture_path) {
                     let texture = WorldTexture::read_from_buffer(file.contents()).expect("Failed to load world texture");
                     Some(texture)
                 }
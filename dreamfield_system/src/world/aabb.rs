@@ -1,4 +1,4 @@
-use cgmath::{Vector3, vec3, ElementWise};
+use cgmath::{Vector3, vec3, ElementWise, InnerSpace};
 use speedy::{Readable, Writable};
 use super::wrapped_vectors::WrappedVector3;
 
@@ -24,6 +24,14 @@ impl Aabb {
         self.min_max = Some((WrappedVector3(*min), WrappedVector3(*max)));
     }
 
+    /// Get the distance from a point to the aabb, or None if the aabb is empty
+    pub fn distance_to_point(&self, p: &Vector3<f32>) -> Option<f32> {
+        self.min_max().map(|(min, max)| {
+            let closest = Self::vec_max(min, &Self::vec_min(max, p));
+            (p - closest).magnitude()
+        })
+    }
+
     pub fn expand_with_point(&mut self, p: &Vector3<f32>) {
         if let Some((min, max)) = self.min_max() {
             let new_min = Self::vec_min(&min, p);
@@ -85,6 +93,10 @@ impl Aabb {
         }
     }
 
+    pub fn contains_point(&self, p: &Vector3<f32>) -> bool {
+        self.intersects_sphere(p, 0.0)
+    }
+
     pub fn intersects_aabb(&self, other: &Aabb) -> bool {
         if let Some((a_min, a_max)) = &self.min_max {
             let a_min = a_min.as_vec();
@@ -0,0 +1,110 @@
+use gltf::accessor::{Accessor, DataType};
+use gltf::accessor::sparse::IndexType;
+use gltf::buffer;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Read a gltf accessor as f32s, with each element's components stored contiguously. Handles
+/// accessor offsets, interleaved (strided) buffer views, normalized integer types and sparse
+/// accessors, so it works for any attribute blender or another exporter might output.
+pub fn read_accessor_f32(accessor: &Accessor, buffers: &[buffer::Data]) -> Vec<f32> {
+    read_accessor(accessor, buffers, |data, data_type| read_component_f32(data, data_type, accessor.normalized()))
+}
+
+/// Read a gltf accessor as u32s, e.g. for mesh indices
+pub fn read_accessor_u32(accessor: &Accessor, buffers: &[buffer::Data]) -> Vec<u32> {
+    read_accessor(accessor, buffers, read_component_u32)
+}
+
+/// Read all components of an accessor, applying any sparse substitutions
+fn read_accessor<T, F>(accessor: &Accessor, buffers: &[buffer::Data], read_component: F) -> Vec<T>
+    where T: Copy + Default,
+          F: Fn(&[u8], DataType) -> T
+{
+    let data_type = accessor.data_type();
+    let component_count = accessor.dimensions().multiplicity();
+    let component_size = data_type.size();
+    let element_size = accessor.size();
+    let count = accessor.count();
+
+    let mut values = vec![T::default(); count * component_count];
+
+    // An accessor without a buffer view is all zeros, but it might still have sparse values
+    if let Some(view) = accessor.view() {
+        let buffer = &buffers[view.buffer().index()];
+        let stride = view.stride().unwrap_or(element_size);
+        let start = view.offset() + accessor.offset();
+
+        for i in 0..count {
+            let element_offset = start + i * stride;
+            for c in 0..component_count {
+                let offset = element_offset + c * component_size;
+                values[i * component_count + c] = read_component(&buffer[offset..offset + component_size], data_type);
+            }
+        }
+    }
+
+    // Overwrite the elements listed in the sparse accessor, if there is one
+    if let Some(sparse) = accessor.sparse() {
+        let sparse_indices = sparse.indices();
+        let indices_view = sparse_indices.view();
+        let indices_buffer = &buffers[indices_view.buffer().index()];
+        let indices_start = indices_view.offset() + sparse_indices.offset() as usize;
+
+        let sparse_values = sparse.values();
+        let values_view = sparse_values.view();
+        let values_buffer = &buffers[values_view.buffer().index()];
+        let values_start = values_view.offset() + sparse_values.offset() as usize;
+
+        let index_type = sparse_indices.index_type();
+        let index_size = match index_type {
+            IndexType::U8 => 1,
+            IndexType::U16 => 2,
+            IndexType::U32 => 4,
+        };
+
+        for i in 0..sparse.count() as usize {
+            let index_offset = indices_start + i * index_size;
+            let index_data = &indices_buffer[index_offset..index_offset + index_size];
+            let index = match index_type {
+                IndexType::U8 => index_data[0] as usize,
+                IndexType::U16 => LittleEndian::read_u16(index_data) as usize,
+                IndexType::U32 => LittleEndian::read_u32(index_data) as usize,
+            };
+
+            // Sparse values are always tightly packed
+            let element_offset = values_start + i * element_size;
+            for c in 0..component_count {
+                let offset = element_offset + c * component_size;
+                values[index * component_count + c] = read_component(&values_buffer[offset..offset + component_size], data_type);
+            }
+        }
+    }
+
+    values
+}
+
+/// Read a single component as an f32, converting normalized integers to the range 0..1 (or -1..1)
+fn read_component_f32(data: &[u8], data_type: DataType, normalized: bool) -> f32 {
+    match (data_type, normalized) {
+        (DataType::F32, _) => LittleEndian::read_f32(data),
+        (DataType::I8, true) => (data[0] as i8 as f32 / 127.0).max(-1.0),
+        (DataType::U8, true) => data[0] as f32 / 255.0,
+        (DataType::I16, true) => (LittleEndian::read_i16(data) as f32 / 32767.0).max(-1.0),
+        (DataType::U16, true) => LittleEndian::read_u16(data) as f32 / 65535.0,
+        (DataType::I8, false) => data[0] as i8 as f32,
+        (DataType::U8, false) => data[0] as f32,
+        (DataType::I16, false) => LittleEndian::read_i16(data) as f32,
+        (DataType::U16, false) => LittleEndian::read_u16(data) as f32,
+        (DataType::U32, _) => LittleEndian::read_u32(data) as f32,
+    }
+}
+
+/// Read a single unsigned integer component as a u32
+fn read_component_u32(data: &[u8], data_type: DataType) -> u32 {
+    match data_type {
+        DataType::U8 => data[0] as u32,
+        DataType::U16 => LittleEndian::read_u16(data) as u32,
+        DataType::U32 => LittleEndian::read_u32(data),
+        data_type => panic!("unsupported integer accessor type: {:?}", data_type)
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use speedy::{Readable, Writable};
+use super::world_chunk::{WorldChunk, WorldLights, ChunkIndex, EntityId};
+use super::world_builder::WorldBuildSettings;
+
+/// The build cache format version, this should be incremented whenever the world builder's output
+/// changes so that old caches are thrown away instead of being mixed with new chunks
+pub const BUILD_CACHE_VERSION: u32 = 16;
+
+/// The name of the build cache index file within the build cache directory
+const BUILD_CACHE_FILE: &str = "cache.json";
+
+/// The world builder's cache from the last build. Each model's contribution to each chunk it
+/// touches is kept as a chunk fragment, so that when a model changes only the chunks it touches
+/// need to be merged and written again.
+#[derive(Serialize, Deserialize, Default)]
+pub struct BuildCache {
+    version: u32,
+    settings: WorldBuildSettings,
+    instance_models: HashMap<String, u64>,
+    pub next_mesh_index: i32,
+    pub texture_count: usize,
+    pub texture_hashes: HashMap<u64, usize>,
+    pub models: HashMap<String, CachedModel>,
+}
+
+/// A model from the last build
+#[derive(Serialize, Deserialize)]
+pub struct CachedModel {
+    pub content_hash: u64,
+    pub chunks: HashSet<ChunkIndex>,
+    pub textures: HashSet<usize>,
+    pub entity_paths: HashMap<EntityId, String>,
+}
+
+impl BuildCache {
+    /// Create a new empty build cache for the given build settings and instance model hashes
+    pub fn new(settings: WorldBuildSettings, instance_models: HashMap<String, u64>) -> Self {
+        Self {
+            version: BUILD_CACHE_VERSION,
+            settings,
+            instance_models,
+            ..Default::default()
+        }
+    }
+
+    /// Load the build cache from a cache directory, returning None if there isn't a usable one for
+    /// the given build settings and instance models. Instance models affect every model's
+    /// instances, so changing them invalidates the whole cache just like the settings.
+    pub fn load(cache_dir: &Path, settings: &WorldBuildSettings, instance_models: &HashMap<String, u64>)
+        -> Option<Self>
+    {
+        let contents = std::fs::read_to_string(cache_dir.join(BUILD_CACHE_FILE)).ok()?;
+
+        match serde_json::from_str::<BuildCache>(&contents) {
+            Ok(cache) if cache.version != BUILD_CACHE_VERSION => {
+                log::warn!("Ignoring build cache version {} (expected {})", cache.version, BUILD_CACHE_VERSION);
+                None
+            }
+            Ok(cache) if cache.settings != *settings => {
+                log::info!("Build settings changed, ignoring build cache");
+                None
+            }
+            Ok(cache) if cache.instance_models != *instance_models => {
+                log::info!("Instance models changed, ignoring build cache");
+                None
+            }
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                log::warn!("Ignoring invalid build cache: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Write the build cache to a cache directory
+    pub fn save(&self, cache_dir: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(cache_dir)?;
+        std::fs::write(cache_dir.join(BUILD_CACHE_FILE), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Remove the build cache index from a cache directory, so that a build that fails part way
+    /// through leaves a clean build to happen next time instead of a half updated cache
+    pub fn invalidate(cache_dir: &Path) -> std::io::Result<()> {
+        let path = cache_dir.join(BUILD_CACHE_FILE);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Read a model's fragment of a chunk
+    pub fn read_fragment(cache_dir: &Path, model_key: u64, chunk_index: ChunkIndex) -> Result<WorldChunk, Box<dyn Error>> {
+        Ok(WorldChunk::read_from_file(Self::fragment_path(cache_dir, model_key, chunk_index))?)
+    }
+
+    /// Write a model's fragment of a chunk
+    pub fn write_fragment(cache_dir: &Path, model_key: u64, chunk_index: ChunkIndex, chunk: &WorldChunk)
+        -> Result<(), Box<dyn Error>>
+    {
+        std::fs::create_dir_all(cache_dir)?;
+        chunk.write_to_file(Self::fragment_path(cache_dir, model_key, chunk_index))?;
+        Ok(())
+    }
+
+    /// Remove a model's fragment of a chunk
+    pub fn remove_fragment(cache_dir: &Path, model_key: u64, chunk_index: ChunkIndex) -> std::io::Result<()> {
+        let path = Self::fragment_path(cache_dir, model_key, chunk_index);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Read a model's world lights, which are empty if it doesn't have any
+    pub fn read_lights_fragment(cache_dir: &Path, model_key: u64) -> Result<WorldLights, Box<dyn Error>> {
+        let path = Self::lights_fragment_path(cache_dir, model_key);
+        match path.exists() {
+            true => Ok(WorldLights::read_from_file(path)?),
+            false => Ok(WorldLights::new())
+        }
+    }
+
+    /// Write a model's world lights, if it has any
+    pub fn write_lights_fragment(cache_dir: &Path, model_key: u64, lights: &WorldLights) -> Result<(), Box<dyn Error>> {
+        if !lights.is_empty() {
+            std::fs::create_dir_all(cache_dir)?;
+            lights.write_to_file(Self::lights_fragment_path(cache_dir, model_key))?;
+        }
+        Ok(())
+    }
+
+    /// Remove a model's world lights
+    pub fn remove_lights_fragment(cache_dir: &Path, model_key: u64) -> std::io::Result<()> {
+        let path = Self::lights_fragment_path(cache_dir, model_key);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Get the path of a model's world lights
+    fn lights_fragment_path(cache_dir: &Path, model_key: u64) -> PathBuf {
+        cache_dir.join(format!("{:016x}_lights.fragment", model_key))
+    }
+
+    /// Get the path of a model's fragment of a chunk, models are identified by a hash of their
+    /// filename since filenames can contain path separators
+    fn fragment_path(cache_dir: &Path, model_key: u64, (x, z): ChunkIndex) -> PathBuf {
+        cache_dir.join(format!("{:016x}_{}_{}.fragment", model_key, x, z))
+    }
+}
@@ -0,0 +1,123 @@
+use cgmath::{Vector3, vec3, InnerSpace};
+use serde::{Serialize, Deserialize};
+use super::world_chunk::{VERTEX_STRIDE, INDEX_STRIDE};
+
+/// The offset of the tangent (xyz + handedness) in a world mesh vertex
+pub const TANGENT_OFFSET: usize = 12;
+
+/// How to generate normals for meshes that don't have any
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NormalGeneration {
+    /// Each triangle uses its face normal, which means no vertices are shared between triangles
+    Flat,
+    /// Face normals are averaged at each vertex, weighted by the area of the triangles
+    #[default]
+    Smooth,
+}
+
+/// Calculate the face normal for each triangle. Degenerate triangles get an up vector.
+pub fn face_normals(positions: &[Vector3<f32>], indices: &[u32]) -> Vec<Vector3<f32>> {
+    indices.chunks_exact(INDEX_STRIDE)
+        .map(|tri| {
+            let normal = triangle_cross(positions, tri);
+            if normal.magnitude2() > 0.0 {
+                normal.normalize()
+            }
+            else {
+                vec3(0.0, 1.0, 0.0)
+            }
+        })
+        .collect()
+}
+
+/// Calculate smooth normals for each vertex. Vertices that aren't used by any non-degenerate
+/// triangle get an up vector.
+pub fn smooth_normals(positions: &[Vector3<f32>], indices: &[u32]) -> Vec<Vector3<f32>> {
+    let mut normals = vec![vec3(0.0, 0.0, 0.0); positions.len()];
+
+    // The cross product's length is twice the triangle's area, so summing them weights by area
+    for tri in indices.chunks_exact(INDEX_STRIDE) {
+        let normal = triangle_cross(positions, tri);
+        for i in tri {
+            normals[*i as usize] += normal;
+        }
+    }
+
+    normals.into_iter()
+        .map(|normal| {
+            if normal.magnitude2() > 0.0 {
+                normal.normalize()
+            }
+            else {
+                vec3(0.0, 1.0, 0.0)
+            }
+        })
+        .collect()
+}
+
+/// Generate MikkTSpace tangents for a world mesh, writing them into the tangent part of each
+/// vertex. Returns false if tangents couldn't be generated.
+pub fn generate_tangents(vertices: &mut [f32], indices: &[u32]) -> bool {
+    assert!(vertices.len().is_multiple_of(VERTEX_STRIDE));
+    assert!(indices.len().is_multiple_of(INDEX_STRIDE));
+
+    mikktspace::generate_tangents(&mut TangentGeometry { vertices, indices })
+}
+
+/// Get the unnormalized normal of a triangle
+fn triangle_cross(positions: &[Vector3<f32>], tri: &[u32]) -> Vector3<f32> {
+    let v0 = positions[tri[0] as usize];
+    let v1 = positions[tri[1] as usize];
+    let v2 = positions[tri[2] as usize];
+    (v1 - v0).cross(v2 - v0)
+}
+
+/// A world mesh as mikktspace geometry
+struct TangentGeometry<'a> {
+    vertices: &'a mut [f32],
+    indices: &'a [u32],
+}
+
+impl TangentGeometry<'_> {
+    fn vertex_offset(&self, face: usize, vert: usize) -> usize {
+        self.indices[face * INDEX_STRIDE + vert] as usize * VERTEX_STRIDE
+    }
+
+    fn vertex(&self, face: usize, vert: usize) -> &[f32] {
+        let offset = self.vertex_offset(face, vert);
+        &self.vertices[offset .. offset + VERTEX_STRIDE]
+    }
+}
+
+impl mikktspace::Geometry for TangentGeometry<'_> {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / INDEX_STRIDE
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        INDEX_STRIDE
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        let v = self.vertex(face, vert);
+        [v[0], v[1], v[2]]
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        let v = self.vertex(face, vert);
+        [v[3], v[4], v[5]]
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        let v = self.vertex(face, vert);
+        [v[6], v[7]]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        // Vertices shared between faces just keep the last tangent written, which is the same
+        // unless there's a uv seam that the mesh doesn't have separate vertices for
+        let offset = self.vertex_offset(face, vert) + TANGENT_OFFSET;
+        self.vertices[offset .. offset + 4].copy_from_slice(&tangent);
+    }
+}
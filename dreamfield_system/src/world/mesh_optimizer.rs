@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::ops::AddAssign;
+use cgmath::{vec3, InnerSpace};
+use super::aabb::Aabb;
+use super::world_chunk::{WorldChunk, WorldChunkMesh, WorldChunkMaterial, WorldChunkCollisionMesh, VERTEX_STRIDE,
+    INDEX_STRIDE, COLLISION_VERTEX_STRIDE};
+
+/// The size of the simulated vertex cache when reordering triangles
+const VERTEX_CACHE_SIZE: usize = 32;
+
+/// Vertex cache scoring parameters, from Tom Forsyth's "Linear-Speed Vertex Cache Optimisation"
+const CACHE_DECAY_POWER: f32 = 1.5;
+const LAST_TRI_SCORE: f32 = 0.75;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+
+/// Triangles with a smaller (doubled) area than this are considered degenerate
+const DEGENERATE_AREA_EPSILON: f32 = 1.0e-10;
+
+/// Mesh, vertex and triangle counts for a set of meshes
+#[derive(Default, Debug, Clone, Copy)]
+pub struct MeshStats {
+    pub meshes: usize,
+    pub vertices: usize,
+    pub triangles: usize,
+}
+
+impl MeshStats {
+    /// Count the meshes, vertices and triangles in a set of meshes
+    pub fn of(meshes: &[WorldChunkMesh]) -> Self {
+        Self {
+            meshes: meshes.len(),
+            vertices: meshes.iter().map(|m| m.vertices().len() / VERTEX_STRIDE).sum(),
+            triangles: meshes.iter().map(|m| m.indices().len() / INDEX_STRIDE).sum(),
+        }
+    }
+}
+
+impl AddAssign for MeshStats {
+    fn add_assign(&mut self, other: Self) {
+        self.meshes += other.meshes;
+        self.vertices += other.vertices;
+        self.triangles += other.triangles;
+    }
+}
+
+/// Optimise the meshes in a chunk: meshes that share a material are merged so they can be drawn
+/// in one draw call, duplicate vertices are welded, degenerate triangles are dropped, and the
+/// triangles are reordered for vertex cache efficiency
+pub fn optimize_chunk(chunk: &mut WorldChunk) {
+    for mesh in merge_meshes_by_material(chunk.take_meshes()) {
+        if let Some(mesh) = optimize_mesh(&mesh) {
+            chunk.add_mesh(mesh);
+        }
+    }
+}
+
+/// Merge meshes that share a material into one mesh. Merged meshes keep the index of the first
+/// mesh in them, which is still unique since the others are gone.
+fn merge_meshes_by_material(meshes: Vec<WorldChunkMesh>) -> Vec<WorldChunkMesh> {
+    let mut groups: Vec<(Option<WorldChunkMaterial>, Vec<WorldChunkMesh>)> = Vec::new();
+
+    for mesh in meshes {
+        match groups.iter_mut().find(|(material, _)| material == mesh.material()) {
+            Some((_, group)) => group.push(mesh),
+            None => groups.push((mesh.material().clone(), vec![mesh])),
+        }
+    }
+
+    groups.into_iter()
+        .map(|(material, mut group)| {
+            if group.len() == 1 {
+                return group.pop().unwrap();
+            }
+
+            let mut aabb = Aabb::new();
+            let mut vertices = Vec::new();
+            let mut indices = Vec::new();
+
+            for mesh in group.iter() {
+                let base_index = (vertices.len() / VERTEX_STRIDE) as u32;
+                aabb.expand_with_aabb(mesh.aabb());
+                vertices.extend_from_slice(mesh.vertices());
+                indices.extend(mesh.indices().iter().map(|i| i + base_index));
+            }
+
+            WorldChunkMesh::new(aabb, group[0].index(), vertices, indices, material)
+        })
+        .collect()
+}
+
+/// Weld duplicate vertices, drop degenerate triangles, reorder the triangles for the vertex cache,
+/// and then reorder the vertices by first use. Returns None if no triangles remain.
+fn optimize_mesh(mesh: &WorldChunkMesh) -> Option<WorldChunkMesh> {
+    let vertices = mesh.vertices();
+
+    // Weld vertices that are exactly the same
+    let mut welded_indices: HashMap<[u32; VERTEX_STRIDE], u32> = HashMap::new();
+    let weld_map: Vec<u32> = vertices.chunks_exact(VERTEX_STRIDE)
+        .enumerate()
+        .map(|(i, vertex)| {
+            let mut key = [0; VERTEX_STRIDE];
+            for (k, v) in key.iter_mut().zip(vertex) {
+                // Treat -0.0 and 0.0 as the same
+                *k = (v + 0.0).to_bits();
+            }
+            *welded_indices.entry(key).or_insert(i as u32)
+        })
+        .collect();
+
+    // Drop degenerate triangles
+    let position = |i: u32| {
+        let offset = i as usize * VERTEX_STRIDE;
+        vec3(vertices[offset], vertices[offset + 1], vertices[offset + 2])
+    };
+
+    let indices: Vec<u32> = mesh.indices()
+        .chunks_exact(INDEX_STRIDE)
+        .map(|tri| [weld_map[tri[0] as usize], weld_map[tri[1] as usize], weld_map[tri[2] as usize]])
+        .filter(|[i1, i2, i3]| {
+            let (v1, v2, v3) = (position(*i1), position(*i2), position(*i3));
+            i1 != i2 && i2 != i3 && i1 != i3 && (v2 - v1).cross(v3 - v1).magnitude2() > DEGENERATE_AREA_EPSILON
+        })
+        .flatten()
+        .collect();
+
+    if indices.is_empty() {
+        return None;
+    }
+
+    let indices = reorder_for_vertex_cache(&indices, vertices.len() / VERTEX_STRIDE);
+
+    // Reorder vertices by first use so that vertex fetches are more linear, which also drops
+    // vertices that are no longer used
+    let mut vertex_map: HashMap<u32, u32> = HashMap::new();
+    let mut new_vertices = Vec::new();
+    let new_indices = indices.iter()
+        .map(|&i| {
+            *vertex_map.entry(i).or_insert_with(|| {
+                let offset = i as usize * VERTEX_STRIDE;
+                new_vertices.extend_from_slice(&vertices[offset .. offset + VERTEX_STRIDE]);
+                (new_vertices.len() / VERTEX_STRIDE - 1) as u32
+            })
+        })
+        .collect();
+
+    Some(WorldChunkMesh::new(mesh.aabb().clone(), mesh.index(), new_vertices, new_indices, mesh.material().clone()))
+}
+
+/// Build a simplified collision mesh from a mesh, keeping only the positions and welding vertices
+/// that share a position, so uv and normal seams don't split it. Degenerate triangles are dropped,
+/// and returns None if no triangles remain.
+pub fn build_collision_mesh(mesh: &WorldChunkMesh) -> Option<WorldChunkCollisionMesh> {
+    let mut welded_indices: HashMap<[u32; COLLISION_VERTEX_STRIDE], u32> = HashMap::new();
+    let mut aabb = Aabb::new();
+    let mut vertices = Vec::new();
+
+    let weld_map: Vec<u32> = mesh.vertices().chunks_exact(VERTEX_STRIDE)
+        .map(|vertex| {
+            let position = &vertex[0..COLLISION_VERTEX_STRIDE];
+            let key = [(position[0] + 0.0).to_bits(), (position[1] + 0.0).to_bits(), (position[2] + 0.0).to_bits()];
+            *welded_indices.entry(key).or_insert_with(|| {
+                vertices.extend_from_slice(position);
+                (vertices.len() / COLLISION_VERTEX_STRIDE - 1) as u32
+            })
+        })
+        .collect();
+
+    let position = |i: u32| {
+        let offset = i as usize * COLLISION_VERTEX_STRIDE;
+        vec3(vertices[offset], vertices[offset + 1], vertices[offset + 2])
+    };
+
+    let indices: Vec<u32> = mesh.indices()
+        .chunks_exact(INDEX_STRIDE)
+        .map(|tri| [weld_map[tri[0] as usize], weld_map[tri[1] as usize], weld_map[tri[2] as usize]])
+        .filter(|[i1, i2, i3]| {
+            let (v1, v2, v3) = (position(*i1), position(*i2), position(*i3));
+            i1 != i2 && i2 != i3 && i1 != i3 && (v2 - v1).cross(v3 - v1).magnitude2() > DEGENERATE_AREA_EPSILON
+        })
+        .flatten()
+        .collect();
+
+    if indices.is_empty() {
+        return None;
+    }
+
+    for &i in indices.iter() {
+        aabb.expand_with_point(&position(i));
+    }
+
+    Some(WorldChunkCollisionMesh::new(aabb, vertices, indices))
+}
+
+/// Reorder triangles to make better use of the gpu's post-transform vertex cache, using Tom
+/// Forsyth's linear-speed vertex cache optimisation algorithm
+fn reorder_for_vertex_cache(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let triangle_count = indices.len() / INDEX_STRIDE;
+
+    // The triangles that use each vertex and haven't been output yet
+    let mut vertex_triangles: Vec<Vec<usize>> = vec![Vec::new(); vertex_count];
+    for (t, tri) in indices.chunks_exact(INDEX_STRIDE).enumerate() {
+        for &i in tri {
+            vertex_triangles[i as usize].push(t);
+        }
+    }
+
+    let mut cache_positions: Vec<Option<usize>> = vec![None; vertex_count];
+    let mut vertex_scores: Vec<f32> = vertex_triangles.iter()
+        .map(|tris| vertex_score(None, tris.len()))
+        .collect();
+
+    let triangle_vertices = |t: usize| &indices[t * INDEX_STRIDE .. t * INDEX_STRIDE + INDEX_STRIDE];
+    let mut triangle_added = vec![false; triangle_count];
+
+    let mut cache: Vec<u32> = Vec::with_capacity(VERTEX_CACHE_SIZE + INDEX_STRIDE);
+    let mut output = Vec::with_capacity(indices.len());
+    let mut best_triangle = None;
+    let mut next_unadded = 0;
+
+    for _ in 0..triangle_count {
+        // If there's no good candidate near the cache, just take the next triangle in order
+        let t = match best_triangle.take() {
+            Some(t) => t,
+            None => {
+                while triangle_added[next_unadded] {
+                    next_unadded += 1;
+                }
+                next_unadded
+            }
+        };
+
+        // Output the triangle
+        triangle_added[t] = true;
+        let tri = triangle_vertices(t);
+        output.extend_from_slice(tri);
+
+        for &i in tri {
+            vertex_triangles[i as usize].retain(|&other| other != t);
+        }
+
+        // Move its vertices to the front of the cache
+        let mut new_cache: Vec<u32> = tri.to_vec();
+        new_cache.extend(cache.iter().filter(|i| !tri.contains(i)));
+        for &evicted in new_cache.iter().skip(VERTEX_CACHE_SIZE) {
+            cache_positions[evicted as usize] = None;
+        }
+        new_cache.truncate(VERTEX_CACHE_SIZE);
+
+        // Update the scores of every vertex that was in the cache, and find the best scoring
+        // triangle that uses one of them
+        let mut changed_vertices = cache.clone();
+        changed_vertices.extend_from_slice(tri);
+
+        for (pos, &i) in new_cache.iter().enumerate() {
+            cache_positions[i as usize] = Some(pos);
+        }
+        for &i in changed_vertices.iter() {
+            let i = i as usize;
+            vertex_scores[i] = vertex_score(cache_positions[i], vertex_triangles[i].len());
+        }
+
+        let mut best_score = -1.0;
+        for &i in changed_vertices.iter() {
+            for &other in vertex_triangles[i as usize].iter() {
+                let score: f32 = triangle_vertices(other).iter().map(|&v| vertex_scores[v as usize]).sum();
+                if score > best_score {
+                    best_score = score;
+                    best_triangle = Some(other);
+                }
+            }
+        }
+
+        cache = new_cache;
+    }
+
+    output
+}
+
+/// Score a vertex by its position in the cache and how many triangles still need it
+fn vertex_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32 {
+    if remaining_triangles == 0 {
+        return -1.0;
+    }
+
+    let cache_score = match cache_position {
+        Some(pos) if pos < INDEX_STRIDE => LAST_TRI_SCORE,
+        Some(pos) => {
+            let scale = 1.0 / (VERTEX_CACHE_SIZE - INDEX_STRIDE) as f32;
+            (1.0 - (pos - INDEX_STRIDE) as f32 * scale).powf(CACHE_DECAY_POWER)
+        },
+        None => 0.0
+    };
+
+    let valence_boost = VALENCE_BOOST_SCALE * (remaining_triangles as f32).powf(-VALENCE_BOOST_POWER);
+
+    cache_score + valence_boost
+}
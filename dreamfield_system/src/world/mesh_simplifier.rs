@@ -0,0 +1,305 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use cgmath::{Vector3, vec3, InnerSpace};
+use serde::{Serialize, Deserialize};
+use super::world_chunk::{WorldChunk, WorldChunkMesh, VERTEX_STRIDE, INDEX_STRIDE};
+
+/// A level of detail to generate for world chunks
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LodSettings {
+    /// The distance from the camera to a chunk at which this level of detail is used
+    pub distance: f32,
+
+    /// The fraction of the original triangles to keep
+    pub triangle_ratio: f32,
+}
+
+/// Generate the lower levels of detail for every mesh in a chunk. Meshes are simplified with
+/// quadric error metric edge collapses onto existing vertices, so the lods are just index lists
+/// that share the original mesh's vertices. Vertices on open edges (including chunk borders and
+/// uv or normal seams, if the mesh has been welded) are never moved, so lods don't crack.
+pub fn generate_lods(chunk: &mut WorldChunk, lods: &[LodSettings]) {
+    let mut meshes = chunk.take_meshes();
+
+    for mesh in meshes.iter_mut() {
+        let mut simplifier = MeshSimplifier::new(mesh);
+        let lod_indices = lods.iter()
+            .map(|lod| {
+                let target = (mesh.indices().len() / INDEX_STRIDE) as f32 * lod.triangle_ratio;
+                simplifier.simplify(target as usize);
+                simplifier.indices()
+            })
+            .collect();
+
+        mesh.set_lods(lod_indices);
+    }
+
+    for mesh in meshes {
+        chunk.add_mesh(mesh);
+    }
+    chunk.set_lod_distances(lods.iter().map(|lod| lod.distance).collect());
+}
+
+/// A symmetric 4x4 error quadric, the sum of the squared distances to a set of planes
+#[derive(Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    /// Create the quadric for a plane with normal n through point p, weighted by area
+    fn from_plane(n: Vector3<f64>, p: Vector3<f64>, weight: f64) -> Self {
+        let d = -n.dot(p);
+        let q = [
+            n.x * n.x, n.x * n.y, n.x * n.z, n.x * d,
+            n.y * n.y, n.y * n.z, n.y * d,
+            n.z * n.z, n.z * d,
+            d * d
+        ];
+        Quadric(q.map(|v| v * weight))
+    }
+
+    /// Add another quadric to this one
+    fn add(&mut self, other: &Quadric) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a += b;
+        }
+    }
+
+    /// Evaluate the error of a point against this quadric
+    fn error(&self, p: Vector3<f64>) -> f64 {
+        let q = &self.0;
+        q[0] * p.x * p.x + 2.0 * q[1] * p.x * p.y + 2.0 * q[2] * p.x * p.z + 2.0 * q[3] * p.x
+            + q[4] * p.y * p.y + 2.0 * q[5] * p.y * p.z + 2.0 * q[6] * p.y
+            + q[7] * p.z * p.z + 2.0 * q[8] * p.z
+            + q[9]
+    }
+}
+
+/// A candidate collapse of vertex `from` onto vertex `to`, ordered so the lowest cost is first
+struct Collapse {
+    cost: f64,
+    from: u32,
+    to: u32,
+    from_version: u32,
+    to_version: u32,
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collapse {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Incrementally simplifies a mesh, so that each level of detail continues from the last
+struct MeshSimplifier {
+    positions: Vec<Vector3<f64>>,
+    triangles: Vec<[u32; 3]>,
+    triangle_removed: Vec<bool>,
+    triangle_count: usize,
+    vertex_triangles: Vec<Vec<usize>>,
+    quadrics: Vec<Quadric>,
+    locked: Vec<bool>,
+    versions: Vec<u32>,
+    heap: BinaryHeap<Collapse>,
+}
+
+impl MeshSimplifier {
+    fn new(mesh: &WorldChunkMesh) -> Self {
+        let positions: Vec<Vector3<f64>> = mesh.vertices()
+            .chunks_exact(VERTEX_STRIDE)
+            .map(|v| vec3(v[0] as f64, v[1] as f64, v[2] as f64))
+            .collect();
+        let vertex_count = positions.len();
+
+        let triangles: Vec<[u32; 3]> = mesh.indices()
+            .chunks_exact(INDEX_STRIDE)
+            .map(|tri| [tri[0], tri[1], tri[2]])
+            .collect();
+
+        // Build vertex adjacency and quadrics
+        let mut vertex_triangles = vec![Vec::new(); vertex_count];
+        let mut quadrics = vec![Quadric::default(); vertex_count];
+        for (t, tri) in triangles.iter().enumerate() {
+            let (p1, p2, p3) = (positions[tri[0] as usize], positions[tri[1] as usize], positions[tri[2] as usize]);
+            let cross = (p2 - p1).cross(p3 - p1);
+            let area = cross.magnitude();
+            if area > 0.0 {
+                let quadric = Quadric::from_plane(cross / area, p1, area);
+                for &i in tri {
+                    quadrics[i as usize].add(&quadric);
+                }
+            }
+            for &i in tri {
+                vertex_triangles[i as usize].push(t);
+            }
+        }
+
+        // Lock vertices on open edges, i.e. edges only used by one triangle
+        let mut edge_counts: HashMap<(u32, u32), usize> = HashMap::new();
+        for tri in triangles.iter() {
+            for e in 0..3 {
+                let (a, b) = (tri[e], tri[(e + 1) % 3]);
+                *edge_counts.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+        let mut locked = vec![false; vertex_count];
+        for ((a, b), count) in edge_counts {
+            if count != 2 {
+                locked[a as usize] = true;
+                locked[b as usize] = true;
+            }
+        }
+
+        let mut simplifier = Self {
+            positions,
+            triangle_removed: vec![false; triangles.len()],
+            triangle_count: triangles.len(),
+            triangles,
+            vertex_triangles,
+            quadrics,
+            locked,
+            versions: vec![0; vertex_count],
+            heap: BinaryHeap::new(),
+        };
+
+        for v in 0..vertex_count as u32 {
+            simplifier.push_collapses(v);
+        }
+
+        simplifier
+    }
+
+    /// Collapse edges until there are no more than target_triangles triangles, or no more edges
+    /// can be collapsed
+    fn simplify(&mut self, target_triangles: usize) {
+        while self.triangle_count > target_triangles {
+            let collapse = match self.heap.pop() {
+                Some(collapse) => collapse,
+                None => break
+            };
+
+            // Skip collapses that are out of date
+            if self.versions[collapse.from as usize] != collapse.from_version ||
+                self.versions[collapse.to as usize] != collapse.to_version
+            {
+                continue;
+            }
+
+            if self.collapse_flips_triangles(collapse.from, collapse.to) {
+                continue;
+            }
+
+            self.collapse(collapse.from, collapse.to);
+        }
+    }
+
+    /// Get the current indices of the simplified mesh
+    fn indices(&self) -> Vec<u32> {
+        self.triangles.iter()
+            .zip(self.triangle_removed.iter())
+            .filter(|(_, removed)| !**removed)
+            .flat_map(|(tri, _)| *tri)
+            .collect()
+    }
+
+    /// Push candidate collapses for every edge from a vertex
+    fn push_collapses(&mut self, from: u32) {
+        if self.locked[from as usize] {
+            return;
+        }
+
+        for to in self.neighbours(from) {
+            let mut quadric = self.quadrics[from as usize];
+            quadric.add(&self.quadrics[to as usize]);
+            let cost = quadric.error(self.positions[to as usize]);
+
+            self.heap.push(Collapse {
+                cost,
+                from,
+                to,
+                from_version: self.versions[from as usize],
+                to_version: self.versions[to as usize],
+            });
+        }
+    }
+
+    /// Get the vertices that share a triangle with a vertex
+    fn neighbours(&self, v: u32) -> HashSet<u32> {
+        self.vertex_triangles[v as usize].iter()
+            .flat_map(|&t| self.triangles[t])
+            .filter(|&other| other != v)
+            .collect()
+    }
+
+    /// Check whether moving `from` onto `to` would flip any of the triangles that remain
+    fn collapse_flips_triangles(&self, from: u32, to: u32) -> bool {
+        self.vertex_triangles[from as usize].iter()
+            .map(|&t| self.triangles[t])
+            .filter(|tri| !tri.contains(&to))
+            .any(|tri| {
+                let normal = |tri: [u32; 3]| {
+                    let (p1, p2, p3) = (self.positions[tri[0] as usize], self.positions[tri[1] as usize],
+                        self.positions[tri[2] as usize]);
+                    (p2 - p1).cross(p3 - p1)
+                };
+
+                let moved = tri.map(|i| if i == from { to } else { i });
+                normal(tri).dot(normal(moved)) <= 0.0
+            })
+    }
+
+    /// Collapse vertex `from` onto vertex `to`
+    fn collapse(&mut self, from: u32, to: u32) {
+        let from_triangles = std::mem::take(&mut self.vertex_triangles[from as usize]);
+
+        for t in from_triangles {
+            if self.triangles[t].contains(&to) {
+                // Triangles on the collapsed edge disappear
+                self.triangle_removed[t] = true;
+                self.triangle_count -= 1;
+                for &i in self.triangles[t].iter() {
+                    if i != from {
+                        self.vertex_triangles[i as usize].retain(|&other| other != t);
+                    }
+                }
+            }
+            else {
+                for i in self.triangles[t].iter_mut() {
+                    if *i == from {
+                        *i = to;
+                    }
+                }
+                self.vertex_triangles[to as usize].push(t);
+            }
+        }
+
+        let from_quadric = self.quadrics[from as usize];
+        self.quadrics[to as usize].add(&from_quadric);
+
+        // Invalidate old collapses around the target, and add new ones
+        let neighbours = self.neighbours(to);
+        self.versions[from as usize] += 1;
+        self.versions[to as usize] += 1;
+        for &v in neighbours.iter() {
+            self.versions[v as usize] += 1;
+        }
+
+        self.push_collapses(to);
+        for v in neighbours {
+            self.push_collapses(v);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::{HashMap, VecDeque};
+use cgmath::{Vector3, vec3, InnerSpace};
+use serde::{Serialize, Deserialize};
+use crate::intersection::{self, Triangle};
+use super::world_chunk::{WorldChunk, WorldChunkNavMesh, WorldChunkNavCell, ChunkIndex, CHUNK_SIZE, INDEX_STRIDE,
+    COLLISION_VERTEX_STRIDE, NAV_DIRECTIONS};
+
+/// How close two surfaces in the same column can be before they're treated as the same surface
+const SURFACE_EPSILON: f32 = 0.01;
+
+/// Settings for baking navigation meshes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct NavMeshSettings {
+    /// The size of each navmesh cell, rounded so that a whole number of cells fits in a chunk
+    pub cell_size: f32,
+
+    /// The steepest slope in degrees that agents can walk on
+    pub max_slope: f32,
+
+    /// The highest step that agents can climb
+    pub step_height: f32,
+
+    /// How far agents need to stay from walls and ledges
+    pub agent_radius: f32,
+
+    /// How much headroom agents need
+    pub agent_height: f32,
+}
+
+impl Default for NavMeshSettings {
+    fn default() -> Self {
+        Self {
+            cell_size: 0.5,
+            max_slope: 45.0,
+            step_height: 0.4,
+            agent_radius: 0.5,
+            agent_height: 2.0,
+        }
+    }
+}
+
+/// A surface crossing the center of a column
+struct Surface {
+    height: f32,
+    walkable: bool,
+    facing_up: bool,
+}
+
+/// A walkable surface found while baking
+struct Sample {
+    column: (i32, i32),
+    height: f32,
+    links: u8,
+}
+
+/// Bake the navigation mesh for a chunk from its collision meshes. Walkable triangles are sampled
+/// at the center of each cell, cells with enough headroom are linked to their neighbours unless a
+/// wall or a step that's too high is in the way, and then cells too close to the edge of the
+/// walkable area are removed. Only this chunk's geometry is used, so links into neighbouring chunks
+/// are just checked for walls on this side, and walls just over the chunk border aren't kept clear
+/// of. Returns None if there's nowhere to walk in the chunk.
+pub fn bake_navmesh(chunk_index: ChunkIndex, chunk: &WorldChunk, settings: &NavMeshSettings) -> Option<WorldChunkNavMesh> {
+    let triangles: Vec<[Vector3<f32>; 3]> = chunk.collision_meshes()
+        .iter()
+        .flat_map(|mesh| {
+            let vertices = mesh.vertices();
+            mesh.indices().chunks_exact(INDEX_STRIDE).map(move |tri| {
+                let vertex = |i: u32| {
+                    let offset = i as usize * COLLISION_VERTEX_STRIDE;
+                    vec3(vertices[offset], vertices[offset + 1], vertices[offset + 2])
+                };
+                [vertex(tri[0]), vertex(tri[1]), vertex(tri[2])]
+            })
+        })
+        .collect();
+
+    let cells_per_chunk = (CHUNK_SIZE / settings.cell_size).round().max(1.0) as i32;
+    let cell_size = CHUNK_SIZE / cells_per_chunk as f32;
+    let (min_x, min_z) = (chunk_index.0 * cells_per_chunk, chunk_index.1 * cells_per_chunk);
+    let (max_x, max_z) = (min_x + cells_per_chunk - 1, min_z + cells_per_chunk - 1);
+    let in_chunk = |(x, z): (i32, i32)| x >= min_x && x <= max_x && z >= min_z && z <= max_z;
+
+    // Find every surface crossing the center of each column in the chunk, and which triangles are
+    // near each column (including the ring of columns just outside the chunk) for checking links
+    let min_walkable_normal_y = settings.max_slope.to_radians().cos();
+    let mut surfaces: HashMap<(i32, i32), Vec<Surface>> = HashMap::new();
+    let mut column_triangles: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+
+    for (t, tri) in triangles.iter().enumerate() {
+        let normal = (tri[1] - tri[0]).cross(tri[2] - tri[0]);
+        if normal.magnitude2() == 0.0 {
+            continue;
+        }
+        let normal_y = normal.normalize().y;
+
+        let tri_min_x = tri.iter().map(|v| v.x).fold(f32::MAX, f32::min);
+        let tri_max_x = tri.iter().map(|v| v.x).fold(f32::MIN, f32::max);
+        let tri_min_z = tri.iter().map(|v| v.z).fold(f32::MAX, f32::min);
+        let tri_max_z = tri.iter().map(|v| v.z).fold(f32::MIN, f32::max);
+
+        let x0 = ((tri_min_x / cell_size).floor() as i32).max(min_x - 1);
+        let x1 = ((tri_max_x / cell_size).floor() as i32).min(max_x + 1);
+        let z0 = ((tri_min_z / cell_size).floor() as i32).max(min_z - 1);
+        let z1 = ((tri_max_z / cell_size).floor() as i32).min(max_z + 1);
+
+        for x in x0..=x1 {
+            for z in z0..=z1 {
+                column_triangles.entry((x, z)).or_default().push(t);
+
+                if in_chunk((x, z)) {
+                    let center_x = (x as f32 + 0.5) * cell_size;
+                    let center_z = (z as f32 + 0.5) * cell_size;
+                    if let Some(height) = triangle_height_at(tri, center_x, center_z) {
+                        surfaces.entry((x, z)).or_default().push(Surface {
+                            height,
+                            walkable: normal_y >= min_walkable_normal_y,
+                            facing_up: normal_y > 0.0,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // Walkable surfaces with enough headroom become samples. If the next surface above is facing
+    // up, the surface is inside something solid.
+    let mut samples = Vec::new();
+    let mut column_samples: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+
+    for (column, column_surfaces) in surfaces.iter_mut() {
+        column_surfaces.sort_by(|a, b| a.height.total_cmp(&b.height));
+
+        let mut last_height = None;
+        for surface in column_surfaces.iter() {
+            let height = surface.height;
+            if !surface.walkable || last_height.map(|last| height - last < SURFACE_EPSILON).unwrap_or(false) {
+                continue;
+            }
+
+            let above = column_surfaces.iter().find(|other| other.height > height + SURFACE_EPSILON);
+            let blocked = match above {
+                Some(above) => above.facing_up || above.height < height + settings.agent_height,
+                None => false
+            };
+
+            if !blocked {
+                column_samples.entry(*column).or_default().push(samples.len());
+                samples.push(Sample { column: *column, height, links: 0 });
+                last_height = Some(height);
+            }
+        }
+    }
+
+    if samples.is_empty() {
+        return None;
+    }
+
+    // Link samples to their neighbours. Samples outside the chunk aren't known yet, so links out
+    // of the chunk are only checked against this chunk's geometry, assuming a level floor.
+    let link_blocked = |from: Vector3<f32>, to: Vector3<f32>, columns: [(i32, i32); 2]| {
+        let base = f32::max(from.y, to.y);
+        let heights = [settings.step_height + SURFACE_EPSILON, settings.agent_height - SURFACE_EPSILON];
+
+        columns.iter()
+            .filter_map(|column| column_triangles.get(column))
+            .flatten()
+            .any(|t| heights.iter().any(|height| {
+                let a = vec3(from.x, base + height, from.z);
+                let b = vec3(to.x, base + height, to.z);
+                segment_intersects_triangle(a, b, &triangles[*t])
+            }))
+    };
+
+    for s in 0..samples.len() {
+        let sample = &samples[s];
+        let from = column_center(sample.column, sample.height, cell_size);
+
+        let mut links = 0;
+        for (direction, (dx, dz)) in NAV_DIRECTIONS.iter().enumerate() {
+            let column = (sample.column.0 + dx, sample.column.1 + dz);
+
+            let to_height = if in_chunk(column) {
+                let neighbour = column_samples.get(&column)
+                    .into_iter()
+                    .flatten()
+                    .map(|n| samples[*n].height)
+                    .filter(|height| (height - sample.height).abs() <= settings.step_height)
+                    .min_by(|a, b| (a - sample.height).abs().total_cmp(&(b - sample.height).abs()));
+
+                match neighbour {
+                    Some(height) => height,
+                    None => continue
+                }
+            }
+            else {
+                sample.height
+            };
+
+            let to = column_center(column, to_height, cell_size);
+            if !link_blocked(from, to, [sample.column, column]) {
+                links |= 1 << direction;
+            }
+        }
+
+        samples[s].links = links;
+    }
+
+    // Remove samples that are too close to the edge of the walkable area, which is anywhere a
+    // sample isn't linked in one of the cardinal directions
+    let mut edge_distance: Vec<Option<u32>> = vec![None; samples.len()];
+    let mut queue = VecDeque::new();
+
+    for (s, sample) in samples.iter().enumerate() {
+        if sample.links & 0b1111 != 0b1111 {
+            edge_distance[s] = Some(0);
+            queue.push_back(s);
+        }
+    }
+
+    while let Some(s) = queue.pop_front() {
+        let distance = edge_distance[s].unwrap();
+        for (direction, (dx, dz)) in NAV_DIRECTIONS.iter().enumerate().take(4) {
+            if samples[s].links & (1 << direction) == 0 {
+                continue;
+            }
+
+            let column = (samples[s].column.0 + dx, samples[s].column.1 + dz);
+            let neighbour = column_samples.get(&column)
+                .into_iter()
+                .flatten()
+                .find(|n| (samples[**n].height - samples[s].height).abs() <= settings.step_height);
+
+            if let Some(n) = neighbour {
+                if edge_distance[*n].is_none() {
+                    edge_distance[*n] = Some(distance + 1);
+                    queue.push_back(*n);
+                }
+            }
+        }
+    }
+
+    let keep: Vec<bool> = edge_distance.iter()
+        .map(|distance| {
+            let distance = distance.map(|d| (d as f32 + 0.5) * cell_size).unwrap_or(f32::MAX);
+            distance >= settings.agent_radius
+        })
+        .collect();
+
+    // Drop links to removed samples, and diagonal links that would cut a corner
+    let cells: Vec<WorldChunkNavCell> = samples.iter()
+        .enumerate()
+        .filter(|(s, _)| keep[*s])
+        .map(|(_, sample)| {
+            let mut links = sample.links;
+
+            for (direction, (dx, dz)) in NAV_DIRECTIONS.iter().enumerate() {
+                let column = (sample.column.0 + dx, sample.column.1 + dz);
+                let neighbour_kept = !in_chunk(column) || column_samples.get(&column)
+                    .into_iter()
+                    .flatten()
+                    .any(|n| keep[*n] && (samples[*n].height - sample.height).abs() <= settings.step_height);
+
+                if !neighbour_kept {
+                    links &= !(1 << direction);
+                }
+            }
+
+            for (direction, (dx, dz)) in NAV_DIRECTIONS.iter().enumerate().skip(4) {
+                let x_direction = if *dx > 0 { 0 } else { 2 };
+                let z_direction = if *dz > 0 { 1 } else { 3 };
+                if links & (1 << x_direction) == 0 || links & (1 << z_direction) == 0 {
+                    links &= !(1 << direction);
+                }
+            }
+
+            WorldChunkNavCell::new(sample.column.0, sample.column.1, sample.height, links)
+        })
+        .collect();
+
+    if cells.is_empty() {
+        return None;
+    }
+
+    Some(WorldChunkNavMesh::new(cell_size, settings.step_height, cells))
+}
+
+/// Get the center of a column at a height
+fn column_center((x, z): (i32, i32), height: f32, cell_size: f32) -> Vector3<f32> {
+    vec3((x as f32 + 0.5) * cell_size, height, (z as f32 + 0.5) * cell_size)
+}
+
+/// Get the height of a triangle at a point on the xz plane, if the point is inside it
+fn triangle_height_at(tri: &[Vector3<f32>; 3], x: f32, z: f32) -> Option<f32> {
+    let [a, b, c] = tri;
+    let det = (b.z - c.z) * (a.x - c.x) + (c.x - b.x) * (a.z - c.z);
+    if det.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let u = ((b.z - c.z) * (x - c.x) + (c.x - b.x) * (z - c.z)) / det;
+    let v = ((c.z - a.z) * (x - c.x) + (a.x - c.x) * (z - c.z)) / det;
+    let w = 1.0 - u - v;
+
+    const EDGE_EPSILON: f32 = -1.0e-5;
+    if u >= EDGE_EPSILON && v >= EDGE_EPSILON && w >= EDGE_EPSILON {
+        Some(u * a.y + v * b.y + w * c.y)
+    }
+    else {
+        None
+    }
+}
+
+/// Check whether a line segment intersects a triangle
+fn segment_intersects_triangle(a: Vector3<f32>, b: Vector3<f32>, tri: &[Vector3<f32>; 3]) -> bool {
+    intersection::toi_segment_triangle(a, b - a, &Triangle::new(tri[0], tri[1], tri[2])).is_some()
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use gltf::image::{Data, Format};
+use serde::{Serialize, Deserialize};
+use super::world_texture::TextureFormat;
+
+/// The 4x4 bayer matrix used for ordered dithering
+const BAYER_4X4: [[f32; 4]; 4] = [
+    [ 0.0,  8.0,  2.0, 10.0],
+    [12.0,  4.0, 14.0,  6.0],
+    [ 3.0, 11.0,  1.0,  9.0],
+    [15.0,  7.0, 13.0,  5.0],
+];
+
+/// Settings for processing world textures at build time
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct TextureSettings {
+    /// The format to store textures in. Indexed formats get a palette generated for each texture.
+    pub format: TextureFormat,
+
+    /// Resize textures to the nearest power of two in each dimension
+    pub resize_to_power_of_two: bool,
+
+    /// The maximum width or height of a texture, larger ones are scaled down
+    pub max_size: Option<u32>,
+
+    /// Quantize the color channels to this many bits. For indexed formats, this applies to the
+    /// palette colors.
+    pub bit_depth: Option<u8>,
+
+    /// Use ordered dithering when quantizing or mapping pixels to the palette, instead of just
+    /// picking the nearest color
+    pub dither: bool,
+
+    /// Generate mip levels down to 1x1
+    pub generate_mips: bool,
+}
+
+impl TextureSettings {
+    /// Check that the settings make sense, since they usually come from a project file
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(bit_depth) = self.bit_depth {
+            if !(1..=8).contains(&bit_depth) {
+                return Err(format!("Texture bit_depth should be between 1 and 8, not {}", bit_depth));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A processed texture, with each mip level's pixels in the given format
+pub struct ProcessedTexture {
+    pub width: u32,
+    pub height: u32,
+    pub format: TextureFormat,
+    pub palette: Vec<u8>,
+    pub levels: Vec<Vec<u8>>,
+}
+
+/// Process a gltf image into rgba8 or palette indices, resizing, generating mips and quantizing it
+/// according to the settings, so that the runtime can just upload it
+pub fn process_texture(image: &Data, settings: &TextureSettings) -> ProcessedTexture {
+    let mut width = image.width;
+    let mut height = image.height;
+    let mut pixels = to_rgba8(image);
+
+    // Resize
+    let (target_width, target_height) = target_size(width, height, settings);
+    if (target_width, target_height) != (width, height) {
+        pixels = resize(&pixels, width, height, target_width, target_height);
+        width = target_width;
+        height = target_height;
+    }
+
+    // Generate mips from the full precision image
+    let mut levels = vec![pixels];
+    if settings.generate_mips {
+        let (mut mip_width, mut mip_height) = (width, height);
+        while mip_width > 1 || mip_height > 1 {
+            let next = downsample(levels.last().unwrap(), mip_width, mip_height);
+            mip_width = u32::max(1, mip_width / 2);
+            mip_height = u32::max(1, mip_height / 2);
+            levels.push(next);
+        }
+    }
+
+    let palette = match settings.format.palette_size() {
+        // Quantize each level
+        None => {
+            if let Some(bit_depth) = settings.bit_depth {
+                let mut level_width = width;
+                for level in levels.iter_mut() {
+                    quantize(level, level_width, bit_depth, settings.dither);
+                    level_width = u32::max(1, level_width / 2);
+                }
+            }
+            Vec::new()
+        },
+
+        // Generate a palette from the full size image, and map each level to it
+        Some(palette_size) => {
+            let mut palette = generate_palette(&levels[0], palette_size);
+            if let Some(bit_depth) = settings.bit_depth {
+                quantize(&mut palette, palette_size as u32, bit_depth, false);
+            }
+
+            let mut level_width = width;
+            for level in levels.iter_mut() {
+                let indices = map_to_palette(level, level_width, &palette, settings.dither);
+                *level = match settings.format {
+                    TextureFormat::Indexed4 => indices
+                        .chunks(2)
+                        .map(|pair| pair[0] | pair.get(1).unwrap_or(&0) << 4)
+                        .collect(),
+                    _ => indices
+                };
+                level_width = u32::max(1, level_width / 2);
+            }
+            palette
+        }
+    };
+
+    ProcessedTexture { width, height, format: settings.format, palette, levels }
+}
+
+/// Convert any gltf image format to rgba8
+fn to_rgba8(image: &Data) -> Vec<u8> {
+    let pixel_count = (image.width * image.height) as usize;
+    let p = &image.pixels;
+
+    // 16 bit images are stored in native byte order, we just keep the high byte
+    let c16 = |i: usize| (u16::from_ne_bytes([p[i * 2], p[i * 2 + 1]]) >> 8) as u8;
+
+    let mut rgba = Vec::with_capacity(pixel_count * 4);
+    for i in 0..pixel_count {
+        let pixel = match image.format {
+            Format::R8 => [p[i], p[i], p[i], 255],
+            Format::R8G8 => [p[i * 2], p[i * 2], p[i * 2], p[i * 2 + 1]],
+            Format::R8G8B8 => [p[i * 3], p[i * 3 + 1], p[i * 3 + 2], 255],
+            Format::R8G8B8A8 => [p[i * 4], p[i * 4 + 1], p[i * 4 + 2], p[i * 4 + 3]],
+            Format::B8G8R8 => [p[i * 3 + 2], p[i * 3 + 1], p[i * 3], 255],
+            Format::B8G8R8A8 => [p[i * 4 + 2], p[i * 4 + 1], p[i * 4], p[i * 4 + 3]],
+            Format::R16 => [c16(i), c16(i), c16(i), 255],
+            Format::R16G16 => [c16(i * 2), c16(i * 2), c16(i * 2), c16(i * 2 + 1)],
+            Format::R16G16B16 => [c16(i * 3), c16(i * 3 + 1), c16(i * 3 + 2), 255],
+            Format::R16G16B16A16 => [c16(i * 4), c16(i * 4 + 1), c16(i * 4 + 2), c16(i * 4 + 3)],
+        };
+        rgba.extend_from_slice(&pixel);
+    }
+
+    rgba
+}
+
+/// Get the size a texture should be resized to
+fn target_size(width: u32, height: u32, settings: &TextureSettings) -> (u32, u32) {
+    let (mut width, mut height) = (width, height);
+
+    if let Some(max_size) = settings.max_size {
+        let scale = f32::min(1.0, max_size as f32 / u32::max(width, height) as f32);
+        width = u32::max(1, (width as f32 * scale).round() as u32);
+        height = u32::max(1, (height as f32 * scale).round() as u32);
+    }
+
+    if settings.resize_to_power_of_two {
+        width = nearest_power_of_two(width);
+        height = nearest_power_of_two(height);
+
+        // Rounding up might have taken us back over the max size
+        if let Some(max_size) = settings.max_size {
+            while width > max_size && width > 1 { width /= 2; }
+            while height > max_size && height > 1 { height /= 2; }
+        }
+    }
+
+    (width, height)
+}
+
+/// Get the nearest power of two to a number
+fn nearest_power_of_two(n: u32) -> u32 {
+    let up = n.next_power_of_two();
+    let down = u32::max(1, up / 2);
+    if n - down < up - n { down } else { up }
+}
+
+/// Resize an rgba8 image with bilinear filtering
+fn resize(pixels: &[u8], width: u32, height: u32, new_width: u32, new_height: u32) -> Vec<u8> {
+    let sample = |x: u32, y: u32, c: usize| pixels[((y * width + x) * 4) as usize + c] as f32;
+
+    let mut resized = Vec::with_capacity((new_width * new_height * 4) as usize);
+    for y in 0..new_height {
+        let src_y = f32::max(0.0, (y as f32 + 0.5) * height as f32 / new_height as f32 - 0.5);
+        let (y0, fy) = (src_y.floor() as u32, src_y.fract());
+        let y1 = u32::min(y0 + 1, height - 1);
+
+        for x in 0..new_width {
+            let src_x = f32::max(0.0, (x as f32 + 0.5) * width as f32 / new_width as f32 - 0.5);
+            let (x0, fx) = (src_x.floor() as u32, src_x.fract());
+            let x1 = u32::min(x0 + 1, width - 1);
+
+            for c in 0..4 {
+                let top = sample(x0, y0, c) * (1.0 - fx) + sample(x1, y0, c) * fx;
+                let bottom = sample(x0, y1, c) * (1.0 - fx) + sample(x1, y1, c) * fx;
+                resized.push((top * (1.0 - fy) + bottom * fy).round() as u8);
+            }
+        }
+    }
+
+    resized
+}
+
+/// Downsample an rgba8 image to the next mip level with a box filter
+fn downsample(pixels: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let (new_width, new_height) = (u32::max(1, width / 2), u32::max(1, height / 2));
+    let sample = |x: u32, y: u32, c: usize| {
+        let (x, y) = (u32::min(x, width - 1), u32::min(y, height - 1));
+        pixels[((y * width + x) * 4) as usize + c] as u32
+    };
+
+    let mut downsampled = Vec::with_capacity((new_width * new_height * 4) as usize);
+    for y in 0..new_height {
+        for x in 0..new_width {
+            for c in 0..4 {
+                let sum = sample(x * 2, y * 2, c) + sample(x * 2 + 1, y * 2, c) +
+                    sample(x * 2, y * 2 + 1, c) + sample(x * 2 + 1, y * 2 + 1, c);
+                downsampled.push(((sum + 2) / 4) as u8);
+            }
+        }
+    }
+
+    downsampled
+}
+
+/// Quantize the color channels of an rgba8 image to a bit depth, optionally with ordered
+/// dithering. Alpha is left alone so that alpha tested edges don't move.
+fn quantize(pixels: &mut [u8], width: u32, bit_depth: u8, dither: bool) {
+    if !(1..=8).contains(&bit_depth) {
+        panic!("quantize: bit_depth should be (1..8)");
+    }
+
+    let max_level = ((1 << bit_depth) - 1) as f32;
+    let step = 255.0 / max_level;
+
+    for (i, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+        let offset = match dither {
+            true => {
+                let (x, y) = (i as u32 % width, i as u32 / width);
+                (BAYER_4X4[(y % 4) as usize][(x % 4) as usize] + 0.5) / 16.0 - 0.5
+            },
+            false => 0.0
+        };
+
+        for c in pixel.iter_mut().take(3) {
+            let level = (*c as f32 / step + offset).round().clamp(0.0, max_level);
+            *c = (level * step).round() as u8;
+        }
+    }
+}
+
+/// Generate a palette for an rgba8 image with median cut, returning palette_size rgba8 colors.
+/// Unused entries are left transparent black.
+fn generate_palette(pixels: &[u8], palette_size: usize) -> Vec<u8> {
+    // Count the unique colors
+    let mut counts: HashMap<[u8; 4], u32> = HashMap::new();
+    for pixel in pixels.chunks_exact(4) {
+        *counts.entry([pixel[0], pixel[1], pixel[2], pixel[3]]).or_insert(0) += 1;
+    }
+
+    // Repeatedly split the box with the widest channel range at its weighted median
+    let mut colors: Vec<([u8; 4], u32)> = counts.into_iter().collect();
+    colors.sort();
+    let mut boxes: Vec<Vec<([u8; 4], u32)>> = vec![colors];
+
+    while boxes.len() < palette_size {
+        let widest = boxes.iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(i, colors)| (i, widest_channel(colors)))
+            .max_by_key(|(_, (_, range))| *range);
+
+        let (i, channel) = match widest {
+            Some((i, (channel, _))) => (i, channel),
+            None => break
+        };
+
+        let mut colors = boxes.swap_remove(i);
+        colors.sort_by_key(|(color, _)| color[channel]);
+
+        let half = colors.iter().map(|(_, count)| count).sum::<u32>() / 2;
+        let mut total = 0;
+        let split = colors.iter()
+            .position(|(_, count)| {
+                total += count;
+                total > half
+            })
+            .unwrap()
+            .clamp(1, colors.len() - 1);
+
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    // Each palette entry is the weighted average of a box
+    let mut palette = vec![0; palette_size * 4];
+    for (entry, colors) in palette.chunks_exact_mut(4).zip(boxes.iter()) {
+        let total = colors.iter().map(|(_, count)| *count as u64).sum::<u64>();
+        for (c, value) in entry.iter_mut().enumerate() {
+            let sum = colors.iter().map(|(color, count)| color[c] as u64 * *count as u64).sum::<u64>();
+            *value = ((sum + total / 2) / total) as u8;
+        }
+    }
+
+    palette
+}
+
+/// Get the channel with the widest range of values in a set of colors, and its range
+fn widest_channel(colors: &[([u8; 4], u32)]) -> (usize, u8) {
+    (0..4)
+        .map(|c| {
+            let min = colors.iter().map(|(color, _)| color[c]).min().unwrap();
+            let max = colors.iter().map(|(color, _)| color[c]).max().unwrap();
+            (c, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+/// Map an rgba8 image to the nearest palette colors, optionally with ordered dithering, returning
+/// one palette index per pixel
+fn map_to_palette(pixels: &[u8], width: u32, palette: &[u8], dither: bool) -> Vec<u8> {
+    // The dither spread is roughly the distance between palette colors
+    let spread = 255.0 / (palette.len() as f32 / 4.0).cbrt();
+    let mut nearest_cache: HashMap<[i32; 4], u8> = HashMap::new();
+
+    pixels.chunks_exact(4)
+        .enumerate()
+        .map(|(i, pixel)| {
+            let offset = match dither {
+                true => {
+                    let (x, y) = (i as u32 % width, i as u32 / width);
+                    ((BAYER_4X4[(y % 4) as usize][(x % 4) as usize] + 0.5) / 16.0 - 0.5) * spread
+                },
+                false => 0.0
+            };
+
+            // Alpha isn't dithered, so that alpha tested edges don't move
+            let color = [
+                (pixel[0] as f32 + offset).round() as i32,
+                (pixel[1] as f32 + offset).round() as i32,
+                (pixel[2] as f32 + offset).round() as i32,
+                pixel[3] as i32
+            ];
+
+            *nearest_cache.entry(color).or_insert_with(|| nearest_palette_index(color, palette))
+        })
+        .collect()
+}
+
+/// Find the index of the nearest color in a palette
+fn nearest_palette_index(color: [i32; 4], palette: &[u8]) -> u8 {
+    palette.chunks_exact(4)
+        .enumerate()
+        .min_by_key(|(_, entry)| {
+            entry.iter().zip(color.iter())
+                .map(|(&a, &b)| (a as i32 - b) * (a as i32 - b))
+                .sum::<i32>()
+        })
+        .map(|(i, _)| i as u8)
+        .unwrap()
+}
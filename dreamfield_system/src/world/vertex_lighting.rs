@@ -0,0 +1,338 @@
+use cgmath::{Vector3, vec3, InnerSpace};
+use serde::{Serialize, Deserialize};
+use crate::intersection::{self, Triangle};
+use super::aabb::Aabb;
+use super::world_chunk::{WorldChunk, WorldChunkLight, WorldLightType, ChunkIndex, VERTEX_STRIDE, INDEX_STRIDE};
+
+/// How far rays start from the surface, so that they don't hit the triangles they start on
+const RAY_BIAS: f32 = 0.01;
+
+/// The most triangles in a bvh leaf
+const BVH_LEAF_SIZE: usize = 4;
+
+/// Settings for baking lighting into world mesh vertex colors
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct VertexLightingSettings {
+    /// The number of rays cast from each vertex for ambient occlusion, 0 to disable it
+    pub ao_samples: u32,
+
+    /// How far away geometry can be and still occlude a vertex
+    pub ao_distance: f32,
+
+    /// How much fully occluded vertices are darkened, from 0 to 1
+    pub ao_strength: f32,
+
+    /// The ambient light color, which is all a vertex gets if no lights are baked
+    pub ambient: [f32; 3],
+
+    /// Whether to bake the world's lights into vertex colors. Baked lights aren't used at runtime.
+    pub bake_lights: bool,
+
+    /// A scale for light intensities, to map them to vertex colors
+    pub light_scale: f32,
+
+    /// Whether baked lights cast shadows
+    pub shadows: bool,
+}
+
+impl Default for VertexLightingSettings {
+    fn default() -> Self {
+        Self {
+            ao_samples: 32,
+            ao_distance: 2.0,
+            ao_strength: 1.0,
+            ambient: [1.0, 1.0, 1.0],
+            bake_lights: false,
+            light_scale: 1.0,
+            shadows: true,
+        }
+    }
+}
+
+/// Bake ambient occlusion and lights into the vertex colors of every mesh in the given chunks, by
+/// casting rays against the triangles of all of their meshes. The baked lighting multiplies the
+/// existing vertex colors, and is clamped to 0..1. Meshes with unlit materials aren't changed. The
+/// world lights are baked along with the chunks' own lights.
+pub fn bake_vertex_lighting(chunks: &mut [(ChunkIndex, WorldChunk)], world_lights: &[WorldChunkLight],
+    settings: &VertexLightingSettings)
+{
+    let triangles = chunks.iter()
+        .flat_map(|(_, chunk)| chunk.meshes())
+        .flat_map(|mesh| {
+            let vertices = mesh.vertices();
+            mesh.indices().chunks_exact(INDEX_STRIDE).map(move |tri| {
+                let vertex = |i: u32| {
+                    let offset = i as usize * VERTEX_STRIDE;
+                    vec3(vertices[offset], vertices[offset + 1], vertices[offset + 2])
+                };
+                Triangle::new(vertex(tri[0]), vertex(tri[1]), vertex(tri[2]))
+            })
+        })
+        .collect();
+    let bvh = Bvh::new(triangles);
+
+    let lights: Vec<WorldChunkLight> = chunks.iter()
+        .flat_map(|(_, chunk)| chunk.lights().iter())
+        .chain(world_lights.iter())
+        .filter(|light| light.baked())
+        .cloned()
+        .collect();
+
+    let ao_directions = hemisphere_directions(settings.ao_samples);
+    let ambient = Vector3::from(settings.ambient);
+
+    for (_, chunk) in chunks.iter_mut() {
+        let mut meshes = chunk.take_meshes();
+
+        // Unlit meshes are drawn with their vertex colors as they are
+        let lit_meshes = meshes.iter_mut()
+            .filter(|mesh| !mesh.material().as_ref().map(|material| material.unlit()).unwrap_or(false));
+
+        for mesh in lit_meshes {
+            for vertex in mesh.vertices_mut().chunks_exact_mut(VERTEX_STRIDE) {
+                let pos = vec3(vertex[0], vertex[1], vertex[2]);
+                let normal = vec3(vertex[3], vertex[4], vertex[5]);
+                if normal.magnitude2() == 0.0 {
+                    continue;
+                }
+
+                let ao = ambient_occlusion(&bvh, pos, normal, &ao_directions, settings);
+                let light = lights.iter()
+                    .map(|light| light_contribution(&bvh, pos, normal, light, settings))
+                    .fold(ambient * ao, |total, light| total + light);
+
+                vertex[8] = (vertex[8] * light.x).clamp(0.0, 1.0);
+                vertex[9] = (vertex[9] * light.y).clamp(0.0, 1.0);
+                vertex[10] = (vertex[10] * light.z).clamp(0.0, 1.0);
+            }
+        }
+
+        for mesh in meshes {
+            chunk.add_mesh(mesh);
+        }
+    }
+}
+
+/// Get the fraction of ambient light that reaches a vertex
+fn ambient_occlusion(bvh: &Bvh, pos: Vector3<f32>, normal: Vector3<f32>, directions: &[Vector3<f32>],
+    settings: &VertexLightingSettings) -> f32
+{
+    if directions.is_empty() {
+        return 1.0;
+    }
+
+    // Build a basis around the normal to rotate the hemisphere directions into
+    let up = if normal.y.abs() < 0.99 { vec3(0.0, 1.0, 0.0) } else { vec3(1.0, 0.0, 0.0) };
+    let tangent = up.cross(normal).normalize();
+    let bitangent = normal.cross(tangent);
+
+    let origin = pos + normal * RAY_BIAS;
+    let occluded = directions.iter()
+        .filter(|dir| {
+            let dir = tangent * dir.x + normal * dir.y + bitangent * dir.z;
+            bvh.intersects_segment(origin, dir * settings.ao_distance)
+        })
+        .count();
+
+    1.0 - settings.ao_strength * occluded as f32 / directions.len() as f32
+}
+
+/// Get the light a vertex gets from a light, using the attenuation recommended by KHR_lights_punctual
+fn light_contribution(bvh: &Bvh, pos: Vector3<f32>, normal: Vector3<f32>, light: &WorldChunkLight,
+    settings: &VertexLightingSettings) -> Vector3<f32>
+{
+    let zero = vec3(0.0, 0.0, 0.0);
+
+    // Get the direction and distance to the light, and how much it's attenuated
+    let (to_light, distance, attenuation) = match light.light_type() {
+        WorldLightType::Directional => (-*light.dir(), f32::MAX, 1.0),
+        WorldLightType::Point | WorldLightType::Spot => {
+            let offset = light.pos() - pos;
+            let distance = offset.magnitude();
+            if distance == 0.0 {
+                return zero;
+            }
+
+            let window = light.range()
+                .map(|range| (1.0 - (distance / range).powi(4)).clamp(0.0, 1.0))
+                .unwrap_or(1.0);
+            let mut attenuation = window / (distance * distance).max(0.01);
+
+            if light.light_type() == WorldLightType::Spot {
+                let inner = light.inner_cone_angle().unwrap_or(0.0).cos();
+                let outer = light.outer_cone_angle().unwrap_or(std::f32::consts::FRAC_PI_4).cos();
+                let cos_angle = light.dir().dot(-offset / distance);
+                let t = ((cos_angle - outer) / (inner - outer).max(0.0001)).clamp(0.0, 1.0);
+                attenuation *= t * t * (3.0 - 2.0 * t);
+            }
+
+            (offset / distance, distance, attenuation)
+        }
+    };
+
+    let n_dot_l = normal.dot(to_light);
+    if n_dot_l <= 0.0 || attenuation <= 0.0 {
+        return zero;
+    }
+
+    if settings.shadows {
+        let origin = pos + normal * RAY_BIAS;
+        let shadow_distance = (distance - RAY_BIAS).min(bvh.extent());
+        if bvh.intersects_segment(origin, to_light * shadow_distance) {
+            return zero;
+        }
+    }
+
+    light.color() * light.intensity() * settings.light_scale * attenuation * n_dot_l
+}
+
+/// Get evenly spread cosine weighted directions on the hemisphere around +y
+fn hemisphere_directions(count: u32) -> Vec<Vector3<f32>> {
+    (0..count)
+        .map(|i| {
+            // Hammersley points mapped to a disc and projected up onto the hemisphere
+            let u = (i as f32 + 0.5) / count as f32;
+            let v = i.reverse_bits() as f32 / 4294967296.0;
+
+            let r = u.sqrt();
+            let theta = 2.0 * std::f32::consts::PI * v;
+            vec3(r * theta.cos(), (1.0 - u).max(0.0).sqrt(), r * theta.sin())
+        })
+        .collect()
+}
+
+/// A bounding volume hierarchy over triangles, for casting rays against
+struct Bvh {
+    nodes: Vec<BvhNode>,
+    triangles: Vec<Triangle>,
+    extent: f32,
+}
+
+/// A node in a bvh. Leaves have triangles, other nodes have two children next to each other.
+struct BvhNode {
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+    first: usize,
+    count: usize,
+}
+
+impl Bvh {
+    fn new(mut triangles: Vec<Triangle>) -> Self {
+        let mut nodes = Vec::new();
+        if !triangles.is_empty() {
+            let count = triangles.len();
+            nodes.push(BvhNode { min: vec3(0.0, 0.0, 0.0), max: vec3(0.0, 0.0, 0.0), first: 0, count });
+            Self::split(&mut nodes, &mut triangles, 0);
+        }
+
+        let extent = nodes.first()
+            .map(|root| (root.max - root.min).magnitude())
+            .unwrap_or(0.0);
+
+        Self {
+            nodes,
+            triangles,
+            extent,
+        }
+    }
+
+    /// Get the size of the diagonal of the bvh's bounds, which is the longest a ray inside it can be
+    fn extent(&self) -> f32 {
+        self.extent
+    }
+
+    /// Fit a node to its triangles and split it along its longest axis, recursively
+    fn split(nodes: &mut Vec<BvhNode>, triangles: &mut [Triangle], node: usize) {
+        let (first, count) = (nodes[node].first, nodes[node].count);
+        let node_triangles = &mut triangles[first..first + count];
+
+        let mut aabb = Aabb::new();
+        for triangle in node_triangles.iter() {
+            for i in 0..3 {
+                aabb.expand_with_point(triangle.vertex_at(i));
+            }
+        }
+        let (min, max) = aabb.min_max().map(|(min, max)| (*min, *max)).unwrap();
+        nodes[node].min = min;
+        nodes[node].max = max;
+
+        if count <= BVH_LEAF_SIZE {
+            return;
+        }
+
+        let size = max - min;
+        let axis = if size.x >= size.y && size.x >= size.z { 0 } else if size.y >= size.z { 1 } else { 2 };
+        let centroid = |triangle: &Triangle| triangle.a[axis] + triangle.b[axis] + triangle.c[axis];
+        node_triangles.select_nth_unstable_by(count / 2, |a, b| centroid(a).total_cmp(&centroid(b)));
+
+        let left = nodes.len();
+        nodes.push(BvhNode { min, max, first, count: count / 2 });
+        nodes.push(BvhNode { min, max, first: first + count / 2, count: count - count / 2 });
+        nodes[node].first = left;
+        nodes[node].count = 0;
+
+        Self::split(nodes, triangles, left);
+        Self::split(nodes, triangles, left + 1);
+    }
+
+    /// Check whether a line segment hits any triangle
+    fn intersects_segment(&self, start: Vector3<f32>, velocity: Vector3<f32>) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        // Hits right at the start are from rays touching a surface the vertex lies on, which only
+        // block the ray if it's going into the front of the surface
+        let min_toi = RAY_BIAS / velocity.magnitude();
+        let inv_velocity = vec3(1.0 / velocity.x, 1.0 / velocity.y, 1.0 / velocity.z);
+        let mut stack = vec![0];
+
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if !Self::segment_hits_bounds(start, inv_velocity, node.min, node.max) {
+                continue;
+            }
+
+            if node.count > 0 {
+                let hit = self.triangles[node.first..node.first + node.count]
+                    .iter()
+                    .any(|triangle| match intersection::toi_segment_triangle(start, velocity, triangle) {
+                        Some(toi) => toi > min_toi || Self::enters_front(velocity, triangle),
+                        None => false
+                    });
+                if hit {
+                    return true;
+                }
+            }
+            else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+
+        false
+    }
+
+    /// Check whether a direction goes into the front of a triangle
+    fn enters_front(dir: Vector3<f32>, triangle: &Triangle) -> bool {
+        (triangle.b - triangle.a).cross(triangle.c - triangle.a).dot(dir) < 0.0
+    }
+
+    /// Slab test for a segment against a box
+    fn segment_hits_bounds(start: Vector3<f32>, inv_velocity: Vector3<f32>, min: Vector3<f32>, max: Vector3<f32>)
+        -> bool
+    {
+        let mut t_min: f32 = 0.0;
+        let mut t_max: f32 = 1.0;
+
+        for axis in 0..3 {
+            let t1 = (min[axis] - start[axis]) * inv_velocity[axis];
+            let t2 = (max[axis] - start[axis]) * inv_velocity[axis];
+            t_min = t_min.max(t1.min(t2));
+            t_max = t_max.min(t1.max(t2));
+        }
+
+        t_min <= t_max
+    }
+}
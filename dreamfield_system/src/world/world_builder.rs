@@ -1,22 +1,37 @@
 use super::world_chunk::{WorldChunk, WorldChunkMesh, ChunkIndex, CHUNK_SIZE, VERTEX_STRIDE, INDEX_STRIDE,
-    WorldChunkMaterial, WorldChunkInstance, WorldChunkEntity};
+    WorldChunkMaterial, WorldAlphaMode, WorldChunkInstance, WorldChunkInstanceCollision, WorldChunkEntity, WorldChunkLight, WorldLightType, WorldChunkTrigger,
+    WorldLights, RespawnPolicy, EntityId, TriggerId};
 use super::aabb::Aabb;
 use super::world_texture::{WorldTexture, TextureIndex};
-use super::wrapped_vectors::{WrappedVector3, WrappedVector4};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::{collections::HashMap, path::Path};
-use gltf::image::Format;
-use gltf::{import_slice, buffer, image, Semantic, Node};
-use cgmath::{Matrix4, SquareMatrix, Vector3, vec4, vec3, vec2, InnerSpace};
-use byteorder::{ReadBytesExt, LittleEndian};
+use super::wrapped_vectors::{WrappedVector3, WrappedVector4, WrappedMatrix4};
+use super::build_cache::{BuildCache, CachedModel};
+use super::mesh_optimizer::{self, MeshStats};
+use super::mesh_simplifier::{self, LodSettings};
+use super::accessor_reader::{read_accessor_f32, read_accessor_u32};
+use super::texture_processor::{self, TextureSettings};
+use super::mesh_normals::{self, NormalGeneration, TANGENT_OFFSET};
+use super::navmesh_builder::{self, NavMeshSettings};
+use super::vertex_lighting::{self, VertexLightingSettings};
+use std::borrow::Cow;
+use std::ops::AddAssign;
+use std::error::Error;
+use std::path::{Component, PathBuf};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use gltf::accessor::Dimensions;
+use gltf::{import_slice, buffer, image, Semantic, Node, Document, Glb, Gltf};
+use gltf::material::AlphaMode;
+use gltf::khr_lights_punctual::{Light, Kind};
+use cgmath::{Matrix3, Matrix4, SquareMatrix, Vector3, Vector4, Quaternion, Rad, vec4, vec3, vec2, InnerSpace, Matrix,
+    One, Rotation3};
 use serde_json::value::RawValue;
 use speedy::Writable;
 use crate::build_log;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Deserializer};
 
 /// Include a world model at compile time, for use in build.rs to specify what models to build into
-/// the world chunks
+/// the world chunks. Buffers and images that a .gltf references are read from disk when it's built,
+/// relative to the path given here.
 #[macro_export]
 macro_rules! include_world_model {
     ($($tokens: tt)*) => {
@@ -42,118 +57,773 @@ pub struct WorldNodeExtras {
     #[serde(default)]
     pub instance_mesh: Option<String>,
 
+    /// The collision shape of instances, either "bounds" (the default) for the bounds of the
+    /// instance model, "spheroid" for instance_collision_offset and instance_collision_radius, or
+    /// "none". This can be set on instance nodes or in the instance model itself.
+    #[serde(default)]
+    pub instance_collision: Option<String>,
+
+    /// The center of the instance collision spheroid, relative to each instance
+    #[serde(default)]
+    pub instance_collision_offset: Option<[f32; 3]>,
+
+    /// The radius of the instance collision spheroid on each axis
+    #[serde(default)]
+    pub instance_collision_radius: Option<[f32; 3]>,
+
     #[serde(default)]
     pub object_id: Option<String>,
+
+    /// The respawn policy for entities, one of "never", "chunk_reload" or "timer"
+    #[serde(default)]
+    pub respawn: Option<String>,
+
+    /// The respawn time in seconds, for entities with respawn = "timer"
+    #[serde(default)]
+    pub respawn_time: Option<f64>,
+
+    /// An override for the entity ID, instead of deriving it from the node path
+    #[serde(default)]
+    pub entity_id: Option<EntityIdOverride>,
+
+    /// The name of a trigger, which defaults to the node name
+    #[serde(default)]
+    pub trigger_name: Option<String>,
+
+    /// The shape of a trigger volume, either "convex" (the default) for the node's mesh, which
+    /// must be convex, or "aabb" for its bounding box
+    #[serde(default)]
+    pub trigger_shape: Option<String>,
+
+    /// Whether a mesh is only rendered, and not added to the collision meshes
+    #[serde(default, deserialize_with = "WorldNodeExtras::bool_from_json")]
+    pub no_collide: bool,
+}
+
+/// The material extras we support
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WorldMaterialExtras {
+    /// Whether vertex colors multiply the material's base color, on by default
+    #[serde(default = "WorldMaterialExtras::default_vertex_colors", deserialize_with = "WorldNodeExtras::bool_from_json")]
+    pub vertex_colors: bool,
+}
+
+impl Default for WorldMaterialExtras {
+    fn default() -> Self {
+        Self {
+            vertex_colors: Self::default_vertex_colors()
+        }
+    }
 }
 
+impl WorldMaterialExtras {
+    fn default_vertex_colors() -> bool {
+        true
+    }
+}
+
+/// An entity ID override from the node extras, either the ID itself or a key to derive it from
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum EntityIdOverride {
+    Id(EntityId),
+    Key(String),
+}
 
-/// A world model - i.e. a gltf model embedded in a build script that we should build into the game world
+impl WorldNodeExtras {
+    /// Get the respawn policy for an entity node, or an error if the extras don't make sense
+    pub fn respawn_policy(&self) -> Result<RespawnPolicy, String> {
+        match self.respawn.as_deref() {
+            None | Some("never") => Ok(RespawnPolicy::Never),
+            Some("chunk_reload") => Ok(RespawnPolicy::OnChunkReload),
+            Some("timer") => self.respawn_time
+                .map(RespawnPolicy::After)
+                .ok_or_else(|| "respawn = timer must have respawn_time".to_string()),
+            Some(other) => Err(format!("unknown respawn policy {}", other))
+        }
+    }
+
+    /// Deserialize a bool from either a bool or a number, since blender exports bool custom
+    /// properties as either depending on how they were created
+    fn bool_from_json<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum BoolOrNumber {
+            Bool(bool),
+            Int(i64),
+            Float(f64),
+        }
+
+        match BoolOrNumber::deserialize(deserializer)? {
+            BoolOrNumber::Bool(value) => Ok(value),
+            BoolOrNumber::Int(value) => Ok(value != 0),
+            BoolOrNumber::Float(value) => Ok(value != 0.0),
+        }
+    }
+}
+
+
+/// A world model - i.e. a gltf model embedded in a build script, or loaded from disk by the world
+/// builder cli, that we should build into the game world
+#[derive(Clone)]
 pub struct WorldModel {
-    filename: &'static str,
-    data: &'static [u8]
+    filename: Cow<'static, str>,
+    path: Option<PathBuf>,
+    data: Cow<'static, [u8]>
 }
 
 impl WorldModel {
     /// Create a new world model, use include_world_model! instead of calling this directly
     pub const fn new(filename: &'static str, data: &'static [u8]) -> Self {
         Self {
-            filename,
-            data
+            filename: Cow::Borrowed(filename),
+            path: None,
+            data: Cow::Borrowed(data)
+        }
+    }
+
+    /// Load a world model from a file at runtime. The path is relative to root_dir, which should be
+    /// the directory build.rs would include the model from, since the model's filename is taken
+    /// relative to it. Entity IDs and the build cache are derived from the filename, so they don't
+    /// depend on where the world builder is run from.
+    pub fn from_file(root_dir: impl AsRef<Path>, path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let (root_dir, path) = (root_dir.as_ref(), path.as_ref());
+        let full_path = root_dir.join(path);
+        let data = std::fs::read(&full_path)?;
+
+        // Models outside of the root directory keep the path they were given
+        let relative_path = match (root_dir.canonicalize(), full_path.canonicalize()) {
+            (Ok(root_dir), Ok(full_path)) => full_path.strip_prefix(root_dir)
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|_| path.to_path_buf()),
+            _ => path.to_path_buf()
+        };
+
+        // Use / as the separator on every platform
+        let filename = relative_path.components()
+            .filter(|component| !matches!(component, Component::CurDir))
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        Ok(Self {
+            filename: Cow::Owned(filename),
+            path: Some(full_path),
+            data: Cow::Owned(data)
+        })
+    }
+
+    /// Get the filename of this model, which identifies it in the world
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Get the path to read this model and the files it references from
+    pub fn path(&self) -> &Path {
+        self.path.as_deref().unwrap_or(Path::new(self.filename.as_ref()))
+    }
+
+    /// Get the files that this model's buffers and images reference, resolved relative to the model.
+    /// These are empty for .glb files and .gltf files with everything embedded.
+    pub fn dependencies(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let gltf = Gltf::from_slice(&self.data)?;
+        let base_dir = self.path().parent().unwrap_or(Path::new(""));
+
+        let buffer_uris = gltf.buffers().filter_map(|buffer| match buffer.source() {
+            buffer::Source::Uri(uri) => Some(uri),
+            buffer::Source::Bin => None
+        });
+        let image_uris = gltf.images().filter_map(|image| match image.source() {
+            image::Source::Uri { uri, .. } => Some(uri),
+            image::Source::View { .. } => None
+        });
+
+        // Only files are dependencies, data uris are embedded and anything else fails to import
+        let dependencies = buffer_uris.chain(image_uris)
+            .filter_map(|uri| match uri.strip_prefix("file://").or_else(|| uri.strip_prefix("file:")) {
+                Some(path) => Some(PathBuf::from(path)),
+                None if !uri.contains(':') => Some(base_dir.join(uri)),
+                None => None
+            })
+            .collect();
+
+        Ok(dependencies)
+    }
+
+    /// Import the model, loading any buffers and images it references from disk
+    pub fn import(&self) -> gltf::Result<(Document, Vec<buffer::Data>, Vec<image::Data>)> {
+        let has_external_resources = self.dependencies().map(|deps| !deps.is_empty()).unwrap_or(false);
+        match has_external_resources {
+            true => gltf::import(self.path()),
+            false => import_slice(&self.data)
+        }
+    }
+}
+
+/// A summary of what was built, for reporting. Incremental builds only count the chunks and
+/// textures that were written again.
+#[derive(Default, Debug)]
+pub struct WorldBuildSummary {
+    pub models_built: usize,
+    pub models_cached: usize,
+    pub chunks: usize,
+    pub meshes: MeshStats,
+    pub unoptimized_meshes: MeshStats,
+    pub textures: usize,
+    pub entities: usize,
+}
+
+impl AddAssign for WorldBuildSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.models_built += other.models_built;
+        self.models_cached += other.models_cached;
+        self.chunks += other.chunks;
+        self.meshes += other.meshes;
+        self.unoptimized_meshes += other.unoptimized_meshes;
+        self.textures += other.textures;
+        self.entities += other.entities;
+    }
+}
+
+impl std::fmt::Display for WorldBuildSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} models ({} cached), rebuilt {} chunks ({} meshes, {} vertices, {} triangles, {} entities) and \
+            {} textures", self.models_built, self.models_cached, self.chunks, self.meshes.meshes, self.meshes.vertices,
+            self.meshes.triangles, self.entities, self.textures)
+    }
+}
+
+/// How meshes are clipped to the chunks they overlap
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipMode {
+    /// Triangles touching a chunk are kept whole, so triangles on a chunk border end up in every
+    /// chunk they overlap
+    #[default]
+    WholeTriangles,
+    /// Triangles are split against the chunk bounds, with all vertex attributes interpolated, so
+    /// each piece of surface belongs to exactly one chunk
+    Exact,
+}
+
+/// Settings that affect the world builder's output. Changing them invalidates the build cache.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct WorldBuildSettings {
+    pub clip_mode: ClipMode,
+
+    /// Whether to merge meshes that share a material, weld vertices, drop degenerate triangles
+    /// and reorder triangles for the vertex cache
+    pub optimize_meshes: bool,
+
+    /// The lower levels of detail to generate for each chunk, in order of increasing distance.
+    /// Simplification relies on meshes being welded, so this has little effect without
+    /// optimize_meshes.
+    pub lods: Vec<LodSettings>,
+
+    /// How to process world textures
+    pub textures: TextureSettings,
+
+    /// How to generate normals for meshes that don't have them
+    pub missing_normals: NormalGeneration,
+
+    /// How to bake ambient occlusion and lighting into vertex colors, or None to not bake them
+    pub vertex_lighting: Option<VertexLightingSettings>,
+
+    /// How to bake navigation meshes for each chunk, or None to not bake them
+    pub navmesh: Option<NavMeshSettings>,
+
+    /// Whether to generate MikkTSpace tangents for meshes that don't have them. This needs
+    /// texture coordinates, meshes that don't end up with tangents just have zeroes.
+    pub generate_tangents: bool,
+}
+
+impl Default for WorldBuildSettings {
+    fn default() -> Self {
+        Self {
+            clip_mode: ClipMode::default(),
+            optimize_meshes: true,
+            lods: vec![
+                LodSettings { distance: 48.0, triangle_ratio: 0.5 },
+                LodSettings { distance: 96.0, triangle_ratio: 0.25 },
+            ],
+            textures: TextureSettings::default(),
+            vertex_lighting: None,
+            navmesh: Some(NavMeshSettings::default()),
+            missing_normals: NormalGeneration::default(),
+            generate_tangents: false,
         }
     }
 }
 
 /// World builder
 pub struct WorldBuilder {
-    out_dir: &'static str,
-    models: &'static [WorldModel],
+    out_dir: PathBuf,
+    models: Vec<WorldModel>,
+    level: Option<String>,
+    levels: Vec<(String, Vec<WorldModel>)>,
+    cargo_output: bool,
+    settings: WorldBuildSettings,
     chunks: HashMap<ChunkIndex, WorldChunk>,
+    world_lights: WorldLights,
     textures: Vec<WorldTexture>,
+    texture_count: usize,
     texture_hashes: HashMap<u64, usize>,
-    entity_count: i32,
+    free_textures: BTreeSet<usize>,
+    entity_paths: HashMap<EntityId, String>,
+    instance_models: HashMap<String, WorldModel>,
+    instance_collisions: HashMap<String, Option<WorldChunkInstanceCollision>>,
+    node_instances: HashMap<usize, Vec<Matrix4<f32>>>,
 }
 
 impl WorldBuilder {
-    /// Create a new world builder
-    pub fn new(out_dir: &'static str, models: &'static [WorldModel]) -> Self {
+    /// Create a new world builder for use in build.rs, which logs and tracks dependencies through cargo
+    pub fn new(out_dir: &str, models: &[WorldModel]) -> Self {
         Self {
-            out_dir,
-            models,
+            out_dir: PathBuf::from(out_dir),
+            models: models.to_vec(),
+            level: None,
+            levels: Vec::new(),
+            cargo_output: true,
+            settings: WorldBuildSettings::default(),
             chunks: HashMap::new(),
+            world_lights: WorldLights::new(),
             textures: Vec::new(),
+            texture_count: 0,
             texture_hashes: HashMap::new(),
-            entity_count: 0
+            free_textures: BTreeSet::new(),
+            entity_paths: HashMap::new(),
+            instance_models: HashMap::new(),
+            instance_collisions: HashMap::new(),
+            node_instances: HashMap::new(),
         }
     }
 
-    // Build world models
+    /// Create a new world builder for use outside of build.rs, which logs to stdout instead
+    pub fn new_standalone(out_dir: impl Into<PathBuf>, models: Vec<WorldModel>) -> Self {
+        Self {
+            out_dir: out_dir.into(),
+            models,
+            cargo_output: false,
+            ..Self::new("", &[])
+        }
+    }
+
+    /// Set the settings for the world build
+    pub fn set_settings(&mut self, settings: WorldBuildSettings) {
+        self.settings = settings;
+    }
+
+    /// Add a model that's instanced in the world under the given name, so that instances of it can
+    /// get their collision shape from its extras or bounds
+    pub fn add_instance_model(&mut self, name: &str, model: WorldModel) {
+        self.instance_models.insert(name.to_string(), model);
+    }
+
+    /// Add a named level, which gets its own chunk grid in a subdirectory of the output directory.
+    /// The models passed to the constructor make up the default level.
+    pub fn add_level(&mut self, name: &str, models: &[WorldModel]) {
+        if name.is_empty() || name.contains(['/', '\\', '.']) {
+            panic!("Invalid level name: {:?}", name);
+        }
+        if self.levels.iter().any(|(level, _)| level == name) {
+            panic!("Level {} added twice", name);
+        }
+        self.levels.push((name.to_string(), models.to_vec()));
+    }
+
+    // Build world models, panicking on failure
     pub fn build_world_models(&mut self) {
-        std::fs::remove_dir_all(self.out_dir).unwrap();
-        std::fs::create_dir_all(self.out_dir).unwrap();
+        self.build().unwrap();
+    }
+
+    /// Build world models and write the chunks and textures to the output directory, followed by
+    /// any named levels. Builds are incremental: models that haven't changed since the last build
+    /// are skipped, and only the chunks touched by changed models are written again. Malformed
+    /// models still panic with a description of what's wrong with them.
+    pub fn build(&mut self) -> Result<WorldBuildSummary, Box<dyn Error>> {
+        self.settings.textures.validate()?;
+        let mut summary = self.build_level()?;
+
+        for (name, models) in self.levels.iter() {
+            self.log(&format!("Building level {}", name));
+            let mut level_builder = Self {
+                out_dir: self.out_dir.join(name),
+                models: models.clone(),
+                level: Some(name.clone()),
+                cargo_output: self.cargo_output,
+                settings: self.settings.clone(),
+                instance_models: self.instance_models.clone(),
+                ..Self::new("", &[])
+            };
+            summary += level_builder.build_level()?;
+        }
+
+        Ok(summary)
+    }
+
+    /// Build the models of a single level into the output directory
+    fn build_level(&mut self) -> Result<WorldBuildSummary, Box<dyn Error>> {
+        let cache_dir = self.cache_dir();
+        let instance_model_hashes: HashMap<String, u64> = self.instance_models.iter()
+            .map(|(name, model)| Ok((name.clone(), Self::model_content_hash(model)?)))
+            .collect::<Result<_, Box<dyn Error>>>()?;
+
+        // Load the cache from the last build, or start from a clean output directory. If the output
+        // directory is gone the cache is no use either.
+        let cache = match self.out_dir.exists() {
+            true => BuildCache::load(&cache_dir, &self.settings, &instance_model_hashes),
+            false => None
+        };
+        let mut cache = match cache {
+            Some(cache) => cache,
+            None => {
+                self.log("No usable build cache, rebuilding all models");
+                self.clean_out_dir()?;
+                BuildCache::new(self.settings.clone(), instance_model_hashes)
+            }
+        };
+        BuildCache::invalidate(&cache_dir)?;
 
         // Tell cargo to rerun build.rs if any of the models change
-        for model in self.models {
-            println!("cargo:rerun-if-changed=./{}", model.filename);
+        if self.cargo_output {
+            for model in self.models.iter().chain(self.instance_models.values()) {
+                println!("cargo:rerun-if-changed=./{}", model.path().display());
+                for dependency in model.dependencies()? {
+                    println!("cargo:rerun-if-changed={}", dependency.display());
+                }
+            }
         }
 
-        let mut world_mesh_count: i32 = 0;
+        self.load_instance_model_collisions()?;
+
+        let mut world_mesh_count = cache.next_mesh_index;
+        self.texture_count = cache.texture_count;
+        self.texture_hashes = std::mem::take(&mut cache.texture_hashes);
+
+        // Forget models that changed or were removed since the last build, the chunks they were in
+        // need to be written again
+        let models = std::mem::take(&mut self.models);
+        let content_hashes: HashMap<&str, u64> = models.iter()
+            .map(|model| Ok((model.filename(), Self::model_content_hash(model)?)))
+            .collect::<Result<_, Box<dyn Error>>>()?;
 
-        // Iterate all models and add their meshes to the world chunks
-        for model in self.models.iter() {
-            build_log!("Processing model {}", model.filename);
-            let (doc, buffer_data, image_data) = import_slice(model.data).unwrap();
+        let mut dirty_chunks = HashSet::new();
+        let stale_models: Vec<String> = cache.models.iter()
+            .filter(|(filename, cached)| content_hashes.get(filename.as_str()) != Some(&cached.content_hash))
+            .map(|(filename, _)| filename.clone())
+            .collect();
+
+        for filename in stale_models {
+            let cached = cache.models.remove(&filename).unwrap();
+            let model_key = Self::stable_hash(filename.as_bytes());
+            for chunk_index in cached.chunks {
+                BuildCache::remove_fragment(&cache_dir, model_key, chunk_index)?;
+                dirty_chunks.insert(chunk_index);
+            }
+            BuildCache::remove_lights_fragment(&cache_dir, model_key)?;
+        }
+
+        // Entity IDs from unchanged models are still taken
+        for cached in cache.models.values() {
+            self.entity_paths.extend(cached.entity_paths.clone());
+        }
+
+        // Textures that only the forgotten models used are removed, and their indices reused
+        let used_textures: HashSet<usize> = cache.models.values()
+            .flat_map(|cached| cached.textures.iter().cloned())
+            .collect();
+        self.texture_hashes.retain(|_, idx| used_textures.contains(idx));
+        self.free_textures = (0..self.texture_count).filter(|idx| !used_textures.contains(idx)).collect();
+        for idx in self.free_textures.iter() {
+            let texture_path = self.out_dir.join(WorldTexture::filename(*idx as TextureIndex));
+            if texture_path.exists() {
+                std::fs::remove_file(texture_path)?;
+            }
+        }
+
+        // Build changed models, writing each one's contribution to each chunk as a fragment
+        let mut summary = WorldBuildSummary::default();
+        for model in models.iter() {
+            if cache.models.contains_key(model.filename()) {
+                summary.models_cached += 1;
+                continue;
+            }
+            summary.models_built += 1;
+
+            self.log(&format!("Processing model {}", model.filename));
+            let (doc, buffer_data, image_data) = model.import()
+                .map_err(|err| format!("Failed to import {}: {}", model.filename, err))?;
+            self.node_instances = Self::read_gpu_instancing(&model.data, &doc, &buffer_data)
+                .map_err(|err| format!("Failed to read EXT_mesh_gpu_instancing in {}: {}", model.filename, err))?;
+
+            let existing_entities: HashSet<EntityId> = self.entity_paths.keys().cloned().collect();
             let mut model_textures = HashMap::new();
             for scene in doc.scenes() {
                 for n in scene.nodes() {
-                    self.walk_nodes(&Matrix4::identity(), &n, &buffer_data, &image_data, &mut world_mesh_count,
-                        &mut model_textures, None);
+                    self.walk_nodes(&Matrix4::identity(), &n, &model.filename, &buffer_data, &image_data,
+                        &mut world_mesh_count, &mut model_textures, None);
                 }
             }
+
+            let model_key = Self::stable_hash(model.filename().as_bytes());
+            let mut chunks = HashSet::new();
+            for (chunk_index, chunk) in self.chunks.drain() {
+                BuildCache::write_fragment(&cache_dir, model_key, chunk_index, &chunk)?;
+                chunks.insert(chunk_index);
+                dirty_chunks.insert(chunk_index);
+            }
+            BuildCache::write_lights_fragment(&cache_dir, model_key, &std::mem::take(&mut self.world_lights))?;
+
+            let entity_paths = self.entity_paths.iter()
+                .filter(|(entity_id, _)| !existing_entities.contains(entity_id))
+                .map(|(entity_id, path)| (*entity_id, path.clone()))
+                .collect();
+
+            cache.models.insert(model.filename().to_string(), CachedModel {
+                content_hash: content_hashes[model.filename()],
+                chunks,
+                textures: model_textures.values().map(|idx| *idx as usize).collect(),
+                entity_paths,
+            });
+        }
+        self.models = models;
+
+        // Baked lighting depends on the whole world, so every chunk is rebuilt when it's enabled
+        if self.settings.vertex_lighting.is_some() {
+            for cached in cache.models.values() {
+                dirty_chunks.extend(cached.chunks.iter().cloned());
+            }
+        }
+
+        // Merge the fragments of each dirty chunk
+        let mut chunks = Vec::new();
+        for chunk_index in dirty_chunks {
+            let mut chunk = WorldChunk::new();
+            for (filename, cached) in cache.models.iter() {
+                if cached.chunks.contains(&chunk_index) {
+                    let model_key = Self::stable_hash(filename.as_bytes());
+                    chunk.append(BuildCache::read_fragment(&cache_dir, model_key, chunk_index)?);
+                }
+            }
+
+            summary.unoptimized_meshes += MeshStats::of(chunk.meshes());
+            if self.settings.optimize_meshes {
+                mesh_optimizer::optimize_chunk(&mut chunk);
+            }
+            summary.meshes += MeshStats::of(chunk.meshes());
+
+            chunks.push((chunk_index, chunk));
         }
 
-        // Write chunks
-        for ((x, z), chunk) in self.chunks.iter() {
-            let chunk_filename = WorldChunk::filename((*x, *z));
-            let chunk_path = Path::new(self.out_dir).join(chunk_filename);
-            chunk.write_to_file(chunk_path).unwrap();
+        // Merge every model's world lights, they're cheap enough to always write again
+        let mut world_lights = WorldLights::new();
+        for filename in cache.models.keys() {
+            let model_key = Self::stable_hash(filename.as_bytes());
+            world_lights.append(BuildCache::read_lights_fragment(&cache_dir, model_key)?);
         }
 
-        // Write textures
-        for (i, tex) in self.textures.iter().enumerate() {
-            let texture_filename = WorldTexture::filename(i as TextureIndex);
-            let texture_path = Path::new(self.out_dir).join(texture_filename);
-            tex.write_to_file(texture_path).unwrap();
+        if let Some(lighting_settings) = &self.settings.vertex_lighting {
+            vertex_lighting::bake_vertex_lighting(&mut chunks, world_lights.lights(), lighting_settings);
+        }
+
+        let world_lights_path = self.out_dir.join(WorldLights::filename());
+        if !world_lights.is_empty() {
+            world_lights.write_to_file(world_lights_path)?;
+        }
+        else if world_lights_path.exists() {
+            std::fs::remove_file(world_lights_path)?;
+        }
+
+        // Finish each chunk and write it, chunks that no longer have anything in them are removed
+        for (chunk_index, mut chunk) in chunks {
+            if !self.settings.lods.is_empty() {
+                mesh_simplifier::generate_lods(&mut chunk, &self.settings.lods);
+            }
+
+            if let Some(navmesh_settings) = &self.settings.navmesh {
+                chunk.set_navmesh(navmesh_builder::bake_navmesh(chunk_index, &chunk, navmesh_settings));
+            }
+
+            let chunk_path = self.out_dir.join(WorldChunk::filename(chunk_index));
+            if chunk.is_empty() {
+                if chunk_path.exists() {
+                    std::fs::remove_file(chunk_path)?;
+                }
+                continue;
+            }
+
+            chunk.write_to_file(chunk_path)?;
+
+            summary.chunks += 1;
+            summary.entities += chunk.entities().len();
+        }
+
+        // Write new textures, existing ones are left alone
+        for tex in self.textures.drain(..) {
+            let texture_path = self.out_dir.join(WorldTexture::filename(tex.index()));
+            tex.write_to_file(texture_path)?;
+            summary.textures += 1;
+        }
+
+        // Free indices at the end don't need to be counted
+        while self.texture_count > 0 && self.free_textures.remove(&(self.texture_count - 1)) {
+            self.texture_count -= 1;
+        }
+
+        // Save the cache for next time
+        cache.next_mesh_index = world_mesh_count;
+        cache.texture_count = self.texture_count;
+        cache.texture_hashes = std::mem::take(&mut self.texture_hashes);
+        cache.save(&cache_dir)?;
+
+        self.log(&format!("Built {}", summary));
+        if self.settings.optimize_meshes {
+            let before = &summary.unoptimized_meshes;
+            self.log(&format!("Optimized meshes from {} meshes, {} vertices, {} triangles", before.meshes,
+                before.vertices, before.triangles));
+        }
+
+        Ok(summary)
+    }
+
+    /// Get the build cache directory. This is next to the output directory rather than in it, so
+    /// that it doesn't get embedded in the game along with the chunks. Named levels are built into
+    /// a subdirectory of the output directory, so their caches go next to the root one instead.
+    fn cache_dir(&self) -> PathBuf {
+        match &self.level {
+            Some(level) => {
+                let root_dir = self.out_dir.parent().unwrap_or(Path::new(""));
+                let mut cache_dir = root_dir.as_os_str().to_os_string();
+                cache_dir.push(format!(".{}.cache", level));
+                PathBuf::from(cache_dir)
+            },
+            None => {
+                let mut cache_dir = self.out_dir.clone().into_os_string();
+                cache_dir.push(".cache");
+                PathBuf::from(cache_dir)
+            }
+        }
+    }
+
+    /// Create the output directory, removing any chunks, textures, lights and build cache left over from a
+    /// previous build, without touching anything else that might be in there
+    fn clean_out_dir(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.out_dir)?;
+
+        for entry in std::fs::read_dir(&self.out_dir)? {
+            let path = entry?.path();
+            let is_build_output = matches!(path.extension().and_then(|ext| ext.to_str()), Some("chunk" | "texture" | "lights"));
+            if path.is_file() && is_build_output {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        let cache_dir = self.cache_dir();
+        if cache_dir.exists() {
+            std::fs::remove_dir_all(cache_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Log a message, as a cargo warning in build.rs so that it's actually visible
+    fn log(&self, msg: &str) {
+        if self.cargo_output {
+            build_log!("{}", msg);
+        }
+        else {
+            println!("{}", msg);
         }
     }
 
     /// Walk model hierarchy, adding geometry to chunks
-    fn walk_nodes(&mut self, parent_world_transform: &Matrix4<f32>, node: &Node, buffers: &[buffer::Data],
-        image_data: &[image::Data], world_mesh_count: &mut i32, model_textures: &mut HashMap<usize, i32>,
-        parent_node_extras: Option<&Box<RawValue>>)
+    fn walk_nodes(&mut self, parent_world_transform: &Matrix4<f32>, node: &Node, parent_path: &str,
+        buffers: &[buffer::Data], image_data: &[image::Data], world_mesh_count: &mut i32,
+        model_textures: &mut HashMap<usize, i32>, parent_node_extras: Option<&Box<RawValue>>)
     {
         let local_transform = cgmath::Matrix4::from(node.transform().matrix());
         let world_transform = parent_world_transform * local_transform;
 
+        // The path of the node within the model, used to derive stable entity IDs. Unnamed nodes
+        // fall back to their index.
+        let node_path = match node.name() {
+            Some(name) => format!("{}/{}", parent_path, name),
+            None => format!("{}/#{}", parent_path, node.index()),
+        };
+
         // Pass down extras until they're replaced so they inherit.. This allows us to read a
         // blender node's custom properties when we're on the mesh node.
         let node_extras = node.extras().as_ref().or(parent_node_extras);
-        let node_extras_parsed: Option<WorldNodeExtras> = node_extras.as_ref().map(|extras| {
-            serde_json::from_str(extras.get()).unwrap()
+        let node_extras_parsed: Option<WorldNodeExtras> = node_extras.as_ref().and_then(|extras| {
+            match serde_json::from_str(extras.get()) {
+                Ok(extras) => Some(extras),
+                Err(err) => {
+                    self.log(&format!("Warning: Ignoring invalid extras on node {}: {}", node_path, err));
+                    None
+                }
+            }
         });
 
-        if let Some(mesh) = node.mesh() {
+        // Meshes instanced with EXT_mesh_gpu_instancing are added as instances of the mesh instead
+        let gpu_instances = self.node_instances.get(&node.index()).cloned();
+        if let (Some(mesh), Some(transforms)) = (node.mesh(), gpu_instances) {
+            let instance_mesh = node_extras_parsed.as_ref()
+                .and_then(|e| e.instance_mesh.clone())
+                .or(mesh.name().map(|name| name.to_string()));
+
+            match instance_mesh {
+                Some(instance_mesh) => {
+                    let collision = self.instance_collision(&instance_mesh, node_extras_parsed.as_ref());
+                    let transforms = transforms.iter().map(|transform| world_transform * transform).collect();
+                    self.add_instance_transforms(instance_mesh, transforms, collision);
+                },
+                None => self.log(&format!("Warning: Skipping node {} with EXT_mesh_gpu_instancing but no \
+                    instance_mesh or mesh name", node_path))
+            }
+        }
+        else if let Some(mesh) = node.mesh() {
             for prim in mesh.primitives() {
                 // Either load the primitive as point instances, or as a regular mesh
                 let node_type = node_extras_parsed.as_ref().map(|e| e.node_type.clone()).flatten();
                 if let Some(node_type) = node_type.as_ref() {
                     if node_type == "instances" {
-                        let instance_mesh = node_extras_parsed.as_ref()
-                            .map(|e| e.instance_mesh.clone())
-                            .flatten()
-                            .expect(&format!("Node {} with node_type = instances must have instance_mesh",
-                                node.name().unwrap_or("no-name")));
-
-                        self.add_instances(&prim, &world_transform, instance_mesh, &buffers);
+                        let instance_mesh = match node_extras_parsed.as_ref().and_then(|e| e.instance_mesh.clone()) {
+                            Some(instance_mesh) => instance_mesh,
+                            None => {
+                                self.log(&format!("Warning: Skipping node {} with node_type = instances but no \
+                                    instance_mesh", node_path));
+                                continue;
+                            }
+                        };
+
+                        let collision = self.instance_collision(&instance_mesh, node_extras_parsed.as_ref());
+                        self.add_instances(&prim, &world_transform, instance_mesh, collision, &buffers);
+                    }
+                    else if node_type == "collision" {
+                        self.add_mesh(&node, &prim, &buffers, &world_transform, world_mesh_count, None, false, true);
+                    }
+                    else if node_type == "trigger" {
+                        let node_name = node.name().unwrap_or("no-name");
+                        let trigger_path = match prim.index() {
+                            0 => node_path.clone(),
+                            i => format!("{}#{}", node_path, i),
+                        };
+                        let name = node_extras_parsed.as_ref()
+                            .and_then(|e| e.trigger_name.clone())
+                            .unwrap_or(node_name.to_string());
+                        let convex = match node_extras_parsed.as_ref().and_then(|e| e.trigger_shape.as_deref()) {
+                            None | Some("convex") => true,
+                            Some("aabb") => false,
+                            Some(other) => {
+                                self.log(&format!("Warning: Skipping trigger {} with unknown trigger_shape {}",
+                                    node_path, other));
+                                continue;
+                            }
+                        };
+
+                        self.add_trigger(&prim, &world_transform, Self::stable_hash(trigger_path.as_bytes()), name,
+                            convex, &buffers, node_extras);
                     }
                     else if node_type == "entity" {
                         let object_id = node_extras_parsed.as_ref()
@@ -162,19 +832,42 @@ impl WorldBuilder {
                             .expect(&format!("Node {} with node_type = entity must have object_id",
                                 node.name().unwrap_or("no-name")));
 
-                        self.add_entity(&prim, &world_transform, object_id, &buffers, node_extras);
+                        let respawn_policy = node_extras_parsed.as_ref()
+                            .map(|e| e.respawn_policy())
+                            .unwrap_or(Ok(RespawnPolicy::Never))
+                            .unwrap_or_else(|err| {
+                                self.log(&format!("Warning: Entity {} won't respawn: {}", node_path, err));
+                                RespawnPolicy::Never
+                            });
+
+                        // Nodes with more than one primitive get one entity per primitive
+                        let entity_path = match prim.index() {
+                            0 => node_path.clone(),
+                            i => format!("{}#{}", node_path, i),
+                        };
+                        let entity_id_override = node_extras_parsed.as_ref().and_then(|e| e.entity_id.as_ref());
+                        let entity_id = self.allocate_entity_id(&entity_path, entity_id_override);
+
+                        self.add_entity(&prim, &world_transform, entity_id, object_id, respawn_policy, &buffers,
+                            node_extras);
                     }
                 }
                 else {
                     let material = self.load_material(&prim.material(), model_textures, image_data);
-                    self.add_mesh(&node, &prim, &buffers, &world_transform, world_mesh_count, material);
+                    let collide = !node_extras_parsed.as_ref().map(|e| e.no_collide).unwrap_or(false);
+                    self.add_mesh(&node, &prim, &buffers, &world_transform, world_mesh_count, Some(material), true,
+                        collide);
                 }
             }
         }
 
+        if let Some(light) = node.light() {
+            self.add_light(&light, &world_transform);
+        }
+
         for child in node.children() {
-            self.walk_nodes(&world_transform, &child, &buffers, &image_data, world_mesh_count, model_textures,
-                node_extras);
+            self.walk_nodes(&world_transform, &child, &node_path, &buffers, &image_data, world_mesh_count,
+                model_textures, node_extras);
         }
     }
 
@@ -184,117 +877,110 @@ impl WorldBuilder {
     {
         let pbr = material.pbr_metallic_roughness();
         let base_color = pbr.base_color_factor();
+        let emissive = material.emissive_factor();
 
-        // Get texture index, inserting it into the texture map if it's not already there
-        let texture = pbr.base_color_texture()
-            .map(|tex_info| {
-                let image = tex_info.texture().source();
-                let data = &image_data[image.index()];
-
-                *model_textures
-                    .entry(image.index())
-                    .or_insert_with(|| {
-                        // Hash image data to check if it's already in the world textures
-                        let mut s = DefaultHasher::new();
-                        data.pixels.hash(&mut s);
-                        let image_hash = s.finish();
-
-                        let texture_index = *self.texture_hashes
-                            .entry(image_hash)
-                            .or_insert_with(|| {
-                                let idx = self.textures.len();
-                                let pixels = data.pixels.clone();
-                                let gl_format = Self::format_to_gl_enum(data.format);
-                                let texture = WorldTexture::new(pixels, gl_format, data.width, data.height, idx as TextureIndex);
-                                self.textures.push(texture);
-                                idx
-                            });
+        let base_color_tex = pbr.base_color_texture()
+            .map(|tex_info| self.load_texture(&tex_info.texture(), model_textures, image_data));
+        let emissive_tex = material.emissive_texture()
+            .map(|tex_info| self.load_texture(&tex_info.texture(), model_textures, image_data));
 
-                        texture_index as i32
-                    })
-            });
+        let alpha_mode = match material.alpha_mode() {
+            AlphaMode::Opaque => WorldAlphaMode::Opaque,
+            AlphaMode::Mask => WorldAlphaMode::Mask,
+            AlphaMode::Blend => WorldAlphaMode::Blend,
+        };
 
-        WorldChunkMaterial::new(WrappedVector4(vec4(base_color[0], base_color[1], base_color[2], base_color[3])), texture)
+        let extras: WorldMaterialExtras = material.extras().as_ref()
+            .and_then(|extras| match serde_json::from_str(extras.get()) {
+                Ok(extras) => Some(extras),
+                Err(err) => {
+                    self.log(&format!("Warning: Ignoring invalid extras on material {}: {}",
+                        material.name().unwrap_or("no-name"), err));
+                    None
+                }
+            })
+            .unwrap_or_default();
+
+        WorldChunkMaterial::new(
+            WrappedVector4(vec4(base_color[0], base_color[1], base_color[2], base_color[3])),
+            base_color_tex,
+            alpha_mode,
+            material.alpha_cutoff().unwrap_or(0.5),
+            material.double_sided(),
+            WrappedVector3(vec3(emissive[0], emissive[1], emissive[2])),
+            emissive_tex,
+            material.unlit(),
+            extras.vertex_colors)
     }
 
-    /// Convert gltf image format to a gl enum
-    fn format_to_gl_enum(format: Format) -> u32 {
-        match format {
-            Format::R8 => gl::RED,
-            Format::R8G8 => gl::RG,
-            Format::R8G8B8 => gl::RGB,
-            Format::R8G8B8A8 => gl::RGBA,
-            Format::R16 => gl::R16,
-            Format::R16G16 => gl::RG16,
-            Format::R16G16B16 => gl::RGB16,
-            Format::R16G16B16A16 => gl::RGBA16,
-            Format::B8G8R8 => gl::BGR,
-            Format::B8G8R8A8 => gl::BGRA
-        }
+    /// Get the world texture index for a gltf texture, inserting it into the world textures if it's
+    /// not already there
+    fn load_texture(&mut self, texture: &gltf::Texture, model_textures: &mut HashMap<usize, i32>,
+        image_data: &[image::Data]) -> i32
+    {
+        let image = texture.source();
+        let data = &image_data[image.index()];
+
+        *model_textures
+            .entry(image.index())
+            .or_insert_with(|| {
+                // Hash image data to check if it's already in the world textures
+                let image_hash = Self::stable_hash(&data.pixels);
+
+                let texture_index = *self.texture_hashes
+                    .entry(image_hash)
+                    .or_insert_with(|| {
+                        let idx = self.free_textures.pop_first().unwrap_or_else(|| {
+                            self.texture_count += 1;
+                            self.texture_count - 1
+                        });
+                        let processed = texture_processor::process_texture(data, &self.settings.textures);
+                        let mut levels = processed.levels.into_iter();
+                        let pixels = levels.next().unwrap();
+                        let texture = WorldTexture::new(pixels, levels.collect(), processed.format,
+                            processed.palette, processed.width, processed.height, idx as TextureIndex);
+                        self.textures.push(texture);
+                        idx
+                    });
+
+                texture_index as i32
+            })
     }
 
-    /// Add a gltf primitive to the world as a WorldChunkMesh
+    /// Add a gltf primitive to the world as a WorldChunkMesh for rendering, and/or a
+    /// WorldChunkCollisionMesh for collision
     fn add_mesh(&mut self, node: &gltf::Node, prim: &gltf::Primitive, buffers: &[buffer::Data],
-        world_transform: &Matrix4<f32>, world_mesh_count: &mut i32, material: WorldChunkMaterial)
+        world_transform: &Matrix4<f32>, world_mesh_count: &mut i32, material: Option<WorldChunkMaterial>, render: bool,
+        collide: bool)
     {
         // Read indices for mesh
-        let indices = prim.indices().map(|accessor| {
-            let buffer_view = accessor.view().unwrap();
-            let buffer_index = buffer_view.buffer().index();
-
-            let buffer = &buffers[buffer_index];
+        let indices = prim.indices().map(|accessor| read_accessor_u32(&accessor, buffers));
 
-            if accessor.data_type() != gltf::accessor::DataType::U16 {
-                panic!("not u16 mesh indices: {:?}", accessor.data_type());
-            }
-            let data_type_size = std::mem::size_of::<u16>();
-
-            let offset = buffer_view.offset();
-            let length_bytes = buffer_view.length();
-            let length_elements = length_bytes / data_type_size;
-
-            let mut indices = vec![0; length_elements];
-            let mut slice = &buffer[offset..offset+length_bytes];
-            slice.read_u16_into::<LittleEndian>(&mut indices).expect("Failed!");
-
-            indices
-        });
-
-        // Read vertex attributes for mesh
+        // Read vertex attributes for mesh, expanding rgb vertex colors to rgba
         let attribs = prim.attributes()
             .map(|(semantic, accessor)| {
-                let buffer_view  = accessor.view().unwrap();
-                let buffer_index = buffer_view.buffer().index();
-
-                let buffer = &buffers[buffer_index];
+                let values = read_accessor_f32(&accessor, buffers);
 
-                let attrib_stride = buffer_view.stride().unwrap_or(0) as i32;
-                if attrib_stride != 0 {
-                    panic!("unhandled");
-                }
+                let values = match (&semantic, accessor.dimensions()) {
+                    (Semantic::Colors(_), Dimensions::Vec3) => values
+                        .chunks_exact(3)
+                        .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 1.0])
+                        .collect(),
+                    _ => values
+                };
 
-                // Assuming that it's always gl::FLOAT but I might be wrong
-                let data_size_type = std::mem::size_of::<f32>();
-
-                let offset = buffer_view.offset();
-                let length_bytes = buffer_view.length();
-                let length_elements = length_bytes / data_size_type;
-
-                let mut vertices = vec![0.0; length_elements];
-                let mut slice = &buffer[offset..offset+length_bytes];
-                slice.read_f32_into::<LittleEndian>(&mut vertices).expect("Failed!");
-
-                (semantic, vertices)
+                (semantic, values)
             })
-        .collect::<HashMap<Semantic, Vec<f32>>>();
+            .collect::<HashMap<Semantic, Vec<f32>>>();
 
-        // Enforce that we now have indices and vertices, if we want to support non-indexed
-        // meshes (which are uncommon and blender's gltf exporter doesn't output them),
-        // we'll have to change this
-        let indices = indices.expect(&format!("Mesh {} must have indices", node.name().unwrap_or("no-name")));
-        let (vertices, aabb) = Self::build_mesh_vertices(&attribs, &world_transform);
+        let name = node.name().unwrap_or("no-name");
+        let (vertices, indices, aabb) = match self.build_mesh_vertices(name, &attribs, indices, world_transform) {
+            Some(mesh) => mesh,
+            None => return
+        };
 
         // Add the mesh to each chunk that the mesh overlaps
+        let clip_mode = self.settings.clip_mode;
         if let Some((min, max)) = aabb.min_max().map(|(a, b)| (a.clone(), b.clone())) {
             // Get the min and max
             let (chunk_x_min, chunk_z_min) = WorldChunk::point_to_chunk_index(&min);
@@ -307,75 +993,303 @@ impl WorldBuilder {
                     let chunk_bounds_min = vec3(x as f32 * CHUNK_SIZE, -1000.0, z as f32 * CHUNK_SIZE);
                     let chunk_bounds_max = vec3(chunk_bounds_min.x + CHUNK_SIZE, 1000.0, chunk_bounds_min.z + CHUNK_SIZE);
 
-                    let chunk_mesh = Self::clip_mesh_to_aabb(&vertices, &indices, &chunk_bounds_min, &chunk_bounds_max,
-                        *world_mesh_count, &material);
+                    let chunk_mesh = match clip_mode {
+                        ClipMode::WholeTriangles => Self::clip_mesh_to_aabb(&vertices, &indices, &chunk_bounds_min,
+                            &chunk_bounds_max, *world_mesh_count, &material),
+                        ClipMode::Exact => Self::split_mesh_to_aabb(&vertices, &indices, &chunk_bounds_min,
+                            &chunk_bounds_max, *world_mesh_count, &material),
+                    };
 
                     if let Some(mesh) = chunk_mesh {
-                        *world_mesh_count += 1;
-                        chunk.add_mesh(mesh.clone());
+                        if collide {
+                            if let Some(collision_mesh) = mesh_optimizer::build_collision_mesh(&mesh) {
+                                chunk.add_collision_mesh(collision_mesh);
+                            }
+                        }
+                        if render {
+                            *world_mesh_count += 1;
+                            chunk.add_mesh(mesh);
+                        }
                     }
                 }
             }
         }
     }
 
-    /// Add instances
-    fn add_instances(&mut self, prim: &gltf::Primitive, world_transform: &Matrix4<f32>, mesh: String, buffers: &[buffer::Data]) {
-        let points: Vec<WrappedVector3> = prim.attributes()
+    /// Add instances at the points of a primitive. Points can have a NORMAL attribute to align the
+    /// instance's up axis to, a _SCALE attribute that's either a scalar or a vec3, and a _YAW_SEED
+    /// attribute to randomly rotate them around their up axis.
+    fn add_instances(&mut self, prim: &gltf::Primitive, world_transform: &Matrix4<f32>, mesh: String,
+        collision: Option<WorldChunkInstanceCollision>, buffers: &[buffer::Data])
+    {
+        let positions = prim.attributes()
             .find(|attrib| attrib.0 == Semantic::Positions)
-            .map(|(_, accessor)| {
-                let buffer_view  = accessor.view().unwrap();
-                let buffer_index = buffer_view.buffer().index();
-
-                let buffer = &buffers[buffer_index];
-
-                let attrib_stride = buffer_view.stride().unwrap_or(0) as i32;
-                if attrib_stride != 0 {
-                    panic!("unhandled");
+            .map(|(_, accessor)| read_accessor_f32(&accessor, buffers))
+            .expect("Instance mesh must have points");
+        let count = positions.len() / 3;
+
+        let normals = prim.attributes()
+            .find(|attrib| attrib.0 == Semantic::Normals)
+            .map(|(_, accessor)| read_accessor_f32(&accessor, buffers));
+
+        let scales = prim.get(&Semantic::Extras("SCALE".to_string()))
+            .and_then(|accessor| match accessor.dimensions() {
+                Dimensions::Scalar => Some((read_accessor_f32(&accessor, buffers), 1)),
+                Dimensions::Vec3 => Some((read_accessor_f32(&accessor, buffers), 3)),
+                _ => {
+                    self.log(&format!("Warning: Ignoring _SCALE attribute for instances of {} that isn't a scalar \
+                        or vec3", mesh));
+                    None
                 }
+            });
 
-                // Assuming that it's always gl::FLOAT but I might be wrong
-                let data_size_type = std::mem::size_of::<f32>();
-
-                let offset = buffer_view.offset();
-                let length_bytes = buffer_view.length();
-                let length_elements = length_bytes / data_size_type;
+        let yaw_seeds = prim.get(&Semantic::Extras("YAW_SEED".to_string()))
+            .map(|accessor| read_accessor_f32(&accessor, buffers));
+
+        let transforms = (0..count)
+            .map(|i| {
+                let translation = Matrix4::from_translation(vec3(positions[i * 3], positions[i * 3 + 1],
+                    positions[i * 3 + 2]));
+
+                let align = normals.as_ref()
+                    .and_then(|normals| normals.get(i * 3..i * 3 + 3))
+                    .map(|n| vec3(n[0], n[1], n[2]))
+                    .filter(|n| n.magnitude2() > 0.0)
+                    .map(|n| Quaternion::from_arc(vec3(0.0, 1.0, 0.0), n.normalize(), Some(vec3(1.0, 0.0, 0.0))))
+                    .unwrap_or(Quaternion::one());
+
+                let yaw = yaw_seeds.as_ref()
+                    .and_then(|seeds| seeds.get(i))
+                    .map(|seed| {
+                        let t = Self::stable_hash(&seed.to_le_bytes()) as f64 / u64::MAX as f64;
+                        Quaternion::from_angle_y(Rad((t * std::f64::consts::TAU) as f32))
+                    })
+                    .unwrap_or(Quaternion::one());
 
-                let mut vertices = vec![0.0; length_elements];
-                let mut slice = &buffer[offset..offset+length_bytes];
-                slice.read_f32_into::<LittleEndian>(&mut vertices).expect("Failed!");
+                let scale = match &scales {
+                    Some((scales, 1)) => scales.get(i).map(|s| Matrix4::from_scale(*s)),
+                    Some((scales, _)) => scales.get(i * 3..i * 3 + 3)
+                        .map(|s| Matrix4::from_nonuniform_scale(s[0], s[1], s[2])),
+                    None => None
+                };
 
-                vertices
+                world_transform * translation * Matrix4::from(align * yaw) * scale.unwrap_or(Matrix4::identity())
             })
-            .expect("Instance mesh must have points")
-            .chunks_exact(3)
-            .map(|v| WrappedVector3((world_transform * vec4(v[0], v[1], v[2], 1.0)).truncate()))
             .collect();
 
-        // Split points by chunk
-        let mut chunk_points = HashMap::<ChunkIndex, Vec<WrappedVector3>>::new();
+        self.add_instance_transforms(mesh, transforms, collision);
+    }
 
-        for point in points.iter() {
-            chunk_points
-                .entry(WorldChunk::point_to_chunk_index(point.as_vec()))
+    /// Add instances with the given world transforms, split by chunk
+    fn add_instance_transforms(&mut self, mesh: String, transforms: Vec<Matrix4<f32>>,
+        collision: Option<WorldChunkInstanceCollision>)
+    {
+        let mut chunk_transforms = HashMap::<ChunkIndex, Vec<WrappedMatrix4>>::new();
+
+        for transform in transforms {
+            chunk_transforms
+                .entry(WorldChunk::point_to_chunk_index(&transform.w.truncate()))
                 .or_insert_with(Vec::new)
-                .push(point.clone());
+                .push(WrappedMatrix4(transform));
         }
 
-        // Add points to chunks
-        for (chunk_index, points) in chunk_points.iter() {
-            self.get_chunk(*chunk_index)
-                .add_instances(WorldChunkInstance::new(mesh.clone(), points.to_vec()));
+        for (chunk_index, transforms) in chunk_transforms {
+            self.get_chunk(chunk_index)
+                .add_instances(WorldChunkInstance::new(mesh.clone(), transforms, collision.clone()));
         }
     }
 
-    /// Add an entity
-    fn add_entity(&mut self, prim: &gltf::Primitive, world_transform: &Matrix4<f32>, object_id: String,
-        buffers: &[buffer::Data], raw_extras: Option<&Box<RawValue>>)
+    /// Get the collision shape for instances of a mesh, from the instance node's extras, or the
+    /// instance model's extras or bounds if it was added with add_instance_model
+    fn instance_collision(&mut self, mesh: &str, node_extras: Option<&WorldNodeExtras>)
+        -> Option<WorldChunkInstanceCollision>
+    {
+        if let Some(extras) = node_extras {
+            match Self::instance_collision_from_extras(extras) {
+                Ok(Some(collision)) => return collision,
+                Ok(None) => {},
+                Err(err) => self.log(&format!("Warning: Ignoring instance collision extras on instances of {}: {}",
+                    mesh, err))
+            }
+        }
+
+        // Instance models are all in here already
+        if !self.instance_collisions.contains_key(mesh) {
+            // Keep the old default for meshes we don't know anything about
+            self.log(&format!("Warning: No instance model or collision extras for instances of {}, \
+                using a default collision shape", mesh));
+            let collision = Some(WorldChunkInstanceCollision::new(vec3(0.0, 1.0, 0.0), vec3(1.0, 2.0, 1.0)));
+            self.instance_collisions.insert(mesh.to_string(), collision);
+        }
+
+        self.instance_collisions[mesh].clone()
+    }
+
+    /// Get an explicit instance collision shape from some extras, None if they don't have one, or
+    /// an error if they don't make sense
+    fn instance_collision_from_extras(extras: &WorldNodeExtras)
+        -> Result<Option<Option<WorldChunkInstanceCollision>>, String>
+    {
+        let spheroid = || {
+            let radius = extras.instance_collision_radius
+                .ok_or("instance_collision = spheroid must have instance_collision_radius")?;
+            let offset = extras.instance_collision_offset.unwrap_or([0.0, 0.0, 0.0]);
+            Ok(Some(Some(WorldChunkInstanceCollision::new(Vector3::from(offset), Vector3::from(radius)))))
+        };
+
+        match extras.instance_collision.as_deref() {
+            None if extras.instance_collision_radius.is_some() => spheroid(),
+            None | Some("bounds") => Ok(None),
+            Some("spheroid") => spheroid(),
+            Some("none") => Ok(Some(None)),
+            Some(other) => Err(format!("unknown instance_collision {}", other))
+        }
+    }
+
+    /// Work out the collision shapes of all the instance models up front, so that a model that
+    /// can't be imported fails the build
+    fn load_instance_model_collisions(&mut self) -> Result<(), Box<dyn Error>> {
+        let names: Vec<String> = self.instance_models.keys().cloned().collect();
+        for name in names {
+            let collision = self.instance_model_collision(&name, &self.instance_models[&name])?;
+            self.instance_collisions.insert(name, collision);
+        }
+        Ok(())
+    }
+
+    /// Get the collision shape for an instance model from its scene or node extras, or a spheroid
+    /// fitting its bounds
+    fn instance_model_collision(&self, mesh: &str, model: &WorldModel)
+        -> Result<Option<WorldChunkInstanceCollision>, Box<dyn Error>>
     {
-        let entity_id = self.entity_count;
-        self.entity_count += 1;
+        let (doc, buffers, _) = model.import()
+            .map_err(|err| format!("Failed to import instance model {}: {}", model.filename, err))?;
+
+        // Extras are parsed after walking the model, so that invalid ones can be logged
+        let mut raw_extras: Vec<(String, String)> = Vec::new();
+        let mut aabb = Aabb::new();
+
+        fn walk(node: &Node, parent_transform: &Matrix4<f32>, buffers: &[buffer::Data],
+            raw_extras: &mut Vec<(String, String)>, aabb: &mut Aabb)
+        {
+            let transform = parent_transform * Matrix4::from(node.transform().matrix());
+            if let Some(node_extras) = node.extras() {
+                let location = format!("node {}", node.name().unwrap_or("no-name"));
+                raw_extras.push((location, node_extras.get().to_string()));
+            }
+
+            for prim in node.mesh().iter().flat_map(|mesh| mesh.primitives()) {
+                if let Some(accessor) = prim.get(&Semantic::Positions) {
+                    for p in read_accessor_f32(&accessor, buffers).chunks_exact(3) {
+                        aabb.expand_with_point(&(transform * vec4(p[0], p[1], p[2], 1.0)).truncate());
+                    }
+                }
+            }
+
+            for child in node.children() {
+                walk(&child, &transform, buffers, raw_extras, aabb);
+            }
+        }
+
+        for scene in doc.scenes() {
+            if let Some(scene_extras) = scene.extras() {
+                raw_extras.push(("the scene".to_string(), scene_extras.get().to_string()));
+            }
+            for node in scene.nodes() {
+                walk(&node, &Matrix4::identity(), &buffers, &mut raw_extras, &mut aabb);
+            }
+        }
+
+        for (location, json) in raw_extras.iter() {
+            let extras: WorldNodeExtras = match serde_json::from_str(json) {
+                Ok(extras) => extras,
+                Err(err) => {
+                    self.log(&format!("Warning: Ignoring invalid extras on {} of instance model {}: {}", location,
+                        mesh, err));
+                    continue;
+                }
+            };
+
+            match Self::instance_collision_from_extras(&extras) {
+                Ok(Some(collision)) => return Ok(collision),
+                Ok(None) => {},
+                Err(err) => self.log(&format!("Warning: Ignoring instance collision extras on {} of instance model {}: {}",
+                    location, mesh, err))
+            }
+        }
+
+        // Flat models can't have a spheroid around them, so they don't get any collision
+        let collision = aabb.min_max()
+            .filter(|(min, max)| max.x > min.x && max.y > min.y && max.z > min.z)
+            .map(|(min, max)| WorldChunkInstanceCollision::new((min + max) * 0.5, (max - min) * 0.5));
+
+        Ok(collision)
+    }
+
+    /// Read the per-instance transforms of nodes using EXT_mesh_gpu_instancing, by node index. The
+    /// gltf crate doesn't know about the extension, so it's read from the raw json.
+    fn read_gpu_instancing(data: &[u8], doc: &Document, buffers: &[buffer::Data])
+        -> Result<HashMap<usize, Vec<Matrix4<f32>>>, Box<dyn Error>>
+    {
+        let json: serde_json::Value = match data.starts_with(b"glTF") {
+            true => serde_json::from_slice(&Glb::from_slice(data)?.json)?,
+            false => serde_json::from_slice(data)?
+        };
 
+        let read_attribute = |attributes: &serde_json::Value, name: &str| -> Result<Option<Vec<f32>>, String> {
+            match attributes.get(name).and_then(|index| index.as_u64()) {
+                Some(index) => doc.accessors().nth(index as usize)
+                    .map(|accessor| Some(read_accessor_f32(&accessor, buffers)))
+                    .ok_or(format!("Invalid {} accessor {}", name, index)),
+                None => Ok(None)
+            }
+        };
+
+        let mut node_instances = HashMap::new();
+        let nodes = json.get("nodes").and_then(|nodes| nodes.as_array()).into_iter().flatten();
+
+        for (i, node) in nodes.enumerate() {
+            let attributes = match node.pointer("/extensions/EXT_mesh_gpu_instancing/attributes") {
+                Some(attributes) => attributes,
+                None => continue
+            };
+
+            let translations = read_attribute(attributes, "TRANSLATION")?;
+            let rotations = read_attribute(attributes, "ROTATION")?;
+            let scales = read_attribute(attributes, "SCALE")?;
+
+            let count = [(&translations, 3), (&rotations, 4), (&scales, 3)].iter()
+                .filter_map(|(values, stride)| values.as_ref().map(|values| values.len() / stride))
+                .min()
+                .unwrap_or(0);
+
+            let transforms = (0..count)
+                .map(|j| {
+                    let translation = translations.as_ref()
+                        .map(|t| Matrix4::from_translation(vec3(t[j * 3], t[j * 3 + 1], t[j * 3 + 2])))
+                        .unwrap_or(Matrix4::identity());
+                    let rotation = rotations.as_ref()
+                        .map(|r| Matrix4::from(Quaternion::new(r[j * 4 + 3], r[j * 4], r[j * 4 + 1], r[j * 4 + 2])
+                            .normalize()))
+                        .unwrap_or(Matrix4::identity());
+                    let scale = scales.as_ref()
+                        .map(|s| Matrix4::from_nonuniform_scale(s[j * 3], s[j * 3 + 1], s[j * 3 + 2]))
+                        .unwrap_or(Matrix4::identity());
+
+                    translation * rotation * scale
+                })
+                .collect();
+
+            node_instances.insert(i, transforms);
+        }
+
+        Ok(node_instances)
+    }
+
+    /// Add an entity
+    fn add_entity(&mut self, prim: &gltf::Primitive, world_transform: &Matrix4<f32>, entity_id: EntityId,
+        object_id: String, respawn_policy: RespawnPolicy, buffers: &[buffer::Data], raw_extras: Option<&Box<RawValue>>)
+    {
         // Add this entity to exactly the chunk it's supposed to be in based on its transform
         let chunk = {
             let entity_pos = world_transform.w.truncate();
@@ -388,68 +1302,267 @@ impl WorldBuilder {
         // useful anyway.
         let points = prim.attributes()
             .find(|attrib| attrib.0 == Semantic::Positions)
-            .map(|(_, accessor)| {
-                let buffer_view  = accessor.view().unwrap();
-                let buffer_index = buffer_view.buffer().index();
+            .map(|(_, accessor)| read_accessor_f32(&accessor, buffers))
+            .map(|points| {
+                points.chunks_exact(3)
+                .map(|v| WrappedVector3((world_transform * vec4(v[0], v[1], v[2], 1.0)).truncate()))
+                .collect()
+            });
+
+        chunk.add_entity(WorldChunkEntity::new(entity_id, object_id, *world_transform, points,
+            raw_extras.map(|e| e.get().to_string()), respawn_policy));
+    }
+
+    /// Add a trigger volume to each chunk it overlaps
+    fn add_trigger(&mut self, prim: &gltf::Primitive, world_transform: &Matrix4<f32>, trigger_id: TriggerId,
+        name: String, convex: bool, buffers: &[buffer::Data], raw_extras: Option<&Box<RawValue>>)
+    {
+        let points: Vec<Vector3<f32>> = prim.attributes()
+            .find(|attrib| attrib.0 == Semantic::Positions)
+            .map(|(_, accessor)| read_accessor_f32(&accessor, buffers))
+            .unwrap_or_default()
+            .chunks_exact(3)
+            .map(|v| (world_transform * vec4(v[0], v[1], v[2], 1.0)).truncate())
+            .collect();
 
-                let buffer = &buffers[buffer_index];
+        if points.is_empty() {
+            self.log(&format!("Warning: Skipping trigger {} with no points", name));
+            return;
+        }
 
-                let attrib_stride = buffer_view.stride().unwrap_or(0) as i32;
-                if attrib_stride != 0 {
-                    panic!("unhandled");
+        let mut aabb = Aabb::new();
+        for point in points.iter() {
+            aabb.expand_with_point(point);
+        }
+
+        // Use each distinct triangle plane as a plane of the volume, facing away from the centre
+        const PLANE_EPSILON: f32 = 1.0e-4;
+        let mut planes: Vec<Vector4<f32>> = Vec::new();
+        if convex {
+            let centre = points.iter().fold(vec3(0.0, 0.0, 0.0), |sum, p| sum + p) / points.len() as f32;
+            let indices = prim.indices()
+                .map(|accessor| read_accessor_u32(&accessor, buffers))
+                .unwrap_or((0..points.len() as u32).collect());
+
+            for tri in indices.chunks_exact(INDEX_STRIDE) {
+                let (p1, p2, p3) = (points[tri[0] as usize], points[tri[1] as usize], points[tri[2] as usize]);
+                let normal = (p2 - p1).cross(p3 - p1);
+                if normal.magnitude2() <= f32::EPSILON {
+                    continue;
                 }
 
-                // Assuming that it's always gl::FLOAT but I might be wrong
-                let data_size_type = std::mem::size_of::<f32>();
+                let mut normal = normal.normalize();
+                if normal.dot(centre - p1) > 0.0 {
+                    normal = -normal;
+                }
+                let plane = normal.extend(normal.dot(p1));
 
-                let offset = buffer_view.offset();
-                let length_bytes = buffer_view.length();
-                let length_elements = length_bytes / data_size_type;
+                if !planes.iter().any(|other| (other - plane).magnitude2() < PLANE_EPSILON) {
+                    planes.push(plane);
+                }
+            }
+        }
 
-                let mut vertices = vec![0.0; length_elements];
-                let mut slice = &buffer[offset..offset+length_bytes];
-                slice.read_f32_into::<LittleEndian>(&mut vertices).expect("Failed!");
+        let trigger = WorldChunkTrigger::new(trigger_id, name, aabb.clone(), planes,
+            raw_extras.map(|e| e.get().to_string()));
 
-                vertices
-            })
-            .map(|points| {
-                points.chunks_exact(3)
-                .map(|v| WrappedVector3((world_transform * vec4(v[0], v[1], v[2], 1.0)).truncate()))
-                .collect()
-            });
+        if let Some((min, max)) = aabb.min_max() {
+            let (chunk_x_min, chunk_z_min) = WorldChunk::point_to_chunk_index(min);
+            let (chunk_x_max, chunk_z_max) = WorldChunk::point_to_chunk_index(max);
 
-        chunk.add_entity(WorldChunkEntity::new(entity_id, object_id, *world_transform, points, raw_extras.map(|e| e.get().to_string())));
+            for x in chunk_x_min..=chunk_x_max {
+                for z in chunk_z_min..=chunk_z_max {
+                    self.get_chunk((x, z)).add_trigger(trigger.clone());
+                }
+            }
+        }
     }
 
-    /// Build the vertices for a single mesh from a gltf::Primitive
-    fn build_mesh_vertices(attribs: &HashMap<Semantic, Vec<f32>>, world_transform: &Matrix4<f32>) -> (Vec<f32>, Aabb) {
-        let positions = attribs.get(&Semantic::Positions).expect("Need positions");
-        let normals = attribs.get(&Semantic::Normals).expect("Need normals");
-        let uvs = attribs.get(&Semantic::TexCoords(0));
-        let colors = attribs.get(&Semantic::Colors(0));
+    /// Add a light to the chunk it's in, or to the world lights if it reaches the whole world
+    fn add_light(&mut self, light: &Light, world_transform: &Matrix4<f32>) {
+        let (light_type, inner_cone_angle, outer_cone_angle) = match light.kind() {
+            Kind::Directional => (WorldLightType::Directional, None, None),
+            Kind::Point => (WorldLightType::Point, None, None),
+            Kind::Spot { inner_cone_angle, outer_cone_angle } =>
+                (WorldLightType::Spot, Some(inner_cone_angle), Some(outer_cone_angle))
+        };
+
+        let baked = self.settings.vertex_lighting.as_ref().map(|lighting| lighting.bake_lights).unwrap_or(false);
+
+        // Lights point down -z in their local space
+        let pos = world_transform.w.truncate();
+        let dir = (world_transform * vec4(0.0, 0.0, -1.0, 0.0)).truncate().normalize();
+
+        let light = WorldChunkLight::new(light_type, pos, dir, Vector3::from(light.color()), light.intensity(),
+            light.range(), inner_cone_angle, outer_cone_angle, baked);
+
+        match light.is_global() {
+            true => self.world_lights.add_light(light),
+            false => self.get_chunk(WorldChunk::point_to_chunk_index(&pos)).add_light(light)
+        }
+    }
+
+    /// Get the entity ID for an entity node, either from its override or derived from its path,
+    /// and check that no other entity already has it
+    fn allocate_entity_id(&mut self, entity_path: &str, id_override: Option<&EntityIdOverride>) -> EntityId {
+        let entity_id = match id_override {
+            Some(EntityIdOverride::Id(id)) => *id,
+            Some(EntityIdOverride::Key(key)) => Self::stable_hash(key.as_bytes()),
+            // Levels are built separately, so the level name keeps the same model in two levels
+            // from getting the same IDs
+            None => match &self.level {
+                Some(level) => Self::stable_hash(format!("{}:{}", level, entity_path).as_bytes()),
+                None => Self::stable_hash(entity_path.as_bytes()),
+            },
+        };
+
+        if let Some(other_path) = self.entity_paths.get(&entity_id) {
+            panic!("Duplicate entity ID {} for nodes {} and {}", entity_id, other_path, entity_path);
+        }
+        self.entity_paths.insert(entity_id, entity_path.to_string());
+
+        entity_id
+    }
+
+    /// Hash some bytes with 64-bit FNV-1a. DefaultHasher isn't guaranteed to give the same results
+    /// between rust releases, and these hashes end up in save games and the build cache.
+    fn stable_hash(bytes: &[u8]) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        Self::stable_hash_extend(FNV_OFFSET_BASIS, bytes)
+    }
+
+    /// Continue a stable_hash with some more bytes
+    fn stable_hash_extend(hash: u64, bytes: &[u8]) -> u64 {
+        const FNV_PRIME: u64 = 0x100000001b3;
+        bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+    }
+
+    /// Hash a model along with the buffers and images it references, so that the build cache
+    /// notices when any of them change
+    fn model_content_hash(model: &WorldModel) -> Result<u64, Box<dyn Error>> {
+        let mut hash = Self::stable_hash(&model.data);
+        let dependencies = model.dependencies()
+            .map_err(|err| format!("Failed to parse {}: {}", model.filename, err))?;
+        for dependency in dependencies {
+            let data = std::fs::read(&dependency)
+                .map_err(|err| format!("Failed to read {} referenced by {}: {}", dependency.display(), model.filename, err))?;
+            hash = Self::stable_hash_extend(hash, &data);
+        }
+        Ok(hash)
+    }
+
+    /// Build the vertices and indices for a single mesh from a gltf::Primitive's attributes,
+    /// transforming them to world space and generating normals and tangents if needed. Problems
+    /// with the mesh are logged as warnings, and None is returned if it's unusable.
+    fn build_mesh_vertices(&self, name: &str, attribs: &HashMap<Semantic, Vec<f32>>, indices: Option<Vec<u32>>,
+        world_transform: &Matrix4<f32>) -> Option<(Vec<f32>, Vec<u32>, Aabb)>
+    {
+        let positions = match attribs.get(&Semantic::Positions) {
+            Some(positions) if positions.len() % 3 == 0 => positions,
+            _ => {
+                self.log(&format!("Warning: Skipping mesh {} without valid positions", name));
+                return None;
+            }
+        };
 
         let vertex_count = positions.len() / 3;
 
-        assert!(positions.len() % 3 == 0);
-        assert!(normals.len() / 3 == vertex_count);
-        assert!(uvs.map(|uvs| uvs.len() / 2).unwrap_or(vertex_count) == vertex_count);
-        assert!(colors.map(|colors| colors.len() / 4).unwrap_or(vertex_count) == vertex_count);
+        // Get an optional attribute, ignoring it if it doesn't have a value for every vertex
+        let optional_attrib = |semantic: Semantic, size: usize| {
+            attribs.get(&semantic).filter(|values| {
+                let valid = values.len() == vertex_count * size;
+                if !valid {
+                    self.log(&format!("Warning: Ignoring {:?} for mesh {}, expected {} values but found {}",
+                        semantic, name, vertex_count * size, values.len()));
+                }
+                valid
+            })
+        };
+
+        let normals = optional_attrib(Semantic::Normals, 3);
+        let tangents = optional_attrib(Semantic::Tangents, 4);
+        let uvs = optional_attrib(Semantic::TexCoords(0), 2);
+        let colors = optional_attrib(Semantic::Colors(0), 4);
 
-        // Transform vertices to world space, and calculate bounding box
-        let mut vertices = Vec::with_capacity(vertex_count * VERTEX_STRIDE);
-        let mut aabb = Aabb::new();
+        // Non-indexed meshes are uncommon (blender's gltf exporter doesn't output them), but
+        // they're just a list of triangles
+        let indices = indices.unwrap_or_else(|| {
+            self.log(&format!("Warning: Mesh {} has no indices, treating it as a list of triangles", name));
+            (0..vertex_count as u32).collect()
+        });
+
+        // Drop any incomplete triangles or triangles with out of range indices
+        let mut valid_indices: Vec<u32> = indices.chunks_exact(INDEX_STRIDE)
+            .filter(|tri| tri.iter().all(|i| (*i as usize) < vertex_count))
+            .flatten()
+            .copied()
+            .collect();
 
-        for i in 0..vertex_count {
-            // Calculate world position
-            let local_pos = vec3(positions[i*3], positions[i*3+1], positions[i*3+2]);
-            let world_pos = (world_transform * vec4(local_pos.x, local_pos.y, local_pos.z, 1.0)).truncate();
+        if valid_indices.len() != indices.len() {
+            self.log(&format!("Warning: Dropped {} invalid indices from mesh {}", indices.len() - valid_indices.len(),
+                name));
+        }
+
+        // Normals are transformed by the inverse transpose so that non-uniform scale doesn't skew
+        // them, and mirroring transforms flip the winding order and the tangent handedness
+        let transform = Matrix3::from_cols(world_transform.x.truncate(), world_transform.y.truncate(),
+            world_transform.z.truncate());
+        let mirrored = transform.determinant() < 0.0;
+        let normal_transform = transform.invert()
+            .map(|inverse| inverse.transpose())
+            .unwrap_or_else(|| {
+                self.log(&format!("Warning: Mesh {} has a degenerate transform, its normals won't be transformed",
+                    name));
+                Matrix3::identity()
+            });
 
+        if mirrored {
+            for tri in valid_indices.chunks_exact_mut(INDEX_STRIDE) {
+                tri.swap(1, 2);
+            }
+        }
+
+        let world_positions: Vec<Vector3<f32>> = positions.chunks_exact(3)
+            .map(|p| (world_transform * vec4(p[0], p[1], p[2], 1.0)).truncate())
+            .collect();
+
+        // Get the normal for each vertex, along with the original vertex it comes from, since
+        // flat normals need separate vertices for each triangle
+        let (source_vertices, normals): (Vec<u32>, Vec<Vector3<f32>>) = match normals {
+            Some(normals) => {
+                let normals = normals.chunks_exact(3)
+                    .map(|n| normalize_or_zero(normal_transform * vec3(n[0], n[1], n[2])))
+                    .collect();
+                ((0..vertex_count as u32).collect(), normals)
+            }
+            None => {
+                let mode = self.settings.missing_normals;
+                self.log(&format!("Warning: Mesh {} has no normals, generating {} normals", name,
+                    format!("{:?}", mode).to_lowercase()));
+
+                match mode {
+                    NormalGeneration::Smooth => {
+                        ((0..vertex_count as u32).collect(), mesh_normals::smooth_normals(&world_positions, &valid_indices))
+                    }
+                    NormalGeneration::Flat => {
+                        let face_normals = mesh_normals::face_normals(&world_positions, &valid_indices);
+                        let normals = (0..valid_indices.len()).map(|i| face_normals[i / INDEX_STRIDE]).collect();
+                        let flat_indices = (0..valid_indices.len() as u32).collect();
+                        (std::mem::replace(&mut valid_indices, flat_indices), normals)
+                    }
+                }
+            }
+        };
+
+        // Build vertices in world space, and calculate bounding box
+        let mut vertices = Vec::with_capacity(source_vertices.len() * VERTEX_STRIDE);
+        let mut aabb = Aabb::new();
+
+        for (i, normal) in source_vertices.iter().map(|i| *i as usize).zip(normals.iter()) {
             // Expand mesh aabb
+            let world_pos = world_positions[i];
             aabb.expand_with_point(&world_pos);
 
-            // Get normal
-            let normal = vec3(normals[i*3], normals[i*3+1], normals[i*3+2]);
-
             // Get uv
             let uv = match uvs {
                 Some(uvs) => vec2(uvs[i*2], uvs[i*2+1]),
@@ -462,6 +1575,16 @@ impl WorldBuilder {
                 None => vec4(1.0, 1.0, 1.0, 1.0)
             };
 
+            // Get tangent, these are directions along the surface so they're transformed normally
+            let tangent = match tangents {
+                Some(tangents) => {
+                    let dir = normalize_or_zero(transform * vec3(tangents[i*4], tangents[i*4+1], tangents[i*4+2]));
+                    let handedness = if mirrored { -tangents[i*4+3] } else { tangents[i*4+3] };
+                    dir.extend(handedness)
+                }
+                None => vec4(0.0, 0.0, 0.0, 0.0)
+            };
+
             // Add to vertex buffer
             vertices.push(world_pos.x);
             vertices.push(world_pos.y);
@@ -475,17 +1598,31 @@ impl WorldBuilder {
             vertices.push(color.y);
             vertices.push(color.z);
             vertices.push(color.w);
+            vertices.push(tangent.x);
+            vertices.push(tangent.y);
+            vertices.push(tangent.z);
+            vertices.push(tangent.w);
         };
 
-        (vertices, aabb)
+        // Generate tangents from the final world space vertices
+        if tangents.is_none() && self.settings.generate_tangents {
+            if uvs.is_none() {
+                self.log(&format!("Warning: Can't generate tangents for mesh {} without uvs", name));
+            }
+            else if !mesh_normals::generate_tangents(&mut vertices, &valid_indices) {
+                self.log(&format!("Warning: Failed to generate tangents for mesh {}", name));
+            }
+        }
+
+        Some((vertices, valid_indices, aabb))
     }
 
     /// Clip a mesh to an aabb, discarding any triangles that aren't at least touching the aabb.
     /// The resulting mesh can be a little bigger than the aabb as triangles on the border are
     /// retianed to avoid any seams. If no triangles from the original mesh were in the aabb,
     /// returns None.
-    fn clip_mesh_to_aabb(vertices: &[f32], indices: &[u16], clip_min: &Vector3<f32>, clip_max: &Vector3<f32>,
-        next_mesh_index: i32, material: &WorldChunkMaterial) -> Option<WorldChunkMesh>
+    fn clip_mesh_to_aabb(vertices: &[f32], indices: &[u32], clip_min: &Vector3<f32>, clip_max: &Vector3<f32>,
+        next_mesh_index: i32, material: &Option<WorldChunkMaterial>) -> Option<WorldChunkMesh>
     {
         // Build vertex and index buffer for this chunk
         let mut chunk_mesh_aabb = Aabb::new();
@@ -494,11 +1631,11 @@ impl WorldBuilder {
 
         // A map of original mesh indices to new mesh indices, since we're
         // going to be filtering some of them out and need to remap them
-        let mut chunk_index_map: HashMap<u16, u16> = HashMap::new();
+        let mut chunk_index_map: HashMap<u32, u32> = HashMap::new();
 
         // Insert a vertex into the new mesh, returning the index of the vertex
-        let mut insert_chunk_mesh_vertex = |data: &[f32]| -> u16 {
-            let index = (chunk_mesh_vertices.len() / VERTEX_STRIDE) as u16;
+        let mut insert_chunk_mesh_vertex = |data: &[f32]| -> u32 {
+            let index = (chunk_mesh_vertices.len() / VERTEX_STRIDE) as u32;
 
             for i in 0..VERTEX_STRIDE {
                 chunk_mesh_vertices.push(data[i]);
@@ -558,7 +1695,7 @@ impl WorldBuilder {
             assert!(chunk_mesh_vertices.len() % VERTEX_STRIDE == 0);
 
             let mesh = WorldChunkMesh::new(chunk_mesh_aabb.clone(), next_mesh_index, chunk_mesh_vertices,
-                chunk_mesh_indices, Some(material.clone()));
+                chunk_mesh_indices, material.clone());
 
             Some(mesh)
         }
@@ -567,6 +1704,132 @@ impl WorldBuilder {
         }
     }
 
+    /// Clip a mesh to an aabb exactly, splitting triangles against the planes of the aabb and
+    /// interpolating their vertex attributes, so that no part of the resulting mesh is outside of
+    /// the aabb. If no triangles from the original mesh were in the aabb, returns None.
+    fn split_mesh_to_aabb(vertices: &[f32], indices: &[u32], clip_min: &Vector3<f32>, clip_max: &Vector3<f32>,
+        next_mesh_index: i32, material: &Option<WorldChunkMaterial>) -> Option<WorldChunkMesh>
+    {
+        const MIN_CLIPPED_POLYGON_AREA: f32 = 1.0e-6;
+
+        let mut chunk_mesh_aabb = Aabb::new();
+        let mut chunk_mesh_vertices = Vec::new();
+        let mut chunk_mesh_indices = Vec::new();
+
+        // A map of original mesh indices to new mesh indices, for vertices that didn't need to be
+        // split so they can still be shared between triangles
+        let mut chunk_index_map: HashMap<u32, u32> = HashMap::new();
+
+        assert!(vertices.len() % VERTEX_STRIDE == 0);
+        assert!(indices.len() % INDEX_STRIDE == 0);
+        for tri in indices.chunks_exact(INDEX_STRIDE) {
+            let mut polygon: Vec<ClipVertex> = tri.iter()
+                .map(|&i| {
+                    let offset = i as usize * VERTEX_STRIDE;
+                    let mut data = [0.0; VERTEX_STRIDE];
+                    data.copy_from_slice(&vertices[offset .. offset + VERTEX_STRIDE]);
+                    ClipVertex { original_index: Some(i), data }
+                })
+                .collect();
+
+            // Clip the triangle against each plane of the aabb in turn
+            for axis in 0..3 {
+                polygon = Self::clip_polygon_to_plane(&polygon, axis, clip_min[axis], true);
+                polygon = Self::clip_polygon_to_plane(&polygon, axis, clip_max[axis], false);
+            }
+
+            // Triangles that only touch the aabb with an edge or a vertex are clipped down to nothing
+            if polygon.len() < 3 || Self::polygon_area(&polygon) < MIN_CLIPPED_POLYGON_AREA {
+                continue;
+            }
+
+            // Insert the clipped polygon's vertices, and triangulate it as a fan, which works because
+            // clipping a triangle to an aabb always results in a convex polygon
+            let polygon_indices: Vec<u32> = polygon.iter()
+                .map(|vertex| {
+                    chunk_mesh_aabb.expand_with_point(&vec3(vertex.data[0], vertex.data[1], vertex.data[2]));
+
+                    let mut insert = || {
+                        let index = (chunk_mesh_vertices.len() / VERTEX_STRIDE) as u32;
+                        chunk_mesh_vertices.extend_from_slice(&vertex.data);
+                        index
+                    };
+
+                    match vertex.original_index {
+                        Some(i) => *chunk_index_map.entry(i).or_insert_with(insert),
+                        None => insert()
+                    }
+                })
+                .collect();
+
+            for i in 1..polygon_indices.len() - 1 {
+                chunk_mesh_indices.push(polygon_indices[0]);
+                chunk_mesh_indices.push(polygon_indices[i]);
+                chunk_mesh_indices.push(polygon_indices[i + 1]);
+            }
+        }
+
+        // Create the mesh if any triangles remain
+        if chunk_mesh_indices.len() > 0 {
+            assert!(chunk_mesh_indices.len() % INDEX_STRIDE == 0);
+            assert!(chunk_mesh_vertices.len() % VERTEX_STRIDE == 0);
+
+            let mesh = WorldChunkMesh::new(chunk_mesh_aabb.clone(), next_mesh_index, chunk_mesh_vertices,
+                chunk_mesh_indices, material.clone());
+
+            Some(mesh)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Clip a convex polygon against an axis aligned plane with the Sutherland-Hodgman algorithm,
+    /// keeping the part of the polygon above the plane if keep_above is true, or below it otherwise.
+    /// Points on the plane are only kept when keeping the part above it, so that the aabb is
+    /// half-open and polygons on a chunk border only end up in one chunk.
+    fn clip_polygon_to_plane(polygon: &[ClipVertex], axis: usize, plane: f32, keep_above: bool) -> Vec<ClipVertex> {
+        let distance = |vertex: &ClipVertex| match keep_above {
+            true => vertex.data[axis] - plane,
+            false => plane - vertex.data[axis],
+        };
+        let inside = |dist: f32| match keep_above {
+            true => dist >= 0.0,
+            false => dist > 0.0,
+        };
+
+        let mut clipped = Vec::with_capacity(polygon.len() + 1);
+        for (i, cur) in polygon.iter().enumerate() {
+            let next = &polygon[(i + 1) % polygon.len()];
+            let (cur_dist, next_dist) = (distance(cur), distance(next));
+
+            if inside(cur_dist) {
+                clipped.push(cur.clone());
+            }
+
+            // Add the intersection point if the edge crosses the plane
+            if inside(cur_dist) != inside(next_dist) {
+                let t = cur_dist / (cur_dist - next_dist);
+                clipped.push(ClipVertex::lerp(cur, next, t));
+            }
+        }
+
+        clipped
+    }
+
+    /// Get the area of a convex polygon
+    fn polygon_area(polygon: &[ClipVertex]) -> f32 {
+        let position = |vertex: &ClipVertex| vec3(vertex.data[0], vertex.data[1], vertex.data[2]);
+        let origin = position(&polygon[0]);
+
+        let doubled_area: Vector3<f32> = polygon.windows(2)
+            .skip(1)
+            .map(|edge| (position(&edge[0]) - origin).cross(position(&edge[1]) - origin))
+            .sum();
+
+        doubled_area.magnitude() * 0.5
+    }
+
     /// Check whether a triangle intersects an aabb
     /// https://gdbooks.gitbooks.io/3dcollisions/content/Chapter4/aabb-triangle.html
     fn triangle_intersects_aabb(aabb_min: &Vector3<f32>, aabb_max: &Vector3<f32>,
@@ -634,3 +1897,49 @@ impl WorldBuilder {
             .or_insert(WorldChunk::new())
     }
 }
+
+/// A vertex of a polygon being clipped, with the index of the original vertex if it's unchanged
+#[derive(Clone)]
+struct ClipVertex {
+    original_index: Option<u32>,
+    data: [f32; VERTEX_STRIDE],
+}
+
+impl ClipVertex {
+    /// Interpolate between two vertices, creating a new vertex
+    fn lerp(a: &ClipVertex, b: &ClipVertex, t: f32) -> ClipVertex {
+        let mut data = [0.0; VERTEX_STRIDE];
+        for i in 0..VERTEX_STRIDE {
+            data[i] = a.data[i] + (b.data[i] - a.data[i]) * t;
+        }
+
+        // Renormalize the normal and tangent
+        let normal = vec3(data[3], data[4], data[5]);
+        if normal.magnitude2() > 0.0 {
+            let normal = normal.normalize();
+            data[3] = normal.x;
+            data[4] = normal.y;
+            data[5] = normal.z;
+        }
+
+        let tangent = vec3(data[TANGENT_OFFSET], data[TANGENT_OFFSET + 1], data[TANGENT_OFFSET + 2]);
+        if tangent.magnitude2() > 0.0 {
+            let tangent = tangent.normalize();
+            data[TANGENT_OFFSET] = tangent.x;
+            data[TANGENT_OFFSET + 1] = tangent.y;
+            data[TANGENT_OFFSET + 2] = tangent.z;
+        }
+
+        ClipVertex { original_index: None, data }
+    }
+}
+
+/// Normalize a vector, leaving it as zero if it's zero
+fn normalize_or_zero(v: Vector3<f32>) -> Vector3<f32> {
+    if v.magnitude2() > 0.0 {
+        v.normalize()
+    }
+    else {
+        v
+    }
+}
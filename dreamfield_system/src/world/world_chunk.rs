@@ -1,30 +1,56 @@
-use cgmath::{Vector3, Vector2, Matrix4};
+use cgmath::{Vector3, Vector2, Vector4, Matrix4, InnerSpace, vec3};
 use speedy::{Readable, Writable};
+use serde::{Serialize, Deserialize};
 use super::{aabb::Aabb, wrapped_vectors::{WrappedVector4, WrappedVector3, WrappedMatrix4}};
 
 /// World chunk size
 pub const CHUNK_SIZE: f32 = 16.0;
 
-// Stride for world meshes is pos (3) + normals (3) + uv (2) + color (4)
+// Stride for world meshes is pos (3) + normals (3) + uv (2) + color (4) + tangent (4)
 // We could split these into separate buffers since this part only needs positions
-pub const VERTEX_STRIDE: usize = 3 + 3 + 2 + 4;
+pub const VERTEX_STRIDE: usize = 3 + 3 + 2 + 4 + 4;
 
 // For indices it's just 3 because they're triangles
 pub const INDEX_STRIDE: usize = 3;
 
+// Collision meshes only need positions
+pub const COLLISION_VERTEX_STRIDE: usize = 3;
+
 /// Type for chunk indexes
 pub type ChunkIndex = (i32, i32);
 
-/// Type for entity IDs
-pub type EntityId = i32;
+/// Type for entity IDs. These are derived from the model filename and node path by the world
+/// builder, so they stay the same when other nodes are added or removed.
+pub type EntityId = u64;
+
+/// Type for trigger IDs. Like entity IDs, these are derived from the model filename and node path.
+pub type TriggerId = u64;
+
+/// What should happen to a world entity after the game destroys it
+#[derive(Clone, Copy, Readable, Writable, Serialize, Deserialize, Debug, PartialEq, Default)]
+pub enum RespawnPolicy {
+    /// The entity never comes back once it's been destroyed
+    #[default]
+    Never,
+    /// The entity comes back the next time its chunk is reloaded, i.e. after every spawn radius
+    /// has left and come back into range of it
+    OnChunkReload,
+    /// The entity comes back after the given number of seconds of sim time
+    After(f64),
+}
 
 /// A single world chunk
 #[derive(Readable, Writable, Debug)]
 pub struct WorldChunk {
     aabb: Aabb,
     meshes: Vec<WorldChunkMesh>,
+    collision_meshes: Vec<WorldChunkCollisionMesh>,
     instances: Vec<WorldChunkInstance>,
     entities: Vec<WorldChunkEntity>,
+    lights: Vec<WorldChunkLight>,
+    triggers: Vec<WorldChunkTrigger>,
+    lod_distances: Vec<f32>,
+    navmesh: Option<WorldChunkNavMesh>,
 }
 
 impl WorldChunk {
@@ -33,8 +59,13 @@ impl WorldChunk {
         Self {
             aabb: Aabb::new(),
             meshes: Vec::new(),
+            collision_meshes: Vec::new(),
             instances: Vec::new(),
             entities: Vec::new(),
+            lights: Vec::new(),
+            triggers: Vec::new(),
+            lod_distances: Vec::new(),
+            navmesh: None,
         }
     }
 
@@ -48,6 +79,11 @@ impl WorldChunk {
         &self.meshes
     }
 
+    /// Get the chunk's collision meshes
+    pub fn collision_meshes(&self) -> &[WorldChunkCollisionMesh] {
+        &self.collision_meshes
+    }
+
     /// Get the chunk's instances
     pub fn instances(&self) -> &[WorldChunkInstance] {
         &self.instances
@@ -58,16 +94,41 @@ impl WorldChunk {
         &self.entities
     }
 
+    /// Get the chunk's lights
+    pub fn lights(&self) -> &[WorldChunkLight] {
+        &self.lights
+    }
+
+    /// Get the chunk's trigger volumes
+    pub fn triggers(&self) -> &[WorldChunkTrigger] {
+        &self.triggers
+    }
+
+    /// Get the chunk's navigation mesh, if it has any walkable surfaces
+    pub fn navmesh(&self) -> Option<&WorldChunkNavMesh> {
+        self.navmesh.as_ref()
+    }
+
     /// Add a mesh to a world chunk
     pub fn add_mesh(&mut self, mesh: WorldChunkMesh) {
         self.aabb.expand_with_aabb(mesh.aabb());
         self.meshes.push(mesh);
     }
 
+    /// Add a collision mesh to a world chunk
+    pub fn add_collision_mesh(&mut self, mesh: WorldChunkCollisionMesh) {
+        self.aabb.expand_with_aabb(mesh.aabb());
+        self.collision_meshes.push(mesh);
+    }
+
     /// Add an instance to a world chunk
     pub fn add_instances(&mut self, instance: WorldChunkInstance) {
-        for WrappedVector3(point) in instance.points().iter() {
-            self.aabb.expand_with_point(point)
+        for WrappedMatrix4(transform) in instance.transforms().iter() {
+            self.aabb.expand_with_point(&transform.w.truncate());
+            if let Some((center, radius)) = instance.collision_spheroid(transform) {
+                self.aabb.expand_with_point(&(center - radius));
+                self.aabb.expand_with_point(&(center + radius));
+            }
         }
         self.instances.push(instance);
     }
@@ -77,6 +138,59 @@ impl WorldChunk {
         self.entities.push(entity);
     }
 
+    /// Add a light to a world chunk
+    pub fn add_light(&mut self, light: WorldChunkLight) {
+        self.lights.push(light);
+    }
+
+    /// Add a trigger volume to a world chunk
+    pub fn add_trigger(&mut self, trigger: WorldChunkTrigger) {
+        self.triggers.push(trigger);
+    }
+
+    /// Get the distances from the camera at which each of the chunk's lower levels of detail
+    /// (starting with lod 1) should be used
+    pub fn lod_distances(&self) -> &[f32] {
+        &self.lod_distances
+    }
+
+    /// Set the distances at which each of the chunk's lower levels of detail should be used
+    pub fn set_lod_distances(&mut self, lod_distances: Vec<f32>) {
+        self.lod_distances = lod_distances;
+    }
+
+    /// Set the chunk's navigation mesh
+    pub fn set_navmesh(&mut self, navmesh: Option<WorldChunkNavMesh>) {
+        self.navmesh = navmesh;
+    }
+
+    /// Get the level of detail to use for this chunk at a given distance from the camera
+    pub fn lod_for_distance(&self, distance: f32) -> usize {
+        self.lod_distances.iter().take_while(|lod_distance| distance >= **lod_distance).count()
+    }
+
+    /// Remove all meshes from a world chunk, returning them
+    pub fn take_meshes(&mut self) -> Vec<WorldChunkMesh> {
+        std::mem::take(&mut self.meshes)
+    }
+
+    /// Append everything in another world chunk to this one
+    pub fn append(&mut self, other: WorldChunk) {
+        self.aabb.expand_with_aabb(&other.aabb);
+        self.meshes.extend(other.meshes);
+        self.collision_meshes.extend(other.collision_meshes);
+        self.instances.extend(other.instances);
+        self.entities.extend(other.entities);
+        self.lights.extend(other.lights);
+        self.triggers.extend(other.triggers);
+    }
+
+    /// Get whether this chunk has nothing in it
+    pub fn is_empty(&self) -> bool {
+        self.meshes.is_empty() && self.collision_meshes.is_empty() && self.instances.is_empty() &&
+            self.entities.is_empty() && self.lights.is_empty() && self.triggers.is_empty()
+    }
+
     /// Get the chunk filename for a given chunk index
     pub fn filename((x, z): ChunkIndex) -> String {
         format!("world_{}_{}.chunk", x, z)
@@ -115,13 +229,14 @@ pub struct WorldChunkMesh {
     aabb: Aabb,
     index: i32,
     vertices: Vec<f32>,
-    indices: Vec<u16>,
+    indices: Vec<u32>,
+    lods: Vec<Vec<u32>>,
     material: Option<WorldChunkMaterial>
 }
 
 impl WorldChunkMesh {
     /// Create a new mesh
-    pub fn new(aabb: Aabb, index: i32, vertices: Vec<f32>, indices: Vec<u16>, material: Option<WorldChunkMaterial>)
+    pub fn new(aabb: Aabb, index: i32, vertices: Vec<f32>, indices: Vec<u32>, material: Option<WorldChunkMaterial>)
         -> Self
     {
         Self {
@@ -129,6 +244,7 @@ impl WorldChunkMesh {
             index,
             vertices,
             indices,
+            lods: Vec::new(),
             material
         }
     }
@@ -148,29 +264,114 @@ impl WorldChunkMesh {
         &self.vertices
     }
 
+    /// Get the vertices of this mesh for modifying
+    pub fn vertices_mut(&mut self) -> &mut [f32] {
+        &mut self.vertices
+    }
+
     /// Get the indices of this mesh
-    pub fn indices(&self) -> &[u16] {
+    pub fn indices(&self) -> &[u32] {
         &self.indices
     }
 
+    /// Get the index lists for the lower levels of detail of this mesh, starting with lod 1. They
+    /// share the mesh's vertices.
+    pub fn lods(&self) -> &[Vec<u32>] {
+        &self.lods
+    }
+
+    /// Set the index lists for the lower levels of detail of this mesh
+    pub fn set_lods(&mut self, lods: Vec<Vec<u32>>) {
+        self.lods = lods;
+    }
+
+    /// Get the indices for a level of detail, falling back to the lowest one the mesh has
+    pub fn lod_indices(&self, lod: usize) -> &[u32] {
+        match lod {
+            0 => &self.indices,
+            _ => self.lods.get(lod - 1).or(self.lods.last()).unwrap_or(&self.indices)
+        }
+    }
+
     /// Get the material of this mesh
     pub fn material(&self) -> &Option<WorldChunkMaterial> {
         &self.material
     }
 }
 
-/// A material within a world chunk
+/// A collision mesh within a world chunk, which only has positions
 #[derive(Clone, Readable, Writable, Debug)]
+pub struct WorldChunkCollisionMesh {
+    aabb: Aabb,
+    vertices: Vec<f32>,
+    indices: Vec<u32>,
+}
+
+impl WorldChunkCollisionMesh {
+    /// Create a new collision mesh
+    pub fn new(aabb: Aabb, vertices: Vec<f32>, indices: Vec<u32>) -> Self {
+        Self {
+            aabb,
+            vertices,
+            indices
+        }
+    }
+
+    /// Get the aabb for this mesh
+    pub fn aabb(&self) -> &Aabb {
+        &self.aabb
+    }
+
+    /// Get the vertex positions of this mesh
+    pub fn vertices(&self) -> &[f32] {
+        &self.vertices
+    }
+
+    /// Get the indices of this mesh
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+}
+
+/// How a world material's alpha is used (the glTF alpha mode)
+#[derive(Clone, Copy, Readable, Writable, Debug, PartialEq)]
+pub enum WorldAlphaMode {
+    /// Alpha is ignored
+    Opaque,
+    /// Fragments with alpha below the material's alpha cutoff are discarded
+    Mask,
+    /// Alpha blended, drawn after opaque meshes
+    Blend,
+}
+
+/// A material within a world chunk
+#[derive(Clone, Readable, Writable, Debug, PartialEq)]
 pub struct WorldChunkMaterial {
     base_color: WrappedVector4,
-    base_color_tex: Option<i32>
+    base_color_tex: Option<i32>,
+    alpha_mode: WorldAlphaMode,
+    alpha_cutoff: f32,
+    double_sided: bool,
+    emissive: WrappedVector3,
+    emissive_tex: Option<i32>,
+    unlit: bool,
+    vertex_colors: bool
 }
 
 impl WorldChunkMaterial {
-    pub fn new(base_color: WrappedVector4, base_color_tex: Option<i32>) -> Self {
+    pub fn new(base_color: WrappedVector4, base_color_tex: Option<i32>, alpha_mode: WorldAlphaMode, alpha_cutoff: f32,
+        double_sided: bool, emissive: WrappedVector3, emissive_tex: Option<i32>, unlit: bool, vertex_colors: bool) -> Self
+    {
         Self {
             base_color,
-            base_color_tex
+            base_color_tex,
+            alpha_mode,
+            alpha_cutoff,
+            double_sided,
+            emissive,
+            emissive_tex,
+            unlit,
+            vertex_colors
         }
     }
 
@@ -181,20 +382,61 @@ impl WorldChunkMaterial {
     pub fn base_color_tex(&self) -> &Option<i32> {
         &self.base_color_tex
     }
+
+    pub fn alpha_mode(&self) -> WorldAlphaMode {
+        self.alpha_mode
+    }
+
+    pub fn alpha_cutoff(&self) -> f32 {
+        self.alpha_cutoff
+    }
+
+    pub fn double_sided(&self) -> bool {
+        self.double_sided
+    }
+
+    pub fn emissive(&self) -> &WrappedVector3 {
+        &self.emissive
+    }
+
+    pub fn emissive_tex(&self) -> &Option<i32> {
+        &self.emissive_tex
+    }
+
+    /// Whether the material ignores lighting (KHR_materials_unlit)
+    pub fn unlit(&self) -> bool {
+        self.unlit
+    }
+
+    /// Whether vertex colors multiply the base color
+    pub fn vertex_colors(&self) -> bool {
+        self.vertex_colors
+    }
 }
 
-/// A mesh instanced in the world at various points
+/// A mesh instanced in the world with various transforms
 #[derive(Clone, Readable, Writable, Debug)]
 pub struct WorldChunkInstance {
     mesh_name: String,
-    points: Vec<WrappedVector3>
+    transforms: Vec<WrappedMatrix4>,
+    collision: Option<WorldChunkInstanceCollision>
+}
+
+/// The collision shape of an instanced mesh, a bounding spheroid in the mesh's own space
+#[derive(Clone, Readable, Writable, Debug)]
+pub struct WorldChunkInstanceCollision {
+    offset: WrappedVector3,
+    radius: WrappedVector3
 }
 
 impl WorldChunkInstance {
-    pub fn new(mesh_name: String, points: Vec<WrappedVector3>) -> Self {
+    pub fn new(mesh_name: String, transforms: Vec<WrappedMatrix4>, collision: Option<WorldChunkInstanceCollision>)
+        -> Self
+    {
         Self {
             mesh_name,
-            points
+            transforms,
+            collision
         }
     }
 
@@ -202,8 +444,47 @@ impl WorldChunkInstance {
         &self.mesh_name
     }
 
-    pub fn points(&self) -> &Vec<WrappedVector3> {
-        &self.points
+    /// Get the world transform of each instance
+    pub fn transforms(&self) -> &Vec<WrappedMatrix4> {
+        &self.transforms
+    }
+
+    /// Get the position of each instance
+    pub fn points(&self) -> impl Iterator<Item = Vector3<f32>> + '_ {
+        self.transforms.iter().map(|transform| transform.as_mat().w.truncate())
+    }
+
+    pub fn collision(&self) -> &Option<WorldChunkInstanceCollision> {
+        &self.collision
+    }
+
+    /// Get the world space center and radius of an instance's collision spheroid, if it has one.
+    /// The spheroid is scaled with the instance but stays axis aligned.
+    pub fn collision_spheroid(&self, transform: &Matrix4<f32>) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        self.collision.as_ref().map(|collision| {
+            let center = (transform * collision.offset.as_vec().extend(1.0)).truncate();
+            let scale = vec3(transform.x.truncate().magnitude(), transform.y.truncate().magnitude(),
+                transform.z.truncate().magnitude());
+            let radius = collision.radius.as_vec();
+            (center, vec3(radius.x * scale.x, radius.y * scale.y, radius.z * scale.z))
+        })
+    }
+}
+
+impl WorldChunkInstanceCollision {
+    pub fn new(offset: Vector3<f32>, radius: Vector3<f32>) -> Self {
+        Self {
+            offset: WrappedVector3(offset),
+            radius: WrappedVector3(radius)
+        }
+    }
+
+    pub fn offset(&self) -> &Vector3<f32> {
+        self.offset.as_vec()
+    }
+
+    pub fn radius(&self) -> &Vector3<f32> {
+        self.radius.as_vec()
     }
 }
 
@@ -220,11 +501,13 @@ pub struct WorldChunkEntity {
     mesh: Option<Vec<WrappedVector3>>,
     /// The gltf extras for this entity
     extras: Option<String>,
+    /// What to do when the game destroys this entity
+    respawn_policy: RespawnPolicy,
 }
 
 impl WorldChunkEntity {
     pub fn new(entity_id: EntityId, object_id: String, world_transform: Matrix4<f32>,
-        mesh: Option<Vec<WrappedVector3>>, extras: Option<String>) -> Self
+        mesh: Option<Vec<WrappedVector3>>, extras: Option<String>, respawn_policy: RespawnPolicy) -> Self
     {
         Self {
             entity_id,
@@ -232,6 +515,7 @@ impl WorldChunkEntity {
             world_transform: WrappedMatrix4(world_transform),
             mesh,
             extras,
+            respawn_policy,
         }
     }
 
@@ -254,4 +538,267 @@ impl WorldChunkEntity {
     pub fn extras(&self) -> Option<&String> {
         self.extras.as_ref()
     }
+
+    pub fn respawn_policy(&self) -> RespawnPolicy {
+        self.respawn_policy
+    }
+}
+
+/// The type of a world light
+#[derive(Clone, Copy, Readable, Writable, Debug, PartialEq)]
+pub enum WorldLightType {
+    Point,
+    Directional,
+    Spot,
+}
+
+/// A light within a world chunk (KHR_lights_punctual)
+#[derive(Clone, Readable, Writable, Debug)]
+pub struct WorldChunkLight {
+    light_type: WorldLightType,
+    pos: WrappedVector3,
+    dir: WrappedVector3,
+    color: WrappedVector3,
+    intensity: f32,
+    range: Option<f32>,
+    inner_cone_angle: Option<f32>,
+    outer_cone_angle: Option<f32>,
+    /// Whether the light is baked into the world's vertex colors
+    baked: bool,
+}
+
+impl WorldChunkLight {
+    pub fn new(light_type: WorldLightType, pos: Vector3<f32>, dir: Vector3<f32>, color: Vector3<f32>, intensity: f32,
+        range: Option<f32>, inner_cone_angle: Option<f32>, outer_cone_angle: Option<f32>, baked: bool) -> Self
+    {
+        Self {
+            light_type,
+            pos: WrappedVector3(pos),
+            dir: WrappedVector3(dir),
+            color: WrappedVector3(color),
+            intensity,
+            range,
+            inner_cone_angle,
+            outer_cone_angle,
+            baked,
+        }
+    }
+
+    pub fn light_type(&self) -> WorldLightType {
+        self.light_type
+    }
+
+    /// Get the world position of the light
+    pub fn pos(&self) -> &Vector3<f32> {
+        self.pos.as_vec()
+    }
+
+    /// Get the world direction of the light, for directional and spot lights
+    pub fn dir(&self) -> &Vector3<f32> {
+        self.dir.as_vec()
+    }
+
+    pub fn color(&self) -> &Vector3<f32> {
+        self.color.as_vec()
+    }
+
+    pub fn intensity(&self) -> f32 {
+        self.intensity
+    }
+
+    /// Get the range of the light, or None if it has infinite range
+    pub fn range(&self) -> Option<f32> {
+        self.range
+    }
+
+    pub fn inner_cone_angle(&self) -> Option<f32> {
+        self.inner_cone_angle
+    }
+
+    pub fn outer_cone_angle(&self) -> Option<f32> {
+        self.outer_cone_angle
+    }
+
+    /// Get whether the light is baked into the world's vertex colors, so shouldn't be used at runtime
+    pub fn baked(&self) -> bool {
+        self.baked
+    }
+
+    /// Get whether the light reaches the whole world, because it's directional or has no range.
+    /// These aren't bound to a chunk, they're stored with the world's lights instead.
+    pub fn is_global(&self) -> bool {
+        self.light_type == WorldLightType::Directional || self.range.is_none()
+    }
+}
+
+/// The lights that reach the whole world, like the sun. Unlike chunk lights these are always loaded.
+#[derive(Clone, Readable, Writable, Debug, Default)]
+pub struct WorldLights {
+    lights: Vec<WorldChunkLight>,
+}
+
+impl WorldLights {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the lights
+    pub fn lights(&self) -> &[WorldChunkLight] {
+        &self.lights
+    }
+
+    /// Add a light
+    pub fn add_light(&mut self, light: WorldChunkLight) {
+        self.lights.push(light);
+    }
+
+    /// Append the lights from another WorldLights
+    pub fn append(&mut self, other: WorldLights) {
+        self.lights.extend(other.lights);
+    }
+
+    /// Get whether there are no lights
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    /// Get the filename of the world lights within a level's directory
+    pub fn filename() -> &'static str {
+        "world.lights"
+    }
+}
+
+/// A trigger volume, which sends events when colliders enter or leave it. Triggers that overlap
+/// several chunks are added to each of them.
+#[derive(Clone, Readable, Writable, Debug)]
+pub struct WorldChunkTrigger {
+    trigger_id: TriggerId,
+    name: String,
+    aabb: Aabb,
+    /// The planes of the convex volume as (normal, distance), with points inside the volume being
+    /// behind every plane. If this is empty the volume is just the aabb.
+    planes: Vec<WrappedVector4>,
+    /// The gltf extras for this trigger
+    extras: Option<String>,
+}
+
+impl WorldChunkTrigger {
+    pub fn new(trigger_id: TriggerId, name: String, aabb: Aabb, planes: Vec<Vector4<f32>>, extras: Option<String>)
+        -> Self
+    {
+        Self {
+            trigger_id,
+            name,
+            aabb,
+            planes: planes.into_iter().map(WrappedVector4).collect(),
+            extras,
+        }
+    }
+
+    pub fn trigger_id(&self) -> TriggerId {
+        self.trigger_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn aabb(&self) -> &Aabb {
+        &self.aabb
+    }
+
+    pub fn extras(&self) -> Option<&String> {
+        self.extras.as_ref()
+    }
+
+    /// Check whether a point is inside the trigger volume
+    pub fn contains_point(&self, p: &Vector3<f32>) -> bool {
+        self.aabb.contains_point(p) && self.planes.iter().all(|plane| {
+            let plane = plane.as_vec();
+            plane.truncate().dot(*p) <= plane.w
+        })
+    }
+}
+
+/// The directions of the links between navigation mesh cells, cardinal directions first
+pub const NAV_DIRECTIONS: [(i32, i32); 8] = [(1, 0), (0, 1), (-1, 0), (0, -1), (1, 1), (-1, 1), (-1, -1), (1, -1)];
+
+/// A chunk's navigation mesh. The walkable surfaces are sampled on a grid aligned to the world
+/// origin, so each cell is a square of walkable surface at a height, linked to the cells around it
+/// that an agent can walk to. There can be several cells in the same column, for overlapping floors.
+#[derive(Clone, Readable, Writable, Debug)]
+pub struct WorldChunkNavMesh {
+    cell_size: f32,
+    step_height: f32,
+    cells: Vec<WorldChunkNavCell>,
+}
+
+impl WorldChunkNavMesh {
+    pub fn new(cell_size: f32, step_height: f32, cells: Vec<WorldChunkNavCell>) -> Self {
+        Self {
+            cell_size,
+            step_height,
+            cells,
+        }
+    }
+
+    /// Get the size of each cell
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    /// Get the maximum height difference between linked cells
+    pub fn step_height(&self) -> f32 {
+        self.step_height
+    }
+
+    pub fn cells(&self) -> &[WorldChunkNavCell] {
+        &self.cells
+    }
+}
+
+/// A walkable cell of a navigation mesh
+#[derive(Clone, Readable, Writable, Debug)]
+pub struct WorldChunkNavCell {
+    x: i32,
+    z: i32,
+    height: f32,
+    /// A bit for each of NAV_DIRECTIONS, set if the cell is linked to the cell in that direction
+    links: u8,
+}
+
+impl WorldChunkNavCell {
+    pub fn new(x: i32, z: i32, height: f32, links: u8) -> Self {
+        Self {
+            x,
+            z,
+            height,
+            links,
+        }
+    }
+
+    /// Get the cell's column, in cells from the world origin
+    pub fn column(&self) -> (i32, i32) {
+        (self.x, self.z)
+    }
+
+    pub fn height(&self) -> f32 {
+        self.height
+    }
+
+    /// Get whether the cell is linked to the cell in the given direction
+    pub fn has_link(&self, direction: usize) -> bool {
+        self.links & (1 << direction) != 0
+    }
+
+    /// Get the column of the neighbouring cell in the given direction
+    pub fn neighbour_column(&self, direction: usize) -> (i32, i32) {
+        let (dx, dz) = NAV_DIRECTIONS[direction];
+        (self.x + dx, self.z + dz)
+    }
+
+    /// Get the center of the cell's walkable surface
+    pub fn center(&self, cell_size: f32) -> Vector3<f32> {
+        vec3((self.x as f32 + 0.5) * cell_size, self.height, (self.z as f32 + 0.5) * cell_size)
+    }
 }
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::{world::{world_chunk::{ChunkIndex, VERTEX_STRIDE, INDEX_STRIDE, WorldChunk}, WorldChunkManager, aabb::Aabb}, intersection::Shape};
+use crate::{world::{world_chunk::{ChunkIndex, COLLISION_VERTEX_STRIDE, INDEX_STRIDE, WorldChunk}, WorldChunkManager, aabb::Aabb}, intersection::Shape};
 use bevy_ecs::prelude::Entity;
 use cgmath::{Vector3, vec3, ElementWise, InnerSpace};
 
@@ -50,6 +50,11 @@ impl Default for WorldCollision {
 }
 
 impl WorldCollision {
+    /// Discard the cached collision meshes, e.g. when the level changes
+    pub fn clear(&mut self) {
+        self.chunk_meshes.clear();
+    }
+
     /// Sweep a sphere out from start to end, returning an intersection result along with a time of impact
     pub fn sweep_sphere(&mut self, world: &mut WorldChunkManager, start: Vector3<f32>, velocity: Vector3<f32>,
         radius: Vector3<f32>, ignore_entity: Option<Entity>) -> Option<SpherecastResult>
@@ -150,11 +155,14 @@ impl WorldCollision {
 
                     // Intersect instances in the chunk
                     if let Some(chunk) = world.get_or_load_chunk((x, z)) {
-                        let shape = Shape::BoundingSpheroid(vec3(0.0, 1.0, 0.0), vec3(1.0, 2.0, 1.0));
                         for instance in chunk.instances().iter() {
-                            for point in instance.points().iter() {
-                                let result = Self::sweep_unit_sphere_entity(start, velocity, cbm, point.as_vec(),
-                                    &shape);
+                            for transform in instance.transforms().iter() {
+                                let (center, radius) = match instance.collision_spheroid(transform.as_mat()) {
+                                    Some(spheroid) => spheroid,
+                                    None => continue
+                                };
+                                let shape = Shape::BoundingSpheroid(vec3(0.0, 0.0, 0.0), radius);
+                                let result = Self::sweep_unit_sphere_entity(start, velocity, cbm, &center, &shape);
                                 if let Some((toi, _, _)) = result {
                                     if let Some((old_toi, _, _)) = closest_intersection {
                                         if toi < old_toi {
@@ -237,18 +245,19 @@ impl WorldCollision {
         world.get_or_load_chunk(chunk_index)
             .as_ref()
             .map(|chunk| {
-                log::info!("Loading {} chunk meshes for chunk {}, {}", chunk.meshes().len(), chunk_index.0, chunk_index.1);
+                log::info!("Loading {} chunk collision meshes for chunk {}, {}", chunk.collision_meshes().len(),
+                    chunk_index.0, chunk_index.1);
 
-                let meshes = chunk.meshes().iter().map(|mesh| {
+                let meshes = chunk.collision_meshes().iter().map(|mesh| {
                     let vertices = mesh.vertices();
 
                     let mut triangles = Vec::with_capacity(mesh.indices().len() / INDEX_STRIDE);
 
                     assert!(mesh.indices().len() % INDEX_STRIDE == 0);
                     for i in mesh.indices().chunks(INDEX_STRIDE) {
-                        let i0 = i[0] as usize * VERTEX_STRIDE;
-                        let i1 = i[1] as usize * VERTEX_STRIDE;
-                        let i2 = i[2] as usize * VERTEX_STRIDE;
+                        let i0 = i[0] as usize * COLLISION_VERTEX_STRIDE;
+                        let i1 = i[1] as usize * COLLISION_VERTEX_STRIDE;
+                        let i2 = i[2] as usize * COLLISION_VERTEX_STRIDE;
 
                         let v1 = &vertices[i0..i0+3];
                         let v2 = &vertices[i1..i1+3];
@@ -261,7 +270,6 @@ impl WorldCollision {
                         ));
                     }
 
-                    // TODO: we could make the builder generate collision specific meshes
                     (mesh.aabb().clone(), triangles)
                 }).collect();
 
@@ -0,0 +1,287 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use cgmath::{Vector3, vec3, InnerSpace};
+
+use crate::world::{WorldChunkManager, world_chunk::{ChunkIndex, WorldChunk, WorldChunkNavCell, NAV_DIRECTIONS}};
+
+/// How far the start and end of a path can be from the navmesh
+const MAX_PATH_SNAP_DISTANCE: f32 = 2.0;
+
+/// The most cells to visit when searching for a path before giving up
+const MAX_PATH_SEARCH_NODES: usize = 20000;
+
+/// A cell in a chunk's navmesh
+type NavNode = (ChunkIndex, usize);
+
+/// The navmesh for a chunk, with its cells indexed by column
+struct ChunkNavMesh {
+    cell_size: f32,
+    step_height: f32,
+    cells: Vec<WorldChunkNavCell>,
+    columns: HashMap<(i32, i32), Vec<usize>>,
+}
+
+/// The world navigation service, for pathfinding over the navmeshes baked into the world chunks
+#[derive(Default)]
+pub struct WorldNavigation {
+    chunk_navmeshes: HashMap<ChunkIndex, Option<ChunkNavMesh>>,
+}
+
+impl WorldNavigation {
+    /// Discard the cached navmeshes, e.g. when the level changes
+    pub fn clear(&mut self) {
+        self.chunk_navmeshes.clear();
+    }
+
+    /// Find the closest point on the navmesh to a point, within max_distance
+    pub fn closest_point(&mut self, world: &mut WorldChunkManager, p: Vector3<f32>, max_distance: f32)
+        -> Option<Vector3<f32>>
+    {
+        self.closest_node(world, p, max_distance).map(|(_, point)| point)
+    }
+
+    /// Find a path between two points over the navmesh, which can cross chunk borders. The start
+    /// and end points are moved to the closest point on the navmesh. Returns the points along the
+    /// path including the start and end, or None if there's no path.
+    pub fn find_path(&mut self, world: &mut WorldChunkManager, start: Vector3<f32>, end: Vector3<f32>)
+        -> Option<Vec<Vector3<f32>>>
+    {
+        let (start_node, start_point) = self.closest_node(world, start, MAX_PATH_SNAP_DISTANCE)?;
+        let (end_node, end_point) = self.closest_node(world, end, MAX_PATH_SNAP_DISTANCE)?;
+
+        let nodes = self.search(world, start_node, end_node)?;
+
+        // Skip any nodes that can be walked past in a straight line
+        let mut path = vec![start_point];
+        let mut anchor = 0;
+        while anchor < nodes.len() - 1 {
+            let mut next = anchor + 1;
+            while next + 1 < nodes.len() && self.has_line_of_sight(world, nodes[anchor], nodes[next + 1]) {
+                next += 1;
+            }
+
+            path.push(self.node_center(nodes[next]));
+            anchor = next;
+        }
+
+        if path.len() > 1 {
+            path.pop();
+        }
+        path.push(end_point);
+
+        Some(path)
+    }
+
+    /// A* search between two nodes, returning the nodes along the path
+    fn search(&mut self, world: &mut WorldChunkManager, start: NavNode, end: NavNode) -> Option<Vec<NavNode>> {
+        let end_center = self.node_center(end);
+
+        let mut open = BinaryHeap::new();
+        let mut came_from: HashMap<NavNode, NavNode> = HashMap::new();
+        let mut costs: HashMap<NavNode, f32> = HashMap::new();
+
+        open.push(OpenNode { estimate: (end_center - self.node_center(start)).magnitude(), node: start });
+        costs.insert(start, 0.0);
+
+        while let Some(OpenNode { node, .. }) = open.pop() {
+            if node == end {
+                let mut path = vec![end];
+                while let Some(previous) = came_from.get(path.last().unwrap()) {
+                    path.push(*previous);
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            if costs.len() > MAX_PATH_SEARCH_NODES {
+                log::warn!("find_path: Gave up after searching {} cells", costs.len());
+                return None;
+            }
+
+            let center = self.node_center(node);
+            let cost = costs[&node];
+
+            for neighbour in self.neighbours(world, node).into_iter().flatten() {
+                let neighbour_center = self.node_center(neighbour);
+                let neighbour_cost = cost + (neighbour_center - center).magnitude();
+
+                if costs.get(&neighbour).map(|existing| neighbour_cost < *existing).unwrap_or(true) {
+                    costs.insert(neighbour, neighbour_cost);
+                    came_from.insert(neighbour, node);
+                    open.push(OpenNode {
+                        estimate: neighbour_cost + (end_center - neighbour_center).magnitude(),
+                        node: neighbour
+                    });
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Find the closest node to a point, and the closest point on it
+    fn closest_node(&mut self, world: &mut WorldChunkManager, p: Vector3<f32>, max_distance: f32)
+        -> Option<(NavNode, Vector3<f32>)>
+    {
+        let (chunk_min_x, chunk_min_z) = WorldChunk::point_to_chunk_index(&(p - vec3(max_distance, 0.0, max_distance)));
+        let (chunk_max_x, chunk_max_z) = WorldChunk::point_to_chunk_index(&(p + vec3(max_distance, 0.0, max_distance)));
+
+        let mut closest: Option<(f32, NavNode, Vector3<f32>)> = None;
+
+        for x in chunk_min_x..=chunk_max_x {
+            for z in chunk_min_z..=chunk_max_z {
+                if let Some(navmesh) = self.get_chunk_navmesh(world, (x, z)) {
+                    for (i, cell) in navmesh.cells.iter().enumerate() {
+                        let (column_x, column_z) = cell.column();
+                        let min_x = column_x as f32 * navmesh.cell_size;
+                        let min_z = column_z as f32 * navmesh.cell_size;
+
+                        let point = vec3(
+                            p.x.clamp(min_x, min_x + navmesh.cell_size),
+                            cell.height(),
+                            p.z.clamp(min_z, min_z + navmesh.cell_size));
+                        let distance = (point - p).magnitude();
+
+                        if distance <= max_distance && closest.map(|(d, _, _)| distance < d).unwrap_or(true) {
+                            closest = Some((distance, ((x, z), i), point));
+                        }
+                    }
+                }
+            }
+        }
+
+        closest.map(|(_, node, point)| (node, point))
+    }
+
+    /// Get the node linked to a node in each direction
+    fn neighbours(&mut self, world: &mut WorldChunkManager, node: NavNode) -> [Option<NavNode>; 8] {
+        let mut neighbours = [None; 8];
+        for (direction, neighbour) in neighbours.iter_mut().enumerate() {
+            *neighbour = self.neighbour(world, node, direction);
+        }
+        neighbours
+    }
+
+    /// Get the node linked to a node in a direction. Links have to go both ways, since links out of
+    /// a chunk are only checked against the geometry in that chunk.
+    fn neighbour(&mut self, world: &mut WorldChunkManager, (chunk_index, i): NavNode, direction: usize)
+        -> Option<NavNode>
+    {
+        let navmesh = self.chunk_navmeshes[&chunk_index].as_ref().unwrap();
+        let cell = &navmesh.cells[i];
+        if !cell.has_link(direction) {
+            return None;
+        }
+
+        let height = cell.height();
+        let column = cell.neighbour_column(direction);
+        let column_center = vec3((column.0 as f32 + 0.5) * navmesh.cell_size, height,
+            (column.1 as f32 + 0.5) * navmesh.cell_size);
+        let neighbour_chunk_index = WorldChunk::point_to_chunk_index(&column_center);
+        let opposite = if direction < 4 { (direction + 2) % 4 } else { 4 + (direction - 2) % 4 };
+
+        let neighbour_navmesh = self.get_chunk_navmesh(world, neighbour_chunk_index).as_ref()?;
+        neighbour_navmesh.columns.get(&column)?
+            .iter()
+            .find(|n| {
+                let neighbour = &neighbour_navmesh.cells[**n];
+                (neighbour.height() - height).abs() <= neighbour_navmesh.step_height && neighbour.has_link(opposite)
+            })
+            .map(|n| (neighbour_chunk_index, *n))
+    }
+
+    /// Check whether an agent can walk in a straight line between two nodes without leaving the
+    /// navmesh
+    fn has_line_of_sight(&mut self, world: &mut WorldChunkManager, from: NavNode, to: NavNode) -> bool {
+        let from_center = self.node_center(from);
+        let to_center = self.node_center(to);
+        let cell_size = self.chunk_navmeshes[&from.0].as_ref().unwrap().cell_size;
+
+        // Step along the line in small enough steps that we can't skip over a cell
+        let steps = ((to_center - from_center).magnitude() / (cell_size * 0.25)).ceil() as usize;
+        let mut current = from;
+
+        for step in 1..=steps {
+            let p = from_center + (to_center - from_center) * (step as f32 / steps as f32);
+            let column = ((p.x / cell_size).floor() as i32, (p.z / cell_size).floor() as i32);
+
+            let current_column = self.node_cell(current).column();
+            if column == current_column {
+                continue;
+            }
+
+            let delta = (column.0 - current_column.0, column.1 - current_column.1);
+            let next = NAV_DIRECTIONS.iter()
+                .position(|direction| *direction == delta)
+                .and_then(|direction| self.neighbour(world, current, direction));
+
+            match next {
+                Some(next) => current = next,
+                None => return false
+            }
+        }
+
+        current == to
+    }
+
+    /// Get the cell for a node
+    fn node_cell(&self, (chunk_index, i): NavNode) -> &WorldChunkNavCell {
+        &self.chunk_navmeshes[&chunk_index].as_ref().unwrap().cells[i]
+    }
+
+    /// Get the center of a node's cell
+    fn node_center(&self, (chunk_index, i): NavNode) -> Vector3<f32> {
+        let navmesh = self.chunk_navmeshes[&chunk_index].as_ref().unwrap();
+        navmesh.cells[i].center(navmesh.cell_size)
+    }
+
+    /// Get the navmesh for a chunk, loading it if necessary from the world chunk manager
+    fn get_chunk_navmesh(&mut self, world: &mut WorldChunkManager, chunk_index: ChunkIndex) -> &Option<ChunkNavMesh> {
+        self.chunk_navmeshes
+            .entry(chunk_index)
+            .or_insert_with(|| {
+                world.get_or_load_chunk(chunk_index)
+                    .as_ref()
+                    .and_then(|chunk| chunk.navmesh())
+                    .map(|navmesh| {
+                        let mut columns: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+                        for (i, cell) in navmesh.cells().iter().enumerate() {
+                            columns.entry(cell.column()).or_default().push(i);
+                        }
+
+                        ChunkNavMesh {
+                            cell_size: navmesh.cell_size(),
+                            step_height: navmesh.step_height(),
+                            cells: navmesh.cells().to_vec(),
+                            columns,
+                        }
+                    })
+            })
+    }
+}
+
+/// A node on the A* open list, ordered so that the lowest estimate comes out of the heap first
+struct OpenNode {
+    estimate: f32,
+    node: NavNode,
+}
+
+impl PartialEq for OpenNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.estimate == other.estimate
+    }
+}
+
+impl Eq for OpenNode {}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OpenNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate)
+    }
+}
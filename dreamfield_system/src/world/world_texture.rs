@@ -1,38 +1,111 @@
 use speedy::{Readable, Writable};
+use serde::{Serialize, Deserialize};
 
 pub type TextureIndex = i32;
 
-/// A single world texture
+/// The pixel format of a world texture
+#[derive(Clone, Copy, Readable, Writable, Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureFormat {
+    /// Full rgba8 pixels
+    #[default]
+    Rgba8,
+    /// 4-bit palette indices packed two to a byte, low nibble first, with a 16 color palette
+    Indexed4,
+    /// 8-bit palette indices with a 256 color palette
+    Indexed8,
+}
+
+impl TextureFormat {
+    /// Get the number of colors in the palette, or None if the format isn't indexed
+    pub fn palette_size(&self) -> Option<usize> {
+        match self {
+            TextureFormat::Rgba8 => None,
+            TextureFormat::Indexed4 => Some(16),
+            TextureFormat::Indexed8 => Some(256),
+        }
+    }
+
+    /// Unpack palette indices in this format to one byte per texel. Panics if the format isn't
+    /// indexed.
+    pub fn unpack_indices(&self, data: &[u8], texel_count: usize) -> Vec<u8> {
+        match self {
+            TextureFormat::Indexed4 => data.iter()
+                .flat_map(|&byte| [byte & 0xf, byte >> 4])
+                .take(texel_count)
+                .collect(),
+            TextureFormat::Indexed8 => data.to_vec(),
+            TextureFormat::Rgba8 => panic!("unpack_indices: Rgba8 textures aren't indexed")
+        }
+    }
+}
+
+/// A single world texture, processed by the world builder so that it's either rgba8 or palette
+/// indices with an rgba8 palette
 #[derive(Readable, Writable)]
 pub struct WorldTexture {
     data: Vec<u8>,
-    format: u32,
+    mips: Vec<Vec<u8>>,
+    format: TextureFormat,
+    palette: Vec<u8>,
     width: u32,
     height: u32,
     index: TextureIndex
 }
 
 impl WorldTexture {
-    pub fn new(pixels: Vec<u8>, format: u32, width: u32, height: u32, index: TextureIndex) -> Self {
+    pub fn new(pixels: Vec<u8>, mips: Vec<Vec<u8>>, format: TextureFormat, palette: Vec<u8>, width: u32, height: u32,
+        index: TextureIndex) -> Self
+    {
         Self {
             data: pixels,
+            mips,
             format,
+            palette,
             width,
             height,
             index
         }
     }
 
-    /// Get the pixels in rgba8 format
+    /// Get the pixels, in rgba8 or as packed palette indices depending on the format
     pub fn pixels(&self) -> &[u8] {
         &self.data
     }
 
-    /// Get the gl format
-    pub fn format(&self) -> u32 {
+    /// Get the pre-generated mip levels after the first, in the same format as the pixels. This is
+    /// empty if mips weren't generated.
+    pub fn mips(&self) -> &[Vec<u8>] {
+        &self.mips
+    }
+
+    /// Get the pixel format
+    pub fn format(&self) -> TextureFormat {
         self.format
     }
 
+    /// Get the rgba8 palette, which is empty if the texture isn't indexed
+    pub fn palette(&self) -> &[u8] {
+        &self.palette
+    }
+
+    /// Get the palette indices for a mip level with one byte per texel, unpacking them if necessary.
+    /// Panics if the texture isn't indexed.
+    pub fn unpack_indices(&self, level: usize) -> Vec<u8> {
+        let data = match level {
+            0 => &self.data,
+            _ => &self.mips[level - 1]
+        };
+
+        let (width, height) = self.mip_size(level);
+        self.format.unpack_indices(data, (width * height) as usize)
+    }
+
+    /// Get the width and height of a mip level, where level 0 is the full size texture
+    pub fn mip_size(&self, level: usize) -> (u32, u32) {
+        (u32::max(1, self.width >> level), u32::max(1, self.height >> level))
+    }
+
     /// Get the width
     pub fn width(&self) -> u32 {
         self.width
@@ -2,7 +2,7 @@ use cgmath::{Vector3, vec3, Vector4, vec4, Matrix4};
 use speedy::{Readable, Writable, Context};
 
 /// A wrapper for Vector3<f32> that's serializable
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct WrappedVector3(pub Vector3<f32>);
 
 impl WrappedVector3 {
@@ -33,7 +33,7 @@ impl<'a, C: Context> Readable<'a, C> for WrappedVector3 {
 }
 
 /// A wrapper for Vector4<f32> that's serializable
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct WrappedVector4(pub Vector4<f32>);
 
 impl WrappedVector4 {